@@ -11,9 +11,12 @@ use kvs::{KvsEngine, KvStore};
 use std::path::PathBuf;
 use std::path::Path;
 use kvs::sled_engine::SledStore;
+use kvs::lsm_engine::LsmStore;
+use crossbeam_utils::thread;
 
 static BASE_PATH: &'static str = "/var/folders/sb/__xlrdmd64v3bmk86q_dg4lx8c1mtb/T/kv-bench";
 static SEQ_LEN: usize = 100;
+static READ_THREADS: [usize; 4] = [1, 2, 4, 8];
 
 fn generate_kv_pairs() -> Vec<(String, String)> {
     let mut rng = thread_rng();
@@ -57,6 +60,12 @@ fn get_sled_store() -> SledStore {
     SledStore::open(full_path).expect("failed to init sled engine")
 }
 
+fn get_lsm_store() -> LsmStore {
+    let base_path = Path::new(BASE_PATH);
+    let full_path = base_path.join("lsm");
+    LsmStore::open(full_path).expect("failed to init lsm engine")
+}
+
 fn bench_kvs_write(c: &mut Criterion) {
     let mut kvs = get_kv_store();
     let pairs1 = generate_kv_pairs();
@@ -95,6 +104,64 @@ fn bench_sled_write(c: &mut Criterion) {
     );
 }
 
+fn bench_lsm_write(c: &mut Criterion) {
+    let lsm = get_lsm_store();
+    let pairs1 = generate_kv_pairs();
+
+    c.bench_function(
+        "lsm write", move |b| {
+            b.iter_batched(|| {
+                let mut pairs = vec![];
+                pairs.clone_from(&pairs1);
+                pairs
+            }, |pairs| {
+                for (k, v) in pairs {
+                    lsm.set(k, v).unwrap();
+                }
+            }, BatchSize::SmallInput)
+        }
+    );
+}
+
+///
+/// the same 1000 reads split over a growing number of threads,
+/// time going down with the thread count means reads scale
+///
+fn bench_concurrent_read<E: KvsEngine + Sync>(c: &mut Criterion, name: &str, engine: E) {
+    let pairs = generate_kv_pairs();
+    for (k, v) in pairs.iter() {
+        engine.set(k.to_string(), v.to_string()).expect("failed to prepare engine");
+    }
+    let keys: Vec<String> = pairs.into_iter().map(|(k, _)| k).collect();
+    let seq = generate_read_seq();
+
+    c.bench_function_over_inputs(
+        name, move |b, &&threads| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for chunk in seq.chunks((seq.len() + threads - 1) / threads) {
+                        let engine = engine.clone();
+                        let keys = &keys;
+                        s.spawn(move |_| {
+                            for &idx in chunk {
+                                black_box(engine.get(keys[idx].to_string()).unwrap());
+                            }
+                        });
+                    }
+                }).unwrap();
+            })
+        }, READ_THREADS.iter()
+    );
+}
+
+fn bench_kvs_concurrent_read(c: &mut Criterion) {
+    bench_concurrent_read(c, "kvs concurrent read", get_kv_store());
+}
+
+fn bench_lsm_concurrent_read(c: &mut Criterion) {
+    bench_concurrent_read(c, "lsm concurrent read", get_lsm_store());
+}
+
 // FIXME: replace bin/bench.rs with this benchmark
-criterion_group!(benches, bench_sled_write);
+criterion_group!(benches, bench_kvs_write, bench_sled_write, bench_lsm_write, bench_kvs_concurrent_read, bench_lsm_concurrent_read);
 criterion_main!(benches);
@@ -11,6 +11,7 @@ use kvs::{KvsEngine, KvStore};
 use std::path::PathBuf;
 use std::path::Path;
 use kvs::sled_engine::SledStore;
+use kvs::lsm_engine::LsmStore;
 use std::time::SystemTime;
 use std::borrow::Borrow;
 
@@ -62,21 +63,32 @@ fn get_sled_store() -> SledStore {
     SledStore::open(full_path).expect("failed to init sled engine")
 }
 
+fn get_lsm_store() -> LsmStore {
+    let base_path = Path::new(BASE_PATH);
+    let full_path = base_path.join("lsm");
+    LsmStore::open(full_path).expect("failed to init lsm engine")
+}
+
 fn main() {
     let mut kvs = get_kv_store();
     let mut sled = get_sled_store();
+    let lsm = get_lsm_store();
     println!("create kv engine...done");
 
     let pairs = generate_kv_pairs();
     let mut pairs1 = vec![];
     let mut pairs2 = vec![];
+    let mut pairs3 = vec![];
     pairs1.clone_from(&pairs);
     pairs2.clone_from(&pairs);
+    pairs3.clone_from(&pairs);
     println!("create test data ({} pairs)...done", pairs1.len());
 
     let seq1 = generate_read_seq(&pairs);
     let mut seq2 = vec![];
+    let mut seq3 = vec![];
     seq2.clone_from(&seq1);
+    seq3.clone_from(&seq1);
     println!("create index sequence...done");
 
     let now1 = SystemTime::now();
@@ -93,10 +105,17 @@ fn main() {
     }
     println!("[sled] finish testing `set`, take {}ms", now2.elapsed().unwrap().as_millis());
 
+    let now5 = SystemTime::now();
+    println!("[lsm] start testing `set`");
+    for (k, v) in pairs3 {
+        lsm.set(k, v).unwrap();
+    }
+    println!("[lsm] finish testing `set`, take {}ms", now5.elapsed().unwrap().as_millis());
+
     let now3 = SystemTime::now();
     println!("[kvs] start testing `get`");
     for (k, v) in seq1 {
-        let target_v = kvs.get(k).unwrap().unwrap();
+        let target_v = kvs.get_string(k).unwrap().unwrap();
         assert_eq!(target_v, v);
     }
     println!("[kvs] finish testing `get`, take {}ms", now3.elapsed().unwrap().as_millis());
@@ -104,8 +123,16 @@ fn main() {
     let now4 = SystemTime::now();
     println!("[sled] start testing `get`");
     for (k, v) in seq2 {
-        let target_v = kvs.get(k).unwrap().unwrap();
+        let target_v = sled.get_string(k).unwrap().unwrap();
         assert_eq!(target_v, v);
     }
     println!("[sled] finish testing `get`, take {}ms", now4.elapsed().unwrap().as_millis());
+
+    let now6 = SystemTime::now();
+    println!("[lsm] start testing `get`");
+    for (k, v) in seq3 {
+        let target_v = lsm.get_string(k).unwrap().unwrap();
+        assert_eq!(target_v, v);
+    }
+    println!("[lsm] finish testing `get`, take {}ms", now6.elapsed().unwrap().as_millis());
 }
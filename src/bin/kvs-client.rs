@@ -4,7 +4,7 @@ use clap::{App, Arg, SubCommand};
 use std::process::exit;
 use std::net::{TcpStream, SocketAddr};
 use kvs::proto::{ReqProto, RespProto};
-use std::io::{Write, Read};
+use std::io::{self, Write, Read};
 
 use kvs::engine::{KvError, Result, KvsEngine};
 use kvs::kvs_engine::KvStore;
@@ -18,9 +18,15 @@ fn main() -> Result<()> {
             .arg(Arg::with_name("set_arg")
                 .value_names(&["KEY", "VALUE"])
                 .required(true)
-                .help("kvs set <KEY> <VALUE>")
+                .help("kvs set <KEY> <VALUE> [--ttl <SECONDS>]")
                 .number_of_values(2)
             )
+            .arg(Arg::with_name("ttl")
+                .long("ttl")
+                .value_name("SECONDS")
+                .help("If specified then the key expires after that many seconds")
+                .takes_value(true)
+            )
             .arg(Arg::with_name("addr")
                 .long("addr")
                 .value_name("IP-PORT")
@@ -56,6 +62,88 @@ fn main() -> Result<()> {
                 .takes_value(true)
             )
         )
+        .subcommand(SubCommand::with_name("cas")
+            .arg(Arg::with_name("cas_arg")
+                .value_name("KEY")
+                .required(true)
+                .help("kvs cas <KEY> [--expected <VALUE>] [--new <VALUE>]")
+                .number_of_values(1)
+            )
+            .arg(Arg::with_name("expected")
+                .long("expected")
+                .value_name("VALUE")
+                .help("current value the key must have, if not specified then the key must not exist")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("new")
+                .long("new")
+                .value_name("VALUE")
+                .help("value to set, if not specified then the key is removed")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP-PORT")
+                .help("If not specified then listen on 127.0.0.1:4000")
+                .takes_value(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("expire")
+            .arg(Arg::with_name("expire_arg")
+                .value_names(&["KEY", "SECONDS"])
+                .required(true)
+                .help("kvs expire <KEY> <SECONDS>")
+                .number_of_values(2)
+            )
+            .arg(Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP-PORT")
+                .help("If not specified then listen on 127.0.0.1:4000")
+                .takes_value(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("ttl")
+            .arg(Arg::with_name("ttl_arg")
+                .value_name("KEY")
+                .required(true)
+                .help("kvs ttl <KEY>, -1 if the key never expires")
+                .number_of_values(1)
+            )
+            .arg(Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP-PORT")
+                .help("If not specified then listen on 127.0.0.1:4000")
+                .takes_value(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("persist")
+            .arg(Arg::with_name("persist_arg")
+                .value_name("KEY")
+                .required(true)
+                .help("kvs persist <KEY>")
+                .number_of_values(1)
+            )
+            .arg(Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP-PORT")
+                .help("If not specified then listen on 127.0.0.1:4000")
+                .takes_value(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("backup")
+            .arg(Arg::with_name("backup_arg")
+                .value_name("DIR")
+                .required(true)
+                .help("kvs backup <DIR>, DIR is relative to the backup directory of the server and must not exist or be empty")
+                .number_of_values(1)
+            )
+            .arg(Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP-PORT")
+                .help("If not specified then listen on 127.0.0.1:4000")
+                .takes_value(true)
+            )
+        )
         .arg(Arg::with_name("version")
             .short("V")
             .help("Prints version information")
@@ -70,19 +158,54 @@ fn main() -> Result<()> {
     match matches.subcommand() {
         ("set", Some(sub_m)) => {
             let input: Vec<&str> = sub_m.values_of("set_arg").unwrap().collect();
-            let proto = ReqProto::Set(input[0].to_string(), input[1].to_string());
+            let proto = match sub_m.value_of("ttl") {
+                Some(secs) => ReqProto::SetWithTtl(input[0].as_bytes().to_vec(), input[1].as_bytes().to_vec(), parse_secs(secs)),
+                None => ReqProto::Set(input[0].as_bytes().to_vec(), input[1].as_bytes().to_vec()),
+            };
             let addr: SocketAddr = sub_m.value_of("addr").unwrap_or("127.0.0.1:4000").parse()?;
             send_command(proto, addr)?;
         }
         ("get", Some(sub_m)) => {
             let key = sub_m.value_of("get_arg").unwrap();
-            let proto = ReqProto::Get(key.to_string());
+            let proto = ReqProto::Get(key.as_bytes().to_vec());
             let addr: SocketAddr = sub_m.value_of("addr").unwrap_or("127.0.0.1:4000").parse()?;
             send_command(proto, addr)?;
         }
         ("rm", Some(sub_m)) => {
             let key = sub_m.value_of("rm_arg").unwrap();
-            let proto = ReqProto::Remove(key.to_string());
+            let proto = ReqProto::Remove(key.as_bytes().to_vec());
+            let addr: SocketAddr = sub_m.value_of("addr").unwrap_or("127.0.0.1:4000").parse()?;
+            send_command(proto, addr)?;
+        }
+        ("cas", Some(sub_m)) => {
+            let key = sub_m.value_of("cas_arg").unwrap();
+            let expected = sub_m.value_of("expected").map(|v| v.as_bytes().to_vec());
+            let new = sub_m.value_of("new").map(|v| v.as_bytes().to_vec());
+            let proto = ReqProto::CompareAndSwap(key.as_bytes().to_vec(), expected, new);
+            let addr: SocketAddr = sub_m.value_of("addr").unwrap_or("127.0.0.1:4000").parse()?;
+            send_command(proto, addr)?;
+        }
+        ("expire", Some(sub_m)) => {
+            let input: Vec<&str> = sub_m.values_of("expire_arg").unwrap().collect();
+            let proto = ReqProto::Expire(input[0].as_bytes().to_vec(), parse_secs(input[1]));
+            let addr: SocketAddr = sub_m.value_of("addr").unwrap_or("127.0.0.1:4000").parse()?;
+            send_command(proto, addr)?;
+        }
+        ("ttl", Some(sub_m)) => {
+            let key = sub_m.value_of("ttl_arg").unwrap();
+            let proto = ReqProto::Ttl(key.as_bytes().to_vec());
+            let addr: SocketAddr = sub_m.value_of("addr").unwrap_or("127.0.0.1:4000").parse()?;
+            send_command(proto, addr)?;
+        }
+        ("persist", Some(sub_m)) => {
+            let key = sub_m.value_of("persist_arg").unwrap();
+            let proto = ReqProto::Persist(key.as_bytes().to_vec());
+            let addr: SocketAddr = sub_m.value_of("addr").unwrap_or("127.0.0.1:4000").parse()?;
+            send_command(proto, addr)?;
+        }
+        ("backup", Some(sub_m)) => {
+            let dir = sub_m.value_of("backup_arg").unwrap();
+            let proto = ReqProto::Backup(dir.to_string());
             let addr: SocketAddr = sub_m.value_of("addr").unwrap_or("127.0.0.1:4000").parse()?;
             send_command(proto, addr)?;
         }
@@ -94,6 +217,16 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn parse_secs(secs: &str) -> u64 {
+    match secs.parse() {
+        Ok(secs) => secs,
+        Err(_) => {
+            eprintln!("Invalid number of seconds: {}", secs);
+            exit(1);
+        }
+    }
+}
+
 fn send_command(proto: ReqProto, addr: SocketAddr) -> Result<()> {
     let mut raw = serde_json::to_string(&proto)?;
     raw.push('\n');
@@ -104,20 +237,26 @@ fn send_command(proto: ReqProto, addr: SocketAddr) -> Result<()> {
     stream.flush()?;
 
     let mut resp = Vec::new();
-    stream.read_to_end(&mut resp);
+    stream.read_to_end(&mut resp)?;
     if resp.is_empty() {
-        return Ok(()); // EOF
+        // the server answers every request, so it went away before it could
+        eprintln!("No response from server");
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
     }
     let proto: RespProto = serde_json::from_slice(resp.as_slice())?;
     match proto {
-        RespProto::OK(Some(mut v)) => {
-            println!("{}", v);
+        RespProto::OK(Some(v)) => {
+            // values are raw bytes, written out as they are
+            let mut stdout = io::stdout();
+            stdout.write_all(v.as_slice())?;
+            stdout.write_all(b"\n")?;
             Ok(())
         },
         RespProto::OK(None) => {
             println!("Key not found");
             Ok(())
         },
+        RespProto::Done => Ok(()),
         RespProto::Error(err) => {
             eprintln!("{}", err);
             Err(KvError::KeyNotFound)
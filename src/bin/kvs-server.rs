@@ -13,12 +13,85 @@ use std::io::BufRead;
 use std::io::BufReader;
 use std::thread;
 use kvs::proto::{ReqProto, RespProto};
-use kvs::engine::{KvError, Result, KvsEngine};
-use kvs::kvs_engine::KvStore;
+use kvs::engine::{KvError, Result, KvsEngine, Transaction, EngineKind};
+use kvs::kvs_engine::{self, KvStore, KvStoreOptions, SyncPolicy, RecoveryMode, EncryptionKey, Compression, IndexMode};
 use kvs::sled_engine::SledStore;
+use kvs::lsm_engine::{LsmStore, LsmOptions};
 use kvs::thread_pool::ThreadPool;
 use kvs::thread_pool::SharedQueueThreadPool;
 use std::borrow::BorrowMut;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// how long a transaction may be left idle before it's dropped, unless given on the command line
+const DEFAULT_TXN_TIMEOUT_SECS: u64 = 60;
+
+///
+/// transactions opened by clients and not committed or aborted yet, by id, along with
+/// when they were last used
+///
+/// every request comes on a connection of its own, so a transaction outlives the connections
+/// and is dropped once it's left idle for `timeout` instead: it's then reported missing,
+/// and the ones nobody comes back for are swept whenever a transaction begins
+///
+#[derive(Clone)]
+struct Transactions {
+    next_id: Arc<AtomicU64>,
+    open: Arc<Mutex<HashMap<u64, (Transaction, Instant)>>>,
+    timeout: Duration,
+}
+
+impl Transactions {
+
+    fn new(timeout: Duration) -> Self {
+        Transactions {
+            next_id: Arc::new(AtomicU64::new(0)),
+            open: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+        }
+    }
+
+    fn insert(&self, txn: Transaction) -> Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut open = self.open.lock().map_err(|_| KvError::LockError)?;
+        let timeout = self.timeout;
+        open.retain(|_, (_, used_at)| used_at.elapsed() < timeout);
+        open.insert(id, (txn, Instant::now()));
+        Ok(id)
+    }
+
+    fn take(&self, id: u64) -> Result<Transaction> {
+        match self.open.lock().map_err(|_| KvError::LockError)?.remove(&id) {
+            Some((txn, used_at)) if used_at.elapsed() < self.timeout => Ok(txn),
+            _ => Err(KvError::TransactionNotFound(id)),
+        }
+    }
+
+    ///
+    /// run `f` on transaction `id`, dropping the transaction if it turns out to conflict
+    ///
+    fn with<T, F>(&self, id: u64, f: F) -> Result<T>
+        where F: FnOnce(&mut Transaction) -> Result<T> {
+        let mut open = self.open.lock().map_err(|_| KvError::LockError)?;
+        let res = match open.get_mut(&id) {
+            Some((_, used_at)) if used_at.elapsed() >= self.timeout => Err(KvError::TransactionNotFound(id)),
+            Some((txn, used_at)) => {
+                *used_at = Instant::now();
+                f(txn)
+            },
+            None => return Err(KvError::TransactionNotFound(id)),
+        };
+        if let Err(KvError::TransactionConflict) | Err(KvError::TransactionNotFound(_)) = res {
+            open.remove(&id);
+        }
+        res
+    }
+}
 
 ///
 /// slog doc: https://docs.rs/slog/2.5.2/slog/
@@ -45,7 +118,122 @@ fn main() -> Result<()> {
         .arg(Arg::with_name("engine")
             .long("engine")
             .value_name("ENGINE-NAME")
-            .help("must be either \"kvs\", in which case the built-in engine is used, or \"sled\"")
+            .help("must be either \"kvs\", in which case the built-in engine is used, \"sled\" or \"lsm\"")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("segment-size")
+            .long("segment-size")
+            .value_name("BYTES")
+            .help("kvs only, size in bytes a log segment grows to before a new one is started")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("compaction-ratio")
+            .long("compaction-ratio")
+            .value_name("RATIO")
+            .help("kvs only, compact once stale bytes reach this fraction of the log, between 0 and 1")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("compaction-min-bytes")
+            .long("compaction-min-bytes")
+            .value_name("BYTES")
+            .help("kvs only, never compact with fewer stale bytes than this")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("compaction-interval")
+            .long("compaction-interval")
+            .value_name("SECONDS")
+            .help("kvs only, how often the background compaction checks the log")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("sync")
+            .long("sync")
+            .value_name("POLICY")
+            .help("kvs and lsm, one of \"always\", to sync every write to disk, \"never\", \"<N>ms\" to sync every N milliseconds or \"<N>writes\" to sync every N writes")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("read-only")
+            .long("read-only")
+            .help("kvs only, serve reads from an existing store and reject writes")
+        )
+        .arg(Arg::with_name("no-create")
+            .long("no-create")
+            .help("kvs only, fail instead of creating a store that doesn't exist")
+        )
+        .arg(Arg::with_name("error-if-exists")
+            .long("error-if-exists")
+            .help("kvs only, fail if a store already exists")
+        )
+        .arg(Arg::with_name("strict-recovery")
+            .long("strict-recovery")
+            .help("kvs only, fail on a corrupt log tail instead of truncating it")
+        )
+        .arg(Arg::with_name("encryption-key-file")
+            .long("encryption-key-file")
+            .value_name("FILE")
+            .help("kvs only, encrypt the store with the key in FILE, either 32 bytes or 64 hex digits")
+            .takes_value(true)
+            .conflicts_with("encryption-key-env")
+        )
+        .arg(Arg::with_name("encryption-key-env")
+            .long("encryption-key-env")
+            .value_name("VAR")
+            .help("kvs only, encrypt the store with the key in the environment variable VAR, as 64 hex digits")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("old-encryption-key-file")
+            .long("old-encryption-key-file")
+            .value_name("FILE")
+            .help("kvs only, key the store was encrypted with before, records are rewritten with the new key by compaction, may be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+        )
+        .arg(Arg::with_name("compression")
+            .long("compression")
+            .value_name("ALGORITHM")
+            .help("kvs only, either \"lz4\" to compress values or \"none\", records are rewritten accordingly by compaction, If not specified then none")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("compression-threshold")
+            .long("compression-threshold")
+            .value_name("BYTES")
+            .help("kvs only, values shorter than this are stored raw")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("blob-threshold")
+            .long("blob-threshold")
+            .value_name("BYTES")
+            .help("kvs only, values at least this long are stored apart in blob files, which compaction doesn't copy, If not specified then none are")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("index-mode")
+            .long("index-mode")
+            .value_name("MODE")
+            .help("kvs only, either \"full\" to keep keys whole in memory or \"hashed\" to keep only their hash, which bounds memory but slows scans down, If not specified then full")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("value-cache-size")
+            .long("value-cache-size")
+            .value_name("BYTES")
+            .help("kvs only, bytes of the values read lately kept in memory, If not specified then none are")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("memtable-size")
+            .long("memtable-size")
+            .value_name("BYTES")
+            .help("lsm only, size in bytes the memtable grows to before it's flushed to a table")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("txn-timeout")
+            .long("txn-timeout")
+            .value_name("SECONDS")
+            .help("how long a transaction may be left idle before it's dropped, If not specified then 60 seconds")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("backup-dir")
+            .long("backup-dir")
+            .value_name("DIR")
+            .help("directory clients may write backups into, as DIR/<NAME>, If not specified then backups are refused")
             .takes_value(true)
         )
         .arg(Arg::with_name("version")
@@ -62,48 +250,229 @@ fn main() -> Result<()> {
     info!(logger, "kvs-server {}", env!("CARGO_PKG_VERSION"));
 
     let addr: SocketAddr = matches.value_of("addr").unwrap_or("127.0.0.1:4000").parse()?;
-    // TODO: limit only kvs or sled, convert to enum
     let engine_name = matches.value_of("engine").unwrap_or("kvs");
+    let engine = match EngineKind::from_name(engine_name) {
+        Some(engine) => engine,
+        None => {
+            error!(logger, "Unrecognized storage engine: `{}`", engine_name);
+            exit(1);
+        }
+    };
     info!(logger, "storage engine `{}`, listen on `{}`...", engine_name, addr);
+    let txn_timeout = parse_arg::<u64>(&matches, "txn-timeout", &logger).unwrap_or(DEFAULT_TXN_TIMEOUT_SECS);
+    let txns = Transactions::new(Duration::from_secs(txn_timeout));
+    let backup_root = matches.value_of("backup-dir").map(|dir| {
+        match fs::create_dir_all(dir).and_then(|_| fs::canonicalize(dir)) {
+            Ok(root) => root,
+            Err(e) => {
+                error!(logger, "Invalid backup directory `{}`: {:?}", dir, e);
+                exit(1);
+            }
+        }
+    });
 
     info!(logger, "initializing storage engine");
-    match engine_name {
-        "kvs" => {
-            let store = KvStore::default();
+    // the data directory is checked to belong to that engine when it's opened
+    match engine {
+        EngineKind::Kvs => {
+            let options = kvs_options(&matches, &logger);
+            let store = match options.open(kvs_engine::DEFAULT_PATH) {
+                Ok(store) => store,
+                Err(e) => {
+                    error!(logger, "Fail to open storage engine: {:?}", e);
+                    exit(1);
+                }
+            };
             let log = logger.clone();
-            run_with(store, addr, log)?;
+            run_with(store, addr, txns, backup_root, log)?;
         },
-        "sled" => {
-            let store = SledStore::default();
+        EngineKind::Sled => {
+            let store = match SledStore::open(kvs_engine::DEFAULT_PATH) {
+                Ok(store) => store,
+                Err(e) => {
+                    error!(logger, "Fail to open storage engine: {:?}", e);
+                    exit(1);
+                }
+            };
             let log = logger.clone();
-            run_with(store, addr, log)?;
+            run_with(store, addr, txns, backup_root, log)?;
         },
-        _ => {
-            error!(logger, "Unrecognized storage engine: `{}`", engine_name);
+        EngineKind::Lsm => {
+            let options = lsm_options(&matches, &logger);
+            let store = match options.open(kvs_engine::DEFAULT_PATH) {
+                Ok(store) => store,
+                Err(e) => {
+                    error!(logger, "Fail to open storage engine: {:?}", e);
+                    exit(1);
+                }
+            };
+            let log = logger.clone();
+            run_with(store, addr, txns, backup_root, log)?;
+        },
+    }
+    Ok(())
+}
+
+///
+/// collect `KvStore` options from the command line, exit on any invalid value
+///
+fn kvs_options(matches: &clap::ArgMatches, logger: &Logger) -> KvStoreOptions {
+    let mut options = KvStore::builder()
+        .read_only(matches.is_present("read-only"))
+        .create_if_missing(!matches.is_present("no-create"))
+        .error_if_exists(matches.is_present("error-if-exists"));
+    if let Some(size) = parse_arg::<u64>(matches, "segment-size", logger) {
+        options = options.segment_size(size);
+    }
+    if let Some(ratio) = parse_arg::<f64>(matches, "compaction-ratio", logger) {
+        if ratio < 0.0 || ratio > 1.0 {
+            error!(logger, "`compaction-ratio` must be between 0 and 1, got `{}`", ratio);
             exit(1);
         }
+        options = options.compaction_stale_ratio(ratio);
     }
-    Ok(())
+    if let Some(bytes) = parse_arg::<u64>(matches, "compaction-min-bytes", logger) {
+        options = options.compaction_min_stale_bytes(bytes);
+    }
+    if let Some(secs) = parse_arg::<u64>(matches, "compaction-interval", logger) {
+        options = options.compaction_interval(Duration::from_secs(secs));
+    }
+    if let Some(policy) = sync_policy(matches, logger) {
+        options = options.sync_policy(policy);
+    }
+    if matches.is_present("strict-recovery") {
+        options = options.recovery_mode(RecoveryMode::Strict);
+    }
+    let key = match (matches.value_of("encryption-key-file"), matches.value_of("encryption-key-env")) {
+        (Some(file), _) => Some(load_key(EncryptionKey::from_file(file), file, logger)),
+        (None, Some(var)) => Some(load_key(EncryptionKey::from_env(var), var, logger)),
+        (None, None) => None,
+    };
+    if let Some(key) = key {
+        options = options.encryption_key(key);
+    }
+    for file in matches.values_of("old-encryption-key-file").into_iter().flatten() {
+        options = options.old_encryption_key(load_key(EncryptionKey::from_file(file), file, logger));
+    }
+    match matches.value_of("compression") {
+        None | Some("none") => {},
+        Some("lz4") => options = options.compression(Compression::Lz4),
+        Some(compression) => {
+            error!(logger, "Unrecognized compression: `{}`", compression);
+            exit(1);
+        }
+    }
+    if let Some(bytes) = parse_arg::<usize>(matches, "compression-threshold", logger) {
+        options = options.compression_threshold(bytes);
+    }
+    if let Some(bytes) = parse_arg::<usize>(matches, "blob-threshold", logger) {
+        options = options.blob_threshold(bytes);
+    }
+    if let Some(bytes) = parse_arg::<u64>(matches, "value-cache-size", logger) {
+        options = options.value_cache_size(bytes);
+    }
+    match matches.value_of("index-mode") {
+        None | Some("full") => {},
+        Some("hashed") => options = options.index_mode(IndexMode::Hashed),
+        Some(mode) => {
+            error!(logger, "Unrecognized index mode: `{}`", mode);
+            exit(1);
+        }
+    }
+    options
+}
+
+///
+/// collect `LsmStore` options from the command line, exit on any invalid value
+///
+fn lsm_options(matches: &clap::ArgMatches, logger: &Logger) -> LsmOptions {
+    let mut options = LsmStore::builder();
+    if let Some(policy) = sync_policy(matches, logger) {
+        options = options.sync_policy(policy);
+    }
+    if let Some(bytes) = parse_arg::<u64>(matches, "memtable-size", logger) {
+        options = options.memtable_size(bytes);
+    }
+    options
+}
+
+fn load_key(key: Result<EncryptionKey>, source: &str, logger: &Logger) -> EncryptionKey {
+    match key {
+        Ok(key) => key,
+        Err(e) => {
+            error!(logger, "Fail to load encryption key from `{}`: {:?}", source, e);
+            exit(1);
+        }
+    }
+}
+
+///
+/// sync policy given on the command line, shared by the kvs and lsm engines, exit if it's invalid
+///
+fn sync_policy(matches: &clap::ArgMatches, logger: &Logger) -> Option<SyncPolicy> {
+    matches.value_of("sync").map(|policy| match parse_sync_policy(policy) {
+        Some(policy) => policy,
+        None => {
+            error!(logger, "Unrecognized sync policy: `{}`", policy);
+            exit(1);
+        }
+    })
+}
+
+fn parse_sync_policy(policy: &str) -> Option<SyncPolicy> {
+    match policy {
+        "always" => Some(SyncPolicy::Always),
+        "never" => Some(SyncPolicy::Never),
+        _ if policy.ends_with("ms") => policy.trim_end_matches("ms").parse().ok()
+            .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms))),
+        _ if policy.ends_with("writes") => policy.trim_end_matches("writes").parse().ok()
+            .filter(|&n| n > 0)
+            .map(SyncPolicy::EveryWrites),
+        _ => None,
+    }
+}
+
+fn parse_arg<T: FromStr>(matches: &clap::ArgMatches, name: &str, logger: &Logger) -> Option<T> {
+    matches.value_of(name).map(|raw| match raw.parse() {
+        Ok(value) => value,
+        Err(_) => {
+            error!(logger, "Invalid value for `{}`: `{}`", name, raw);
+            exit(1);
+        }
+    })
 }
 
-fn run_with(engine: impl KvsEngine, addr: SocketAddr, logger: Logger) -> Result<()> {
+fn run_with(engine: impl KvsEngine, addr: SocketAddr, txns: Transactions, backup_root: Option<PathBuf>,
+            logger: Logger) -> Result<()> {
     let listener = TcpListener::bind(addr)?;
     // TODO: get cpu count
     let pool = SharedQueueThreadPool::new(6)?;
     loop {
         match listener.accept() {
-            Ok((mut stream, peer_addr)) => {
+            Ok((stream, peer_addr)) => {
                 debug!(logger, "[Main] accept remote stream from {}", peer_addr);
                 let engine_cp = engine.clone();
                 let logger_cp = logger.clone();
+                let txns_cp = txns.clone();
+                let backup_root_cp = backup_root.clone();
                 // submit job to the thread pool
                 pool.spawn(move || {
-                    let req_proto = deserialize_request(&stream);
+                    let req_proto = match deserialize_request(&stream) {
+                        // the connection is dropped, there is no one to answer
+                        Err(KvError::IoErr(e)) => {
+                            error!(logger_cp, "Fail to read request from {}: {:?}", peer_addr, e);
+                            return;
+                        },
+                        req_proto => req_proto,
+                    };
                     debug!(logger_cp, "[{:?}] received command => `{:?}`",
                            thread::current().id(),
                            req_proto
                     );
-                    process_request(engine_cp, logger_cp, req_proto, stream);
+                    let log = logger_cp.clone();
+                    if let Err(e) = process_request(engine_cp, txns_cp, backup_root_cp, logger_cp, req_proto, stream) {
+                        error!(log, "Fail to answer {}: {:?}", peer_addr, e);
+                    }
                 });
             },
             Err(e) => error!(logger, "couldn't get remote stream: {:?}", e),
@@ -114,39 +483,149 @@ fn run_with(engine: impl KvsEngine, addr: SocketAddr, logger: Logger) -> Result<
 fn deserialize_request(stream: &TcpStream) -> Result<ReqProto> {
     let mut raw = Vec::new();
     let mut buf_stream = BufReader::new(stream);
-    buf_stream.read_until(b'\n', &mut raw);
+    buf_stream.read_until(b'\n', &mut raw)?;
     serde_json::from_slice(raw.as_slice()).map_err(|e| KvError::SerdeJsonError(e))
 }
 
 fn process_request(engine: impl KvsEngine,
+                   txns: Transactions,
+                   backup_root: Option<PathBuf>,
                    logger: Logger,
                    req: Result<ReqProto>,
                    mut stream: TcpStream) -> Result<()> {
-    match req {
+    let resp = match req {
         Ok(ReqProto::Get(key)) => {
-            let val_opt = engine.get(key)?;
-            let resp = RespProto::OK(val_opt);
-            send_response(&mut stream, resp)?;
+            match engine.get(key) {
+                Ok(val_opt) => RespProto::OK(val_opt),
+                Err(e) => key_error(e, "get"),
+            }
         },
         Ok(ReqProto::Set(key, value)) => {
-            engine.set(key, value)?;
+            done(engine.set(key, value), |e| key_error(e, "set"))
         },
-        Ok(ReqProto::Remove(key)) => {
-            match engine.remove(key) {
-                Err(KvError::KeyNotFound) => {
-                    let resp = RespProto::Error("Key not found".to_string());
-                    send_response(&mut stream, resp)?;
+        Ok(ReqProto::SetWithTtl(key, value, secs)) => {
+            done(engine.set_with_ttl(key, value, Duration::from_secs(secs)), |e| key_error(e, "set"))
+        },
+        Ok(ReqProto::Expire(key, secs)) => {
+            done(engine.expire(key, Duration::from_secs(secs)), |e| key_error(e, "update expiry"))
+        },
+        Ok(ReqProto::Ttl(key)) => {
+            match engine.ttl(key) {
+                Ok(ttl) => RespProto::OK(Some(ttl.map_or("-1".to_string(), |ttl| ((ttl.as_millis() + 500) / 1000).to_string()).into_bytes())),
+                Err(e) => key_error(e, "read expiry"),
+            }
+        },
+        Ok(ReqProto::Persist(key)) => {
+            done(engine.persist(key), |e| key_error(e, "update expiry"))
+        },
+        Ok(ReqProto::Batch(batch)) => {
+            done(engine.write_batch(batch), |e| RespProto::Error(format!("Fail to write batch: {:?}", e)))
+        },
+        Ok(ReqProto::CompareAndSwap(key, expected, new)) => {
+            done(engine.compare_and_swap(key, expected, new), |e| match e {
+                KvError::CompareAndSwapFailed(Some(value)) =>
+                    RespProto::Error(format!("Value mismatch, current value: {}", String::from_utf8_lossy(&value))),
+                KvError::CompareAndSwapFailed(None) => RespProto::Error("Value mismatch, key not found".to_string()),
+                e => RespProto::Error(format!("Fail to compare and swap: {:?}", e)),
+            })
+        },
+        Ok(ReqProto::Begin) => {
+            match engine.begin().and_then(|txn| txns.insert(txn)) {
+                Ok(id) => RespProto::OK(Some(id.to_string().into_bytes())),
+                Err(e) => RespProto::Error(format!("Fail to begin transaction: {:?}", e)),
+            }
+        },
+        Ok(ReqProto::TxnGet(id, key)) => {
+            match txns.with(id, |txn| engine.txn_get(txn, key)) {
+                Ok(val_opt) => RespProto::OK(val_opt),
+                Err(e) => txn_error(e),
+            }
+        },
+        Ok(ReqProto::TxnSet(id, key, value)) => {
+            done(txns.with(id, |txn| { txn.set(key, value); Ok(()) }), txn_error)
+        },
+        Ok(ReqProto::TxnRemove(id, key)) => {
+            done(txns.with(id, |txn| { txn.remove(key); Ok(()) }), txn_error)
+        },
+        Ok(ReqProto::Commit(id)) => {
+            done(txns.take(id).and_then(|txn| engine.commit(txn)), txn_error)
+        },
+        Ok(ReqProto::Abort(id)) => {
+            done(txns.take(id).map(|_| ()), txn_error)
+        },
+        Ok(ReqProto::Backup(dir)) => {
+            match backup_root.as_ref().map(|root| (root, backup_path(root, &dir))) {
+                None => RespProto::Error("Backups are disabled, the server has no backup directory".to_string()),
+                Some((root, None)) => {
+                    error!(logger, "Refuse to backup into {}, outside of {}", dir, root.display());
+                    RespProto::Error(format!("Backup directory `{}` is outside of the server's backup directory", dir))
+                },
+                Some((_, Some(dest))) => {
+                    info!(logger, "Backup into {}", dest.display());
+                    done(engine.checkpoint(&dest), |e| {
+                        error!(logger, "Fail to backup into {}: {:?}", dest.display(), e);
+                        RespProto::Error(format!("Fail to backup: {:?}", e))
+                    })
                 },
-                _ => {}
             }
         },
+        Ok(ReqProto::Remove(key)) => {
+            done(engine.remove(key), |e| key_error(e, "remove"))
+        },
         Err(e) => {
             error!(logger, "[{:?}] Fail to process request {:?}",
                    thread::current().id(),
                    e);
+            RespProto::Error(format!("Fail to read request: {:?}", e))
         },
+    };
+    send_response(&mut stream, resp)
+}
+
+///
+/// acknowledge a write with `Done`, or turn its error into a response
+///
+fn done(res: Result<()>, on_error: impl FnOnce(KvError) -> RespProto) -> RespProto {
+    match res {
+        Ok(()) => RespProto::Done,
+        Err(e) => on_error(e),
+    }
+}
+
+///
+/// where to write the backup requested into `dir`, relative to `root`, the canonical backup directory
+/// the path is resolved through every symlink and must land strictly inside `root`, otherwise `None`
+/// its parent has to exist, only the last component may be created by the checkpoint
+///
+fn backup_path(root: &Path, dir: &str) -> Option<PathBuf> {
+    let dest = root.join(dir);
+    // a dangling symlink exists as well, and would be followed by the checkpoint
+    let resolved = if fs::symlink_metadata(&dest).is_ok() {
+        fs::canonicalize(&dest).ok()?
+    } else {
+        fs::canonicalize(dest.parent()?).ok()?.join(dest.file_name()?)
+    };
+    if resolved != root && resolved.starts_with(root) {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+fn txn_error(e: KvError) -> RespProto {
+    match e {
+        KvError::TransactionConflict => RespProto::Error("Transaction conflict".to_string()),
+        KvError::TransactionNotFound(id) => RespProto::Error(format!("Transaction {} not found", id)),
+        e => RespProto::Error(format!("Transaction failed: {:?}", e)),
+    }
+}
+
+fn key_error(e: KvError, action: &str) -> RespProto {
+    match e {
+        KvError::KeyNotFound => RespProto::Error("Key not found".to_string()),
+        KvError::ReadOnly => RespProto::Error("Store is read-only".to_string()),
+        e => RespProto::Error(format!("Fail to {}: {:?}", action, e)),
     }
-    Ok(())
 }
 
 fn send_response(stream: &mut TcpStream, resp: RespProto) -> Result<()> {
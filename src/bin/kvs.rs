@@ -0,0 +1,166 @@
+extern crate clap;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::process::exit;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use kvs::engine::{Result, KvsEngine, KvError, EngineKind};
+use kvs::kvs_engine::{self, KvStore};
+use kvs::sled_engine::SledStore;
+use kvs::lsm_engine::LsmStore;
+use kvs::dump::{self, DumpFormat};
+use kvs::migrate;
+
+///
+/// offline tools working on a store directly, the server must not be running on it
+///
+fn main() -> Result<()> {
+    let matches = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .subcommand(SubCommand::with_name("dump")
+            .about("Write every key/value pair of a store, with expiries, to a file")
+            .arg(Arg::with_name("dump_arg")
+                .value_name("FILE")
+                .help("kvs dump [FILE] [--format <jsonl|binary>], If not specified then write to stdout")
+            )
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("either \"jsonl\", one json object per line, or \"binary\", If not specified then jsonl")
+                .takes_value(true)
+            )
+            .arg(engine_arg())
+            .arg(path_arg())
+        )
+        .subcommand(SubCommand::with_name("restore")
+            .about("Load a dump of either format into a store, overwriting existing keys")
+            .arg(Arg::with_name("restore_arg")
+                .value_name("FILE")
+                .help("kvs restore [FILE], If not specified then read from stdin")
+            )
+            .arg(engine_arg())
+            .arg(path_arg())
+        )
+        .subcommand(SubCommand::with_name("migrate")
+            .about("Convert a store to another engine in place, checking every key made it")
+            .arg(Arg::with_name("from")
+                .long("from")
+                .value_name("ENGINE-NAME")
+                .help("engine the store is written with, either \"kvs\", \"sled\" or \"lsm\"")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("to")
+                .long("to")
+                .value_name("ENGINE-NAME")
+                .help("engine to convert the store to, either \"kvs\", \"sled\" or \"lsm\"")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(path_arg())
+        )
+        .arg(Arg::with_name("version")
+            .short("V")
+            .help("Prints version information")
+        )
+        .get_matches();
+
+    if matches.is_present("version") {
+        println!("{}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+
+    match matches.subcommand() {
+        ("dump", Some(sub_m)) => {
+            let format = match sub_m.value_of("format").unwrap_or("jsonl") {
+                "jsonl" => DumpFormat::Jsonl,
+                "binary" => DumpFormat::Binary,
+                format => {
+                    eprintln!("Unrecognized dump format: `{}`", format);
+                    exit(1);
+                }
+            };
+            // the store is only read, a wrong path mustn't leave an empty store behind
+            let path = store_path(sub_m);
+            if !Path::new(path).is_dir() {
+                return Err(KvError::StoreNotFound);
+            }
+            let count = match engine_arg_value(sub_m, "engine") {
+                EngineKind::Kvs => dump_to(&KvStore::builder().read_only(true).open(path)?, sub_m, format)?,
+                EngineKind::Sled => dump_to(&SledStore::open(path)?, sub_m, format)?,
+                EngineKind::Lsm => dump_to(&LsmStore::open(path)?, sub_m, format)?,
+            };
+            eprintln!("Dumped {} keys", count);
+        }
+        ("restore", Some(sub_m)) => {
+            let count = match engine_arg_value(sub_m, "engine") {
+                EngineKind::Kvs => restore_from(&KvStore::open(store_path(sub_m))?, sub_m)?,
+                EngineKind::Sled => restore_from(&SledStore::open(store_path(sub_m))?, sub_m)?,
+                EngineKind::Lsm => restore_from(&LsmStore::open(store_path(sub_m))?, sub_m)?,
+            };
+            eprintln!("Restored {} keys", count);
+        }
+        ("migrate", Some(sub_m)) => {
+            let from = engine_arg_value(sub_m, "from");
+            let to = engine_arg_value(sub_m, "to");
+            let digest = migrate::migrate(store_path(sub_m), from, to)?;
+            eprintln!("Migrated {} keys from {} to {}, checksum {:08x}",
+                      digest.keys, from.name(), to.name(), digest.checksum);
+        }
+        _ => {
+            eprintln!("{}", matches.usage());
+            exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+fn engine_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("engine")
+        .long("engine")
+        .value_name("ENGINE-NAME")
+        .help("must be either \"kvs\", in which case the built-in engine is used, \"sled\" or \"lsm\"")
+        .takes_value(true)
+}
+
+fn path_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("path")
+        .long("path")
+        .value_name("DIR")
+        .help("directory of the store, If not specified then the one of kvs-server, ./database")
+        .takes_value(true)
+}
+
+fn engine_arg_value(matches: &ArgMatches, arg: &str) -> EngineKind {
+    let name = matches.value_of(arg).unwrap_or("kvs");
+    match EngineKind::from_name(name) {
+        Some(engine) => engine,
+        None => {
+            eprintln!("Unrecognized storage engine: `{}`", name);
+            exit(1);
+        }
+    }
+}
+
+fn store_path<'a>(matches: &'a ArgMatches) -> &'a str {
+    matches.value_of("path").unwrap_or(kvs_engine::DEFAULT_PATH)
+}
+
+fn dump_to(engine: &impl KvsEngine, matches: &ArgMatches, format: DumpFormat) -> Result<u64> {
+    match matches.value_of("dump_arg") {
+        Some(file) => dump::dump(engine, File::create(file)?, format),
+        None => dump::dump(engine, io::stdout(), format),
+    }
+}
+
+fn restore_from(engine: &impl KvsEngine, matches: &ArgMatches) -> Result<u64> {
+    match matches.value_of("restore_arg") {
+        Some(file) => dump::restore(engine, File::open(file)?),
+        None => dump::restore(engine, io::stdin()),
+    }
+}
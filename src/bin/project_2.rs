@@ -53,7 +53,7 @@ fn main() -> Result<()> {
         }
         ("get", Some(sub_m)) => {
             let key = sub_m.value_of("get_arg").unwrap();
-            let value = kv_store.get(key.to_owned())?;
+            let value = kv_store.get_string(key)?;
             match value {
                 Some(v) => println!("{}", v),
                 _ => println!("Key not found"),
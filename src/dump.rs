@@ -0,0 +1,450 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::ops::Bound;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+use super::engine::{Result, KvsEngine, KvsSnapshot, KvError, WriteBatch};
+use super::kvs_engine::{now_millis, is_expired_at};
+
+/// magic bytes at the start of a binary dump
+const DUMP_MAGIC: [u8; 4] = *b"KVSD";
+/// version of the binary dump format
+const DUMP_VERSION: u16 = 1;
+/// header: magic + version + 2 reserved bytes
+const DUMP_HEADER_LEN: usize = 8;
+/// record header: crc32 + flags + key length + value length
+const RECORD_HEADER_LEN: usize = 13;
+/// flag marking a record with an expiry, stored right after the record header
+const FLAG_EXPIRY: u8 = 0x01;
+/// flag marking the last record, which only carries the number of records before it
+const FLAG_END: u8 = 0x80;
+/// how many pairs are read from the snapshot at once
+const DUMP_PAGE_SIZE: usize = 1024;
+/// how many pairs are written to the engine in one batch
+const RESTORE_BATCH_SIZE: usize = 1024;
+
+///
+/// format of a dump file
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    /// one json object per line, keys and values as strings, or as `{"hex": ..}` if they
+    /// aren't utf-8 encoded
+    Jsonl,
+    /// checksummed binary records, after a header and up to an end marker
+    Binary,
+}
+
+///
+/// a key/value pair of a dump
+///
+struct DumpRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    /// when the key expires, in milliseconds since the unix epoch
+    expires_at: Option<u64>,
+}
+
+///
+/// a line of a jsonl dump
+///
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    key: JsonBytes,
+    value: JsonBytes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+///
+/// bytes in a jsonl dump, readable if they are utf-8 encoded
+///
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonBytes {
+    Text(String),
+    Hex { hex: String },
+}
+
+impl From<Vec<u8>> for JsonBytes {
+    fn from(bytes: Vec<u8>) -> JsonBytes {
+        match String::from_utf8(bytes) {
+            Ok(text) => JsonBytes::Text(text),
+            Err(err) => JsonBytes::Hex { hex: to_hex(err.as_bytes()) },
+        }
+    }
+}
+
+impl JsonBytes {
+    fn into_bytes(self) -> Result<Vec<u8>> {
+        match self {
+            JsonBytes::Text(text) => Ok(text.into_bytes()),
+            JsonBytes::Hex { hex } => from_hex(&hex).ok_or(KvError::CorruptedRecord),
+        }
+    }
+}
+
+///
+/// number of keys and checksum of the content of a store, see `digest`
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Digest {
+    /// number of live keys
+    pub keys: u64,
+    /// crc32 of every key and value in key order, along with whether the key expires
+    pub checksum: u32,
+}
+
+///
+/// running `Digest` of the pairs it's fed in key order
+///
+struct DigestBuilder {
+    keys: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl DigestBuilder {
+
+    fn new() -> Self {
+        DigestBuilder { keys: 0, hasher: crc32fast::Hasher::new() }
+    }
+
+    fn update(&mut self, record: &DumpRecord) {
+        self.keys += 1;
+        self.hasher.update(&(record.key.len() as u32).to_le_bytes());
+        self.hasher.update(&record.key);
+        self.hasher.update(&(record.value.len() as u32).to_le_bytes());
+        self.hasher.update(&record.value);
+        self.hasher.update(&[record.expires_at.is_some() as u8]);
+    }
+
+    fn finish(self) -> Digest {
+        Digest { keys: self.keys, checksum: self.hasher.finalize() }
+    }
+}
+
+///
+/// write every key/value pair of `engine` to `writer`, along with the expiry of the keys
+/// which have one, return the number of pairs written
+///
+/// pairs are read from a snapshot along with their expiries, so the dump is consistent
+/// whatever is written meanwhile
+///
+pub fn dump<E: KvsEngine, W: Write>(engine: &E, writer: W, format: DumpFormat) -> Result<u64> {
+    let mut writer = BufWriter::new(writer);
+    if format == DumpFormat::Binary {
+        writer.write_all(&DUMP_MAGIC)?;
+        writer.write_all(&DUMP_VERSION.to_le_bytes())?;
+        writer.write_all(&[0u8; 2])?;
+    }
+    let mut count = 0u64;
+    for_each_record(engine, |record| {
+        match format {
+            DumpFormat::Jsonl => write_json_record(&mut writer, record)?,
+            DumpFormat::Binary => writer.write_all(&encode_record(&record))?,
+        }
+        count += 1;
+        Ok(())
+    })?;
+    if format == DumpFormat::Binary {
+        writer.write_all(&encode_end(count))?;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+///
+/// count the keys of `engine` and checksum them with their values, two stores holding the
+/// same pairs have the same digest whatever their engine
+///
+pub fn digest<E: KvsEngine>(engine: &E) -> Result<Digest> {
+    let mut digest = DigestBuilder::new();
+    for_each_record(engine, |record| {
+        digest.update(&record);
+        Ok(())
+    })?;
+    Ok(digest.finish())
+}
+
+///
+/// load every key/value pair of `src` into `dst`, return the digest of the pairs loaded
+///
+pub(crate) fn copy<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D) -> Result<Digest> {
+    let mut loader = Loader::new(dst);
+    for_each_record(src, |record| loader.load(record))?;
+    loader.finish()?;
+    Ok(loader.digest.finish())
+}
+
+///
+/// whether `dst` holds the same pairs as `src`, with the same keys expiring
+///
+/// a key may expire between the snapshots of the two stores, so only the keys which are
+/// still alive once both snapshots are taken are compared
+///
+pub(crate) fn same_content<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D) -> Result<bool> {
+    let src = src.snapshot()?;
+    let dst = dst.snapshot()?;
+    let now = now_millis();
+    Ok(snapshot_digest(&src, now)? == snapshot_digest(&dst, now)?)
+}
+
+///
+/// digest of the pairs of `snapshot` which don't expire by `now`
+///
+fn snapshot_digest<S: KvsSnapshot>(snapshot: &S, now: u64) -> Result<Digest> {
+    let mut digest = DigestBuilder::new();
+    for_each_snapshot_record(snapshot, |record| {
+        if !is_expired_at(record.expires_at, now) {
+            digest.update(&record);
+        }
+        Ok(())
+    })?;
+    Ok(digest.finish())
+}
+
+///
+/// call `f` on every key/value pair of a snapshot of `engine`, in key order, along with
+/// the expiry of the key
+///
+fn for_each_record<E, F>(engine: &E, f: F) -> Result<()>
+    where E: KvsEngine, F: FnMut(DumpRecord) -> Result<()> {
+    for_each_snapshot_record(&engine.snapshot()?, f)
+}
+
+///
+/// call `f` on every key/value pair of `snapshot`, in key order, along with the expiry
+/// of the key as of the snapshot
+///
+fn for_each_snapshot_record<S, F>(snapshot: &S, mut f: F) -> Result<()>
+    where S: KvsSnapshot, F: FnMut(DumpRecord) -> Result<()> {
+    let mut start = Bound::Unbounded;
+    loop {
+        let records = snapshot.scan_with_expiry((start.clone(), Bound::Unbounded), Some(DUMP_PAGE_SIZE))?;
+        let exhausted = records.len() < DUMP_PAGE_SIZE;
+        if let Some((last, _, _)) = records.last() {
+            start = Bound::Excluded(last.clone());
+        }
+        for (key, value, expires_at) in records {
+            f(DumpRecord { key, value, expires_at })?;
+        }
+        if exhausted {
+            return Ok(());
+        }
+    }
+}
+
+///
+/// load every key/value pair of a dump read from `reader` into `engine`, whatever its format,
+/// keys which already exist are overwritten, keys which expired since the dump are skipped
+/// return the number of pairs loaded
+///
+pub fn restore<E: KvsEngine, R: Read>(engine: &E, reader: R) -> Result<u64> {
+    let mut reader = BufReader::new(reader);
+    let binary = reader.fill_buf()?.starts_with(&DUMP_MAGIC);
+    let mut loader = Loader::new(engine);
+    if binary {
+        let mut header = [0u8; DUMP_HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != DUMP_VERSION {
+            return Err(KvError::UnsupportedFormatVersion(version));
+        }
+        let mut read = 0u64;
+        loop {
+            match read_record(&mut reader)? {
+                Some(record) => {
+                    loader.load(record)?;
+                    read += 1;
+                },
+                None => break,
+            }
+        }
+        // the end marker is checked for the count in `read_record`
+        let mut count = [0u8; 8];
+        reader.read_exact(&mut count).map_err(|_| KvError::CorruptedRecord)?;
+        if u64::from_le_bytes(count) != read {
+            return Err(KvError::CorruptedRecord);
+        }
+    } else {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: JsonRecord = serde_json::from_str(&line)?;
+            loader.load(DumpRecord {
+                key: record.key.into_bytes()?,
+                value: record.value.into_bytes()?,
+                expires_at: record.expires_at,
+            })?;
+        }
+    }
+    loader.finish()?;
+    Ok(loader.digest.keys)
+}
+
+///
+/// bulk load of a dump: pairs without expiry go in batches, the others one by one
+///
+struct Loader<'a, E: KvsEngine> {
+    engine: &'a E,
+    batch: WriteBatch,
+    /// digest of the pairs loaded so far, expired ones are skipped
+    digest: DigestBuilder,
+}
+
+impl<'a, E: KvsEngine> Loader<'a, E> {
+
+    fn new(engine: &'a E) -> Self {
+        Loader { engine, batch: WriteBatch::new(), digest: DigestBuilder::new() }
+    }
+
+    fn load(&mut self, record: DumpRecord) -> Result<()> {
+        match record.expires_at {
+            None => {
+                self.digest.update(&record);
+                self.batch.set(record.key, record.value);
+                if self.batch.len() >= RESTORE_BATCH_SIZE {
+                    self.flush_batch()?;
+                }
+            },
+            Some(expires_at) => {
+                let now = now_millis();
+                if expires_at <= now {
+                    return Ok(());
+                }
+                self.digest.update(&record);
+                let ttl = Duration::from_millis(expires_at - now);
+                self.engine.set_with_ttl(record.key, record.value, ttl)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn flush_batch(&mut self) -> Result<()> {
+        let batch = std::mem::take(&mut self.batch);
+        self.engine.write_batch(batch)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if !self.batch.is_empty() {
+            self.flush_batch()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_json_record<W: Write>(writer: &mut W, record: DumpRecord) -> Result<()> {
+    let record = JsonRecord {
+        key: record.key.into(),
+        value: record.value.into(),
+        expires_at: record.expires_at,
+    };
+    serde_json::to_writer(&mut *writer, &record)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+///
+/// encode a binary record
+/// layout: | crc32: u32 | flags: u8 | key len: u32 | value len: u32 |
+///         | expiry: u64, only with `FLAG_EXPIRY` | key | value |
+///
+fn encode_record(record: &DumpRecord) -> Vec<u8> {
+    let flags = if record.expires_at.is_some() { FLAG_EXPIRY } else { 0 };
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + 8 + record.key.len() + record.value.len());
+    buf.extend_from_slice(&[0u8; 4]);
+    buf.push(flags);
+    buf.extend_from_slice(&(record.key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(record.value.len() as u32).to_le_bytes());
+    if let Some(expires_at) = record.expires_at {
+        buf.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    buf.extend_from_slice(&record.key);
+    buf.extend_from_slice(&record.value);
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+///
+/// encode the end marker, followed by the number of records
+/// layout: | crc32 of the flags: u32 | flags: u8 | count: u64 |
+///
+fn encode_end(count: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(13);
+    buf.extend_from_slice(&crc32fast::hash(&[FLAG_END]).to_le_bytes());
+    buf.push(FLAG_END);
+    buf.extend_from_slice(&count.to_le_bytes());
+    buf
+}
+
+///
+/// read the next binary record, `None` at the end marker
+/// a dump cut short fails rather than being taken as complete
+///
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<DumpRecord>> {
+    let mut head = [0u8; 5];
+    reader.read_exact(&mut head).map_err(truncated)?;
+    let crc = u32::from_le_bytes([head[0], head[1], head[2], head[3]]);
+    let flags = head[4];
+    if flags & FLAG_END != 0 {
+        if crc32fast::hash(&[flags]) != crc {
+            return Err(KvError::ChecksumMismatch);
+        }
+        return Ok(None);
+    }
+    let mut lens = [0u8; 8];
+    reader.read_exact(&mut lens).map_err(truncated)?;
+    let key_len = u32::from_le_bytes([lens[0], lens[1], lens[2], lens[3]]) as u64;
+    let value_len = u32::from_le_bytes([lens[4], lens[5], lens[6], lens[7]]) as u64;
+    let expiry_len = if flags & FLAG_EXPIRY != 0 { 8 } else { 0 };
+    let mut body = Vec::new();
+    // read through `take` so that a garbage length can't make us allocate it upfront
+    reader.by_ref().take(expiry_len + key_len + value_len).read_to_end(&mut body)?;
+    if body.len() as u64 != expiry_len + key_len + value_len {
+        return Err(KvError::CorruptedRecord);
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[flags]);
+    hasher.update(&lens);
+    hasher.update(&body);
+    if hasher.finalize() != crc {
+        return Err(KvError::ChecksumMismatch);
+    }
+    let expires_at = if expiry_len > 0 {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&body[..8]);
+        Some(u64::from_le_bytes(buf))
+    } else {
+        None
+    };
+    let value = body.split_off((expiry_len + key_len) as usize);
+    let key = body.split_off(expiry_len as usize);
+    Ok(Some(DumpRecord { key, value, expires_at }))
+}
+
+fn truncated(err: io::Error) -> KvError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => KvError::CorruptedRecord,
+        _ => KvError::IoErr(err),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
@@ -1,5 +1,14 @@
 use std::io;
+use std::io::Write;
+use std::fs;
+use std::path::Path;
+use std::string::FromUtf8Error;
 use std::result;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
 use std::sync::{RwLock, RwLockReadGuard};
 
 ///
@@ -12,6 +21,8 @@ pub enum KvError {
     IoErr(io::Error),
     /// error from serde_json
     SerdeJsonError(serde_json::Error),
+    /// value read as a string is not utf-8 encoded
+    Utf8Error(FromUtf8Error),
     /// key not found error
     KeyNotFound,
     /// deal with Path error
@@ -20,10 +31,48 @@ pub enum KvError {
     FileMismatchInPath,
     /// log file not found in path
     UnexpectedLogFile,
+    /// segment of the log is missing
+    SegmentNotFound(u64),
+    /// blob file a value is stored in is missing
+    BlobNotFound(u64),
+    /// record on disk is malformed
+    CorruptedRecord,
+    /// record on disk doesn't match its checksum
+    ChecksumMismatch,
+    /// log written by a newer, unsupported format version
+    UnsupportedFormatVersion(u16),
+    /// log can't be decoded from (segment, offset) on
+    CorruptedLog(u64, u64),
+    /// write to a store opened read-only
+    ReadOnly,
+    /// write applied and visible to readers, but syncing it to disk failed, so a power loss
+    /// may lose it, carries the sync error
+    NotDurable(String),
+    /// no store in the directory, and it's not to be created
+    StoreNotFound,
+    /// a store already exists in the directory
+    StoreExists,
+    /// a key of the transaction was changed by another write since it began
+    TransactionConflict,
+    /// no open transaction with that id
+    TransactionNotFound(u64),
+    /// compare-and-swap found another value, carries the current one
+    CompareAndSwapFailed(Option<Vec<u8>>),
+    /// data directory belongs to another engine, carries the name it's marked with
+    EngineMismatch(String),
+    /// migrated store doesn't hold the same keys and values as the source
+    MigrationMismatch,
+    /// record can't be decrypted: the key is wrong or missing, or the record was tampered with
+    DecryptionFailed,
+    /// encryption key is not 32 bytes or 64 hex digits, or can't be found
+    InvalidEncryptionKey,
     /// server side error
     InvalidIpAddr(std::net::AddrParseError),
     /// wrapper of sled engine error
     SledError(sled::Error),
+    /// data directory written by sled 0.24 or older, which the sled in use can't read,
+    /// see `SledStore::open`
+    OutdatedSledFormat,
     /// error when acquire RwLock
     LockError
 }
@@ -40,6 +89,12 @@ impl From<serde_json::Error> for KvError {
     }
 }
 
+impl From<FromUtf8Error> for KvError {
+    fn from(err: FromUtf8Error) -> KvError {
+        KvError::Utf8Error(err)
+    }
+}
+
 impl From<std::net::AddrParseError> for KvError {
     fn from(err: std::net::AddrParseError) -> KvError {
         KvError::InvalidIpAddr(err)
@@ -58,21 +113,425 @@ pub type Result<T> = result::Result<T, KvError>;
 ///
 /// defines the storage interface called by KvsServer
 ///
+/// keys and values are arbitrary bytes, keys are ordered bytewise
+/// anything that converts into bytes is accepted, strings included, and `get_string`
+/// reads a value back as a string
+///
 pub trait KvsEngine: Clone + Send + 'static {
 
     ///
-    /// Set the value of a string key to a string.
+    /// read-only view of the store at a point in time, see `snapshot`
+    ///
+    type Snapshot: KvsSnapshot;
+
+    ///
+    /// Set the value of a key.
+    /// Return an error if the value is not written successfully.
+    ///
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+    ///
+    /// Set the value of a key, the key is removed once `ttl` has passed.
+    /// A plain `set` of the key drops its expiry.
     /// Return an error if the value is not written successfully.
     ///
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set_with_ttl(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>, ttl: Duration) -> Result<()>;
     ///
-    /// Get the string value of a string key. If the key does not exist, return None.
+    /// Make a given key expire once `ttl` has passed.
+    /// Return an error if the key does not exist or the expiry is not written successfully.
+    ///
+    fn expire(&self, key: impl Into<Vec<u8>>, ttl: Duration) -> Result<()>;
+    ///
+    /// Get the time left before a given key expires, None if it never expires.
+    /// Return an error if the key does not exist.
+    ///
+    fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>>;
+    ///
+    /// Make a given key never expire.
+    /// Return an error if the key does not exist or the change is not written successfully.
+    ///
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+    ///
+    /// Get the value of a key. If the key does not exist, return None.
     /// Return an error if the value is not read successfully.
     ///
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>>;
+    ///
+    /// Get the value of a key as a string. If the key does not exist, return None.
+    /// Return `KvError::Utf8Error` if the value is not utf-8 encoded.
     ///
-    /// Remove a given string key.
+    fn get_string(&self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+    ///
+    /// Remove a given key.
     /// Return an error if the key does not exit or value is not read successfully.
     ///
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+    ///
+    /// Force the writes done so far to durable storage.
+    /// Return an error if the data can't be synced.
+    ///
+    fn flush(&self) -> Result<()>;
+    ///
+    /// Apply all the sets and removes of `batch` atomically, in order.
+    /// Removing a key which doesn't exist is not an error in a batch, it's skipped.
+    /// Return an error if the batch is not written successfully, then none of it is applied.
+    ///
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    ///
+    /// Atomically set `key` to `new`, or remove it if `new` is None, only if its current value
+    /// is `expected`, None meaning that the key must not exist.
+    /// Return `KvError::CompareAndSwapFailed` with the current value if it's not the expected one.
+    ///
+    fn compare_and_swap(&self, key: impl Into<Vec<u8>>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()>;
+    ///
+    /// Begin an optimistic transaction, see `Transaction`.
+    ///
+    fn begin(&self) -> Result<Transaction>;
+    ///
+    /// Get the value of a key within `txn`, its own pending write first.
+    /// Return `KvError::TransactionConflict` if the key was changed since `txn` began.
+    ///
+    fn txn_get(&self, txn: &mut Transaction, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+    ///
+    /// Apply the writes of `txn` atomically.
+    /// Return `KvError::TransactionConflict` if a key it depends on was changed since it began,
+    /// then none of it is applied and it can be retried from `begin`.
+    ///
+    fn commit(&self, txn: Transaction) -> Result<()>;
+    ///
+    /// Get the key/value pairs with keys in `range`, in bytewise order of the keys,
+    /// at most `limit` of them if given.
+    /// Page through a large range by starting the next scan after the last key returned.
+    ///
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    ///
+    /// Get the key/value pairs with keys starting with `prefix`, see `scan`.
+    ///
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = prefix.into();
+        let end = prefix_end(&prefix);
+        self.scan((Bound::Included(prefix), end), limit)
+    }
+    ///
+    /// Take a read-only snapshot which keeps observing the store as of now,
+    /// whatever is written afterwards. Keys expire as of the time it was taken.
+    /// Return an error if the snapshot can't be taken.
+    ///
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+    ///
+    /// Write a consistent copy of the store as of now into `dest`, which must not exist
+    /// or be empty, without stopping writes. The copy can be opened as a store of its own.
+    /// Return an error if the copy can't be written.
+    ///
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()>;
+}
+
+///
+/// read-only handle on the state of a store at the time it was taken, see `KvsEngine::snapshot`
+///
+pub trait KvsSnapshot: Clone + Send + 'static {
+
+    ///
+    /// Get the value of a key as of the snapshot. If the key did not exist, return None.
+    /// Return an error if the value is not read successfully.
+    ///
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>>;
+    ///
+    /// Get the value of a key as of the snapshot as a string, see `KvsEngine::get_string`.
+    ///
+    fn get_string(&self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+    ///
+    /// Get the key/value pairs with keys in `range` as of the snapshot, see `KvsEngine::scan`.
+    ///
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.scan_with_expiry(range, limit)?
+            .into_iter()
+            .map(|(key, value, _)| (key, value))
+            .collect())
+    }
+    ///
+    /// Get the key/value pairs with keys in `range` as of the snapshot, see `KvsEngine::scan`,
+    /// along with when each key expires, in milliseconds since the unix epoch, as it was
+    /// when the snapshot was taken.
+    ///
+    fn scan_with_expiry<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>)
+        -> Result<Vec<(Vec<u8>, Vec<u8>, Option<u64>)>>;
+    ///
+    /// Get the key/value pairs with keys starting with `prefix` as of the snapshot,
+    /// see `KvsEngine::scan`.
+    ///
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = prefix.into();
+        let end = prefix_end(&prefix);
+        self.scan((Bound::Included(prefix), end), limit)
+    }
+}
+
+///
+/// a single operation of a `WriteBatch`
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// set key to value
+    Set(Vec<u8>, Vec<u8>),
+    /// remove key
+    Remove(Vec<u8>),
+}
+
+///
+/// sets and removes to be applied together by `KvsEngine::write_batch`
+///
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+
+    ///
+    /// create an empty batch
+    ///
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    ///
+    /// add a set of `key` to `value`
+    ///
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set(key.into(), value.into()));
+        self
+    }
+
+    ///
+    /// add a remove of `key`
+    ///
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Remove(key.into()));
+        self
+    }
+
+    ///
+    /// number of operations in the batch
+    ///
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    ///
+    /// whether the batch has no operation
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    ///
+    /// operations of the batch, in order
+    ///
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    ///
+    /// consume the batch into its operations
+    ///
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+///
+/// an optimistic transaction, created by `KvsEngine::begin`
+///
+/// reads go through `KvsEngine::txn_get` and are remembered, writes are buffered here until
+/// `KvsEngine::commit`, which checks that none of the keys read was changed by another write
+/// in the meantime and applies the writes atomically. `KvStore` also keeps reads on the
+/// snapshot the transaction began on and fails on a conflicting write to a key it writes,
+/// `SledStore` compares the values read.
+/// Removing a key which doesn't exist is skipped at commit, like in a `WriteBatch`.
+///
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    /// engine specific point the transaction began at
+    pub(crate) start: u64,
+    /// every key read, with the value and engine specific version it was read at
+    pub(crate) reads: BTreeMap<Vec<u8>, (Option<Vec<u8>>, u64)>,
+    /// pending writes, `None` for a remove
+    pub(crate) writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+
+    pub(crate) fn new(start: u64) -> Self {
+        Transaction {
+            start,
+            ..Transaction::default()
+        }
+    }
+
+    ///
+    /// set `key` to `value` once committed
+    ///
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.writes.insert(key.into(), Some(value.into()));
+        self
+    }
+
+    ///
+    /// remove `key` once committed
+    ///
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.writes.insert(key.into(), None);
+        self
+    }
+
+    ///
+    /// the pending write of `key`, `Some(None)` if it's removed
+    ///
+    pub(crate) fn pending(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        self.writes.get(key).cloned()
+    }
+
+    ///
+    /// the writes of the transaction as a batch
+    ///
+    pub(crate) fn write_batch(&self) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes.iter() {
+            match value {
+                Some(value) => batch.set(key.clone(), value.clone()),
+                None => batch.remove(key.clone()),
+            };
+        }
+        batch
+    }
+}
+
+///
+/// the smallest key after all the keys starting with `prefix`
+///
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+///
+/// whether `range` can't hold any key, some range APIs panic on such a range
+///
+pub fn is_empty_range<R: RangeBounds<Vec<u8>>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end)) |
+        (Bound::Excluded(start), Bound::Included(end)) |
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+///
+/// create `path` as the destination of a checkpoint, it may already exist as an empty directory
+///
+pub(crate) fn prepare_empty_dir(path: &Path) -> Result<()> {
+    if path.exists() {
+        if path.is_file() {
+            return Err(KvError::DirPathExpected);
+        }
+        if fs::read_dir(path)?.next().is_some() {
+            return Err(KvError::FileMismatchInPath);
+        }
+    }
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+/// file recording which engine a data directory belongs to
+pub const ENGINE_MARKER_FILE: &'static str = "ENGINE";
+
+///
+/// storage engines a data directory can belong to
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineKind {
+    /// `KvStore`, the built-in engine
+    Kvs,
+    /// `SledStore`
+    Sled,
+    /// `LsmStore`
+    Lsm,
+}
+
+impl EngineKind {
+
+    ///
+    /// name of the engine, as given to `--engine` and written in the marker file
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            EngineKind::Kvs => "kvs",
+            EngineKind::Sled => "sled",
+            EngineKind::Lsm => "lsm",
+        }
+    }
+
+    ///
+    /// engine going by `name`, None if there is none
+    ///
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "kvs" => Some(EngineKind::Kvs),
+            "sled" => Some(EngineKind::Sled),
+            "lsm" => Some(EngineKind::Lsm),
+            _ => None,
+        }
+    }
+}
+
+///
+/// name of the engine the data directory `dir` is marked with, None if it has no marker,
+/// as written by versions before the marker was introduced
+///
+pub fn read_engine_marker(dir: impl AsRef<Path>) -> Result<Option<String>> {
+    match fs::read_to_string(dir.as_ref().join(ENGINE_MARKER_FILE)) {
+        Ok(name) => Ok(Some(name.trim().to_string())),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(KvError::IoErr(err)),
+    }
+}
+
+///
+/// fail with `EngineMismatch` if `dir` is marked as belonging to another engine than `kind`
+///
+pub(crate) fn check_engine_marker(dir: &Path, kind: EngineKind) -> Result<()> {
+    match read_engine_marker(dir)? {
+        Some(ref name) if name != kind.name() => Err(KvError::EngineMismatch(name.clone())),
+        _ => Ok(()),
+    }
+}
+
+///
+/// mark `dir` as belonging to `kind`, unless it already is
+///
+pub(crate) fn write_engine_marker(dir: &Path, kind: EngineKind) -> Result<()> {
+    let path = dir.join(ENGINE_MARKER_FILE);
+    if !path.exists() {
+        let mut file = fs::File::create(path)?;
+        file.write_all(kind.name().as_bytes())?;
+        file.sync_all()?;
+    }
+    Ok(())
 }
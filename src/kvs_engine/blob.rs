@@ -0,0 +1,204 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{Result, KvError, read_exact_at};
+use super::compress::{self, Compression, Compressor};
+use super::crypto::Keyring;
+
+/// magic bytes at the start of every blob file
+const BLOB_MAGIC: [u8; 4] = *b"KVSB";
+/// version of the blob format
+const BLOB_VERSION: u16 = 1;
+/// header: magic + version + compression + flags + id of the key blobs are encrypted with
+pub const BLOB_HEADER_LEN: u64 = 12;
+/// flag of the header marking a file whose blobs are encrypted
+const HEADER_FLAG_ENCRYPTED: u8 = 0x01;
+/// blob header: crc32 + flags
+const ENTRY_HEADER_LEN: usize = 5;
+/// flag marking a blob compressed with LZ4
+const FLAG_LZ4: u8 = 0x01;
+/// flag marking a blob sealed by the keyring
+const FLAG_ENCRYPTED: u8 = 0x02;
+/// length of an encoded `BlobRef`
+pub const BLOB_REF_LEN: usize = 24;
+
+///
+/// where a value stored apart is: which blob file, where and how long
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlobRef {
+    pub file: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl BlobRef {
+
+    ///
+    /// layout: | file: u64 | offset: u64 | len: u64 |
+    ///
+    pub fn encode(&self) -> [u8; BLOB_REF_LEN] {
+        let mut buf = [0u8; BLOB_REF_LEN];
+        buf[0..8].copy_from_slice(&self.file.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.len.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<Self> {
+        if raw.len() != BLOB_REF_LEN {
+            return Err(KvError::CorruptedRecord);
+        }
+        Ok(BlobRef {
+            file: read_u64(&raw[0..8]),
+            offset: read_u64(&raw[8..16]),
+            len: read_u64(&raw[16..24]),
+        })
+    }
+}
+
+///
+/// read-only handle of one blob file
+///
+pub struct BlobFile {
+    file: File,
+    /// compression and key the blobs were written with, None if the header can't be read
+    written_with: Option<(Compression, Option<u32>)>,
+    /// keys of the store, to decrypt the blobs with
+    keyring: Arc<Keyring>,
+}
+
+impl BlobFile {
+
+    ///
+    /// open the blob file at `path` for reading
+    ///
+    pub fn open(path: &Path, keyring: &Arc<Keyring>) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let written_with = read_header(&mut file)?;
+        Ok(BlobFile { file, written_with, keyring: keyring.clone() })
+    }
+
+    ///
+    /// whether the blobs are written otherwise than with `compression` and the current key,
+    /// or it can't be told
+    ///
+    pub fn is_outdated(&self, compression: Compression) -> bool {
+        self.written_with != Some((compression, self.keyring.current_id()))
+    }
+
+    ///
+    /// bytes of the file
+    ///
+    pub fn len(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    ///
+    /// handle on the file, to copy it from
+    ///
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    ///
+    /// read and decode the blob at `blob`, a positional read like `Segment::read_entry`
+    ///
+    pub fn read(&self, blob: BlobRef) -> Result<Vec<u8>> {
+        if (blob.len as usize) < ENTRY_HEADER_LEN {
+            return Err(KvError::CorruptedRecord);
+        }
+        let mut raw = vec![0u8; blob.len as usize];
+        read_exact_at(&self.file, raw.as_mut_slice(), blob.offset)?;
+        if crc32fast::hash(&raw[4..]) != read_u32(&raw[0..4]) {
+            return Err(KvError::ChecksumMismatch);
+        }
+        let flags = raw[4];
+        let data = &raw[ENTRY_HEADER_LEN..];
+        let plain = if flags & FLAG_ENCRYPTED != 0 {
+            self.keyring.open(data, &raw[4..ENTRY_HEADER_LEN])?
+        } else {
+            data.to_vec()
+        };
+        if flags & FLAG_LZ4 != 0 {
+            return compress::decompress_lz4(&plain);
+        }
+        Ok(plain)
+    }
+}
+
+///
+/// header written at the start of a new blob file
+///
+pub fn header(compression: Compression, keyring: &Keyring) -> [u8; BLOB_HEADER_LEN as usize] {
+    let mut header = [0u8; BLOB_HEADER_LEN as usize];
+    header[..4].copy_from_slice(&BLOB_MAGIC);
+    header[4..6].copy_from_slice(&BLOB_VERSION.to_le_bytes());
+    header[6] = compression.id();
+    if let Some(key_id) = keyring.current_id() {
+        header[7] = HEADER_FLAG_ENCRYPTED;
+        header[8..12].copy_from_slice(&key_id.to_le_bytes());
+    }
+    header
+}
+
+///
+/// compression and key a blob file is written with, None if its header is missing or unknown,
+/// like a file created by a crash right before its header was written
+///
+fn read_header<R: Read>(reader: &mut R) -> Result<Option<(Compression, Option<u32>)>> {
+    let mut header = [0u8; BLOB_HEADER_LEN as usize];
+    if let Err(err) = reader.read_exact(&mut header) {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(err.into());
+    }
+    if header[..4] != BLOB_MAGIC || u16::from_le_bytes([header[4], header[5]]) > BLOB_VERSION {
+        return Ok(None);
+    }
+    let key_id = if header[7] & HEADER_FLAG_ENCRYPTED != 0 {
+        Some(read_u32(&header[8..12]))
+    } else {
+        None
+    };
+    Ok(Compression::from_id(header[6]).map(|compression| (compression, key_id)))
+}
+
+///
+/// encode a value into a blob, compressed if the compressor finds it worth it,
+/// then encrypted if the keyring has a current key
+/// layout: | crc32: u32 | flags: u8 | value |
+///
+pub fn encode(value: &[u8], compressor: &Compressor, keyring: &Keyring) -> Vec<u8> {
+    let mut flags = 0u8;
+    let compressed = compressor.compress(value);
+    if compressed.is_some() {
+        flags |= FLAG_LZ4;
+    }
+    let data = compressed.as_ref().map_or(value, |compressed| compressed.as_slice());
+    let sealed = keyring.seal(data, &[flags | FLAG_ENCRYPTED]);
+    if sealed.is_some() {
+        flags |= FLAG_ENCRYPTED;
+    }
+    let data = sealed.as_ref().map_or(data, |sealed| sealed.as_slice());
+    let mut buf = Vec::with_capacity(ENTRY_HEADER_LEN + data.len());
+    buf.extend_from_slice(&[0u8; 4]);
+    buf.push(flags);
+    buf.extend_from_slice(data);
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[0..4].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
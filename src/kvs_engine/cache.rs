@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::LogPos;
+
+/// number of parts of the cache locked apart, so that readers seldom wait on each other
+const SHARDS: usize = 16;
+/// bytes charged for every cached value on top of its key and value, for the bookkeeping
+const ENTRY_OVERHEAD: u64 = 96;
+
+///
+/// a value read from the log, along with its key and expiry
+///
+pub struct CachedValue {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub expires_at: Option<u64>,
+}
+
+///
+/// one part of the cache, least recently used values are evicted first
+///
+#[derive(Default)]
+struct Shard {
+    /// (segment, offset) of the record to its value and its last use
+    entries: HashMap<(u64, u64), (Arc<CachedValue>, u64)>,
+    /// last use to the record, the least recent first
+    lru: BTreeMap<u64, (u64, u64)>,
+    tick: u64,
+    bytes: u64,
+}
+
+impl Shard {
+
+    fn remove(&mut self, id: (u64, u64)) {
+        if let Some((cached, used)) = self.entries.remove(&id) {
+            self.lru.remove(&used);
+            self.bytes -= charge(&cached);
+        }
+    }
+}
+
+///
+/// values read lately, by the position of their record, see `KvStoreOptions::value_cache_size`
+///
+/// a record is never written again once appended, so a cached value is never out of date:
+/// a write leaves it behind at the old position, it's dropped then to make room, as it is
+/// once its segment is compacted away
+///
+pub struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    /// bytes every shard holds at most, 0 if the cache is off
+    shard_capacity: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+
+    ///
+    /// cache holding up to `capacity` bytes, off if it's 0
+    ///
+    pub fn new(capacity: u64) -> Self {
+        ValueCache {
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::default())).collect(),
+            shard_capacity: capacity / SHARDS as u64,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    ///
+    /// value of the record at `pos`, if it's cached
+    ///
+    pub fn get(&self, pos: LogPos) -> Option<Arc<CachedValue>> {
+        if self.shard_capacity == 0 {
+            return None;
+        }
+        let id = (pos.gen, pos.offset);
+        let mut shard = self.shard(id).lock().ok()?;
+        shard.tick += 1;
+        let tick = shard.tick;
+        let found = match shard.entries.get_mut(&id) {
+            Some((cached, used)) => Some((cached.clone(), mem::replace(used, tick))),
+            None => None,
+        };
+        match found {
+            Some((cached, used)) => {
+                shard.lru.remove(&used);
+                shard.lru.insert(tick, id);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(cached)
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            },
+        }
+    }
+
+    ///
+    /// cache the value of the record at `pos`, evicting the least recently used ones
+    /// a value too big for the cache is left out
+    ///
+    pub fn insert(&self, pos: LogPos, cached: CachedValue) {
+        if charge(&cached) > self.shard_capacity {
+            return;
+        }
+        let id = (pos.gen, pos.offset);
+        let mut shard = match self.shard(id).lock() {
+            Ok(shard) => shard,
+            Err(_) => return,
+        };
+        shard.remove(id);
+        shard.tick += 1;
+        let tick = shard.tick;
+        shard.bytes += charge(&cached);
+        shard.entries.insert(id, (Arc::new(cached), tick));
+        shard.lru.insert(tick, id);
+        while shard.bytes > self.shard_capacity {
+            let oldest = match shard.lru.keys().next() {
+                Some(&used) => shard.lru[&used],
+                None => break,
+            };
+            shard.remove(oldest);
+        }
+    }
+
+    ///
+    /// drop the value of the record at `pos`, which is overwritten or removed
+    ///
+    pub fn remove(&self, pos: LogPos) {
+        if self.shard_capacity == 0 {
+            return;
+        }
+        let id = (pos.gen, pos.offset);
+        if let Ok(mut shard) = self.shard(id).lock() {
+            shard.remove(id);
+        }
+    }
+
+    ///
+    /// drop the values of segment `gen`, which is compacted away
+    ///
+    pub fn remove_segment(&self, gen: u64) {
+        if self.shard_capacity == 0 {
+            return;
+        }
+        for shard in self.shards.iter() {
+            if let Ok(mut shard) = shard.lock() {
+                let ids: Vec<(u64, u64)> = shard.entries.keys()
+                    .filter(|&&(entry_gen, _)| entry_gen == gen)
+                    .cloned()
+                    .collect();
+                for id in ids {
+                    shard.remove(id);
+                }
+            }
+        }
+    }
+
+    ///
+    /// lookups which found the value, lookups which didn't, and bytes cached
+    ///
+    pub fn counts(&self) -> (u64, u64, u64) {
+        let bytes = self.shards.iter()
+            .filter_map(|shard| shard.lock().ok().map(|shard| shard.bytes))
+            .sum();
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed), bytes)
+    }
+
+    fn shard(&self, (gen, offset): (u64, u64)) -> &Mutex<Shard> {
+        let hash = (gen ^ offset.rotate_left(20)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        &self.shards[(hash >> 32) as usize % SHARDS]
+    }
+}
+
+///
+/// bytes a cached value counts for
+///
+fn charge(cached: &CachedValue) -> u64 {
+    (cached.key.len() + cached.value.len()) as u64 + ENTRY_OVERHEAD
+}
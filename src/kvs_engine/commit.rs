@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Condvar, Mutex};
+
+use super::{Result, KvError, Store, WriteBatch, Transaction};
+
+///
+/// write waiting to be committed
+///
+pub enum WriteOp {
+    Set(Vec<u8>, Vec<u8>, Option<u64>),
+    Expire(Vec<u8>, Option<u64>),
+    Remove(Vec<u8>),
+    Batch(WriteBatch),
+    CompareAndSwap(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>),
+    Commit(Transaction),
+}
+
+///
+/// group commit of concurrent writes
+///
+/// writers enqueue their op and wait, whoever finds no leader at work becomes the leader:
+/// it takes every op queued so far, applies them under the store lock, syncs them
+/// once as the sync policy asks, then releases all of their writers together
+///
+pub struct GroupCommit {
+    queue: Mutex<Queue>,
+    committed: Condvar,
+}
+
+struct Queue {
+    next_ticket: u64,
+    pending: Vec<(u64, WriteOp)>,
+    /// results of the committed ops, until their writers pick them up
+    done: HashMap<u64, Result<()>>,
+    leading: bool,
+}
+
+impl GroupCommit {
+
+    pub fn new() -> Self {
+        GroupCommit {
+            queue: Mutex::new(Queue {
+                next_ticket: 0,
+                pending: Vec::new(),
+                done: HashMap::new(),
+                leading: false,
+            }),
+            committed: Condvar::new(),
+        }
+    }
+
+    ///
+    /// commit `op` to `store`, return once it's applied and synced
+    /// `KvError::NotDurable` if it's applied but the sync failed
+    ///
+    pub fn write(&self, store: &Mutex<Store>, op: WriteOp) -> Result<()> {
+        let mut queue = self.queue.lock().map_err(|_| KvError::LockError)?;
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((ticket, op));
+
+        loop {
+            if let Some(res) = queue.done.remove(&ticket) {
+                return res;
+            }
+            if !queue.leading {
+                // our op is either in this batch or already in `done`
+                queue.leading = true;
+                let batch = mem::take(&mut queue.pending);
+                drop(queue);
+                let mut leader = Leader {
+                    group: self,
+                    tickets: batch.iter().map(|(ticket, _)| *ticket).collect(),
+                    results: Vec::new(),
+                };
+                leader.results = commit_batch(store, batch);
+                drop(leader);
+                queue = self.queue.lock().map_err(|_| KvError::LockError)?;
+                continue;
+            }
+            queue = self.committed.wait(queue).map_err(|_| KvError::LockError)?;
+        }
+    }
+}
+
+///
+/// turn of the writer leading a batch, ended when dropped, even by a panic
+///
+/// the results of the batch are handed to its writers, those of a batch which didn't
+/// finish fail, and the writers are woken up so that one of them leads the next batch
+///
+struct Leader<'a> {
+    group: &'a GroupCommit,
+    tickets: Vec<u64>,
+    results: Vec<(u64, Result<()>)>,
+}
+
+impl<'a> Drop for Leader<'a> {
+    fn drop(&mut self) {
+        // the queue isn't held while a batch is committed, a panic there doesn't poison it
+        let mut queue = match self.group.queue.lock() {
+            Ok(queue) => queue,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        queue.leading = false;
+        queue.done.extend(self.results.drain(..));
+        for &ticket in self.tickets.iter() {
+            queue.done.entry(ticket).or_insert(Err(KvError::LockError));
+        }
+        // wake the writers of this batch, and one of those queued meanwhile to lead the next
+        self.group.committed.notify_all();
+    }
+}
+
+///
+/// apply `batch` in order with a single sync, return the result of every op
+///
+fn commit_batch(store: &Mutex<Store>, batch: Vec<(u64, WriteOp)>) -> Vec<(u64, Result<()>)> {
+    let mut guard = match store.lock() {
+        Ok(guard) => guard,
+        Err(_) => return batch.into_iter().map(|(ticket, _)| (ticket, Err(KvError::LockError))).collect(),
+    };
+    let mut results: Vec<(u64, Result<()>)> = batch.into_iter()
+        .map(|(ticket, op)| {
+            let res = match op {
+                WriteOp::Set(k, v, expires_at) => guard.set_internal(k, v, expires_at),
+                WriteOp::Expire(k, expires_at) => guard.expire_internal(k, expires_at),
+                WriteOp::Remove(k) => guard.remove_internal(k),
+                WriteOp::Batch(batch) => guard.write_batch_internal(batch),
+                WriteOp::CompareAndSwap(k, expected, new) => guard.compare_and_swap_internal(k, expected, new),
+                WriteOp::Commit(txn) => guard.commit_internal(txn),
+            };
+            (ticket, res)
+        })
+        .collect();
+    if let Err(err) = guard.sync_due() {
+        // the writes are in the index already, readers may have seen them
+        let msg = format!("{:?}", err);
+        for (_, res) in results.iter_mut().filter(|(_, res)| res.is_ok()) {
+            *res = Err(KvError::NotDurable(msg.clone()));
+        }
+    }
+    results
+}
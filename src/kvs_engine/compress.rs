@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Result, KvError};
+
+///
+/// how the values of a store are compressed, see `KvStoreOptions::compression`
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// values are stored as they are
+    None,
+    /// values are compressed with LZ4, fast enough to leave on for any workload
+    Lz4,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+
+    ///
+    /// id of the compression, as written in the header of the segments
+    ///
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    ///
+    /// compression of the given id, None if it's unknown
+    ///
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+///
+/// compresses the values of a store as they are encoded, and counts how well it does
+///
+pub struct Compressor {
+    compression: Compression,
+    /// values shorter than that are stored raw
+    threshold: usize,
+    values: AtomicU64,
+    compressed_values: AtomicU64,
+    value_bytes: AtomicU64,
+    stored_value_bytes: AtomicU64,
+}
+
+impl Compressor {
+
+    pub fn new(compression: Compression, threshold: usize) -> Self {
+        Compressor {
+            compression,
+            threshold,
+            values: AtomicU64::new(0),
+            compressed_values: AtomicU64::new(0),
+            value_bytes: AtomicU64::new(0),
+            stored_value_bytes: AtomicU64::new(0),
+        }
+    }
+
+    ///
+    /// compression values are written with
+    ///
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    ///
+    /// compress `value` if it's long enough and compressing it actually saves space,
+    /// None if it's to be stored raw
+    ///
+    pub fn compress(&self, value: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self.compression {
+            Compression::Lz4 if value.len() >= self.threshold =>
+                Some(lz4_flex::compress_prepend_size(value)).filter(|c| c.len() < value.len()),
+            _ => None,
+        };
+        self.values.fetch_add(1, Ordering::Relaxed);
+        self.value_bytes.fetch_add(value.len() as u64, Ordering::Relaxed);
+        let stored_len = match compressed.as_ref() {
+            Some(compressed) => {
+                self.compressed_values.fetch_add(1, Ordering::Relaxed);
+                compressed.len()
+            },
+            None => value.len(),
+        };
+        self.stored_value_bytes.fetch_add(stored_len as u64, Ordering::Relaxed);
+        compressed
+    }
+
+    ///
+    /// values compressed so far: how many, how many of them were stored compressed,
+    /// their total size before and after
+    ///
+    pub fn counts(&self) -> (u64, u64, u64, u64) {
+        (
+            self.values.load(Ordering::Relaxed),
+            self.compressed_values.load(Ordering::Relaxed),
+            self.value_bytes.load(Ordering::Relaxed),
+            self.stored_value_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+///
+/// decompress a value compressed with LZ4 by `Compressor::compress`
+///
+pub fn decompress_lz4(compressed: &[u8]) -> Result<Vec<u8>> {
+    lz4_flex::decompress_size_prepended(compressed).map_err(|_| KvError::CorruptedRecord)
+}
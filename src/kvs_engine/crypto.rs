@@ -0,0 +1,168 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use rand::RngCore;
+
+use super::{Result, KvError};
+
+/// length of an encryption key
+pub const KEY_LEN: usize = 32;
+/// length of the nonce stored along with every encrypted blob
+pub const NONCE_LEN: usize = 12;
+/// length of the authentication tag appended to every encrypted blob
+pub const TAG_LEN: usize = 16;
+/// encrypted blob header: id of the key + nonce
+pub const SEALED_HEADER_LEN: usize = 4 + NONCE_LEN;
+/// associated data the key ids are derived with, so they say nothing about the key
+const KEY_ID_CONTEXT: &'static [u8] = b"kvs key id";
+
+///
+/// 256-bit key of the authenticated encryption (ChaCha20-Poly1305) of a store,
+/// see `KvStoreOptions::encryption_key`
+///
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+
+    ///
+    /// key made of these bytes
+    ///
+    pub fn new(bytes: [u8; KEY_LEN]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    ///
+    /// key read from a file, holding either the 32 bytes of the key or 64 hex digits
+    ///
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let raw = fs::read(path)?;
+        if raw.len() == KEY_LEN {
+            let mut bytes = [0u8; KEY_LEN];
+            bytes.copy_from_slice(&raw);
+            return Ok(EncryptionKey(bytes));
+        }
+        let hex = String::from_utf8(raw).map_err(|_| KvError::InvalidEncryptionKey)?;
+        Self::from_hex(hex.trim())
+    }
+
+    ///
+    /// key read from the environment variable `var`, as 64 hex digits
+    ///
+    pub fn from_env(var: &str) -> Result<Self> {
+        let hex = env::var(var).map_err(|_| KvError::InvalidEncryptionKey)?;
+        Self::from_hex(hex.trim())
+    }
+
+    fn from_hex(hex: &str) -> Result<Self> {
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(KvError::InvalidEncryptionKey);
+        }
+        let mut bytes = [0u8; KEY_LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| KvError::InvalidEncryptionKey)?;
+        }
+        Ok(EncryptionKey(bytes))
+    }
+}
+
+///
+/// an encryption key ready to use, along with its id
+///
+struct Cipher {
+    id: u32,
+    aead: ChaCha20Poly1305,
+}
+
+impl Cipher {
+
+    fn new(key: &EncryptionKey) -> Self {
+        let aead = ChaCha20Poly1305::new(Key::from_slice(&key.0));
+        // the tag of an empty message: the same for the same key, and unrelated otherwise
+        let tag = aead.encrypt(Nonce::from_slice(&[0u8; NONCE_LEN]), Payload { msg: &[], aad: KEY_ID_CONTEXT })
+            .expect("Fail to derive the id of an encryption key");
+        let id = u32::from_le_bytes([tag[0], tag[1], tag[2], tag[3]]);
+        Cipher { id, aead }
+    }
+}
+
+///
+/// keys of a store: the one everything is written with, if any, and older ones
+/// which are only read with, until a compaction rewrites their records
+///
+#[derive(Default)]
+pub struct Keyring {
+    current: Option<Cipher>,
+    old: Vec<Cipher>,
+}
+
+impl Keyring {
+
+    pub fn new(current: Option<&EncryptionKey>, old: &[EncryptionKey]) -> Self {
+        Keyring {
+            current: current.map(Cipher::new),
+            old: old.iter().map(Cipher::new).collect(),
+        }
+    }
+
+    ///
+    /// id of the key data is written with, None if it's written in plain
+    ///
+    pub fn current_id(&self) -> Option<u32> {
+        self.current.as_ref().map(|cipher| cipher.id)
+    }
+
+    ///
+    /// encrypt `msg` with the current key, authenticating `aad` along with it
+    /// layout: | key id: u32 | nonce | ciphertext | tag |
+    /// None if there is no current key, the data is to be written in plain
+    ///
+    pub fn seal(&self, msg: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let cipher = self.current.as_ref()?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let sealed = cipher.aead.encrypt(Nonce::from_slice(&nonce), Payload { msg, aad })
+            .expect("Fail to encrypt");
+        let mut buf = Vec::with_capacity(SEALED_HEADER_LEN + sealed.len());
+        buf.extend_from_slice(&cipher.id.to_le_bytes());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&sealed);
+        Some(buf)
+    }
+
+    ///
+    /// decrypt a blob sealed by `seal`, with whichever key it was sealed with
+    /// `DecryptionFailed` if that key isn't known, or the blob or `aad` were tampered with
+    ///
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < SEALED_HEADER_LEN + TAG_LEN {
+            return Err(KvError::CorruptedRecord);
+        }
+        let id = sealed_key_id(sealed);
+        let cipher = self.current.iter()
+            .chain(self.old.iter())
+            .find(|cipher| cipher.id == id)
+            .ok_or(KvError::DecryptionFailed)?;
+        let nonce = Nonce::from_slice(&sealed[4..SEALED_HEADER_LEN]);
+        cipher.aead.decrypt(nonce, Payload { msg: &sealed[SEALED_HEADER_LEN..], aad })
+            .map_err(|_| KvError::DecryptionFailed)
+    }
+}
+
+///
+/// id of the key a blob was sealed with, the blob must hold at least its header
+///
+pub fn sealed_key_id(sealed: &[u8]) -> u32 {
+    u32::from_le_bytes([sealed[0], sealed[1], sealed[2], sealed[3]])
+}
@@ -0,0 +1,154 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+use super::Result;
+use super::crypto::{self, Keyring};
+
+/// magic bytes at the start of every hint file
+const HINT_MAGIC: [u8; 4] = *b"KVSH";
+/// version of the hint format
+/// 2: entries may be encrypted
+/// 3: entries tell where the value is when it's stored apart in a blob file
+const HINT_VERSION: u16 = 3;
+/// header: magic + version + flags + reserved byte + length of the segment + number of entries
+const HINT_HEADER_LEN: usize = 24;
+/// flag marking a hint file whose entries are sealed by the keyring, as a whole
+const FLAG_ENCRYPTED: u8 = 0x01;
+/// entry header: crc32 + key length + offset + length of the record
+/// + blob file and length of the blob, both 0 if the value isn't stored apart
+const ENTRY_HEADER_LEN: usize = 40;
+/// entry header of hint files before version 3, without the blob
+const V2_ENTRY_HEADER_LEN: usize = 24;
+
+///
+/// one entry of a hint file: where the latest record of `key` is in the segment
+///
+pub struct Hint {
+    pub key: Vec<u8>,
+    pub offset: u64,
+    pub len: u64,
+    /// blob file the value is in and the length of the blob, if it's stored apart
+    pub blob: Option<(u64, u64)>,
+}
+
+///
+/// write the hint file of a segment of `segment_len` bytes, its entries are encrypted
+/// with the current key of `keyring` if there is one, as the keys they hold are
+/// layout of an entry: | crc32: u32 | key len: u32 | offset: u64 | len: u64 |
+///                     | blob file: u64 | blob len: u64 | key |
+///
+pub fn write_hints(path: &Path, segment_len: u64, hints: &[Hint], keyring: &Keyring) -> Result<()> {
+    let mut entries = Vec::new();
+    for hint in hints {
+        let start = entries.len();
+        entries.extend_from_slice(&[0u8; 4]);
+        entries.extend_from_slice(&(hint.key.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&hint.offset.to_le_bytes());
+        entries.extend_from_slice(&hint.len.to_le_bytes());
+        let (blob_file, blob_len) = hint.blob.unwrap_or((0, 0));
+        entries.extend_from_slice(&blob_file.to_le_bytes());
+        entries.extend_from_slice(&blob_len.to_le_bytes());
+        entries.extend_from_slice(&hint.key);
+        let crc = crc32fast::hash(&entries[start + 4..]);
+        entries[start..start + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+    let mut header = Vec::with_capacity(HINT_HEADER_LEN);
+    header.extend_from_slice(&HINT_MAGIC);
+    header.extend_from_slice(&HINT_VERSION.to_le_bytes());
+    header.push(if keyring.current_id().is_some() { FLAG_ENCRYPTED } else { 0 });
+    header.push(0);
+    header.extend_from_slice(&segment_len.to_le_bytes());
+    header.extend_from_slice(&(hints.len() as u64).to_le_bytes());
+    if let Some(sealed) = keyring.seal(&entries, &header) {
+        entries = sealed;
+    }
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&header)?;
+    writer.write_all(&entries)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+///
+/// read the hint file of a segment which is currently `segment_len` bytes long, along with
+/// the id of the key it's encrypted with, which is the one the records of the segment are
+/// return `None` if there is no hint file, or if it's not usable: written for another
+/// version of the segment, truncated, corrupt, or encrypted with a key `keyring` doesn't hold
+///
+pub fn read_hints(path: &Path, segment_len: u64, keyring: &Keyring) -> Result<Option<(Vec<Hint>, Option<u32>)>> {
+    let raw = match fs::read(path) {
+        Ok(raw) => raw,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(parse_hints(raw.as_slice(), segment_len, keyring))
+}
+
+fn parse_hints(raw: &[u8], segment_len: u64, keyring: &Keyring) -> Option<(Vec<Hint>, Option<u32>)> {
+    if raw.len() < HINT_HEADER_LEN || raw[..4] != HINT_MAGIC ||
+        u16::from_le_bytes([raw[4], raw[5]]) > HINT_VERSION ||
+        read_u64(&raw[8..16]) != segment_len {
+        return None;
+    }
+    let entry_header_len = if u16::from_le_bytes([raw[4], raw[5]]) < 3 {
+        V2_ENTRY_HEADER_LEN
+    } else {
+        ENTRY_HEADER_LEN
+    };
+    let count = read_u64(&raw[16..24]);
+    let (header, body) = raw.split_at(HINT_HEADER_LEN);
+    let (entries, key_id) = if header[6] & FLAG_ENCRYPTED != 0 {
+        if body.len() < crypto::SEALED_HEADER_LEN {
+            return None;
+        }
+        (keyring.open(body, header).ok()?, Some(crypto::sealed_key_id(body)))
+    } else {
+        (body.to_vec(), None)
+    };
+    let mut hints = vec![];
+    let mut rest = entries.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < entry_header_len {
+            return None;
+        }
+        let crc = read_u32(&rest[0..4]);
+        let key_len = read_u32(&rest[4..8]) as usize;
+        let entry_len = entry_header_len + key_len;
+        if rest.len() < entry_len || crc32fast::hash(&rest[4..entry_len]) != crc {
+            return None;
+        }
+        let key = rest[entry_header_len..entry_len].to_vec();
+        let blob = if entry_header_len == ENTRY_HEADER_LEN && read_u64(&rest[24..32]) != 0 {
+            Some((read_u64(&rest[24..32]), read_u64(&rest[32..40])))
+        } else {
+            None
+        };
+        hints.push(Hint {
+            key,
+            offset: read_u64(&rest[8..16]),
+            len: read_u64(&rest[16..24]),
+            blob,
+        });
+        rest = &rest[entry_len..];
+    }
+    if hints.len() as u64 != count {
+        return None;
+    }
+    Some((hints, key_id))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
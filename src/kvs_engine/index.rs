@@ -0,0 +1,342 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryFrom;
+use std::hash::Hasher;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+
+use super::{Result, LogPos};
+
+/// bookkeeping of the allocator for every key kept whole
+const ALLOCATION_OVERHEAD: usize = 16;
+
+///
+/// how a store keeps its keys in memory, see `KvStoreOptions::index_mode`
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexMode {
+    /// every key is kept whole and in order, memory grows with the length of the keys
+    Full,
+    /// only a 64-bit hash of every key is kept, along with the few keys whose hash is taken
+    /// by another one, the key is read back from the log to tell them apart
+    /// memory doesn't depend on the length of the keys, but there is no order to scan them in:
+    /// every scan reads all the keys of the store back from disk, and sorts the ones in range
+    /// in memory, so it takes time in proportion to the whole store and memory to its range,
+    /// a snapshot sorts all of them on its first scan and keeps them for the next ones
+    Hashed,
+}
+
+impl Default for IndexMode {
+    fn default() -> Self {
+        IndexMode::Full
+    }
+}
+
+///
+/// reads back the key of the record at a position, to tell apart the keys with the same hash
+///
+pub trait KeyReader {
+    fn read_key(&self, pos: LogPos) -> Result<Vec<u8>>;
+}
+
+///
+/// position of the latest record of every live key
+///
+/// in `IndexMode::Hashed`, a key is either in `hashed` under its hash, or in `keys` when
+/// its hash was already taken by another key or its position can't be packed, never in both
+///
+#[derive(Debug, Clone)]
+pub struct KeyIndex {
+    /// every key in `IndexMode::Full`, only the ones which can't be hashed in `IndexMode::Hashed`
+    keys: BTreeMap<Vec<u8>, LogPos>,
+    /// hash of the key to its position, in `IndexMode::Hashed` only
+    hashed: Option<HashMap<u64, PackedPos>>,
+    /// total length of the keys in `keys`
+    key_bytes: u64,
+}
+
+impl KeyIndex {
+
+    pub fn new(mode: IndexMode) -> Self {
+        KeyIndex {
+            keys: BTreeMap::new(),
+            hashed: match mode {
+                IndexMode::Full => None,
+                IndexMode::Hashed => Some(HashMap::new()),
+            },
+            key_bytes: 0,
+        }
+    }
+
+    ///
+    /// number of keys
+    ///
+    pub fn len(&self) -> usize {
+        self.keys.len() + self.hashed.as_ref().map_or(0, |hashed| hashed.len())
+    }
+
+    ///
+    /// position `key` is at, without reading the log
+    /// in `IndexMode::Hashed` it may be the record of another key with the same hash,
+    /// the caller reads the record anyway and tells by its key
+    ///
+    pub fn lookup(&self, key: &[u8]) -> Option<LogPos> {
+        if let Some(&pos) = self.keys.get(key) {
+            return Some(pos);
+        }
+        self.hashed.as_ref()
+            .and_then(|hashed| hashed.get(&hash_key(key)))
+            .map(|packed| packed.unpack())
+    }
+
+    ///
+    /// position `key` is at, read back from the log in `IndexMode::Hashed` if need be
+    ///
+    pub fn get<R: KeyReader + ?Sized>(&self, key: &[u8], reader: &R) -> Result<Option<LogPos>> {
+        if let Some(&pos) = self.keys.get(key) {
+            return Ok(Some(pos));
+        }
+        match self.lookup(key) {
+            Some(pos) if reader.read_key(pos)? == key => Ok(Some(pos)),
+            _ => Ok(None),
+        }
+    }
+
+    ///
+    /// whether `key` is at `pos`, which is a position the caller got for it,
+    /// positions are never shared so it's told without reading the log
+    ///
+    pub fn is_at(&self, key: &[u8], pos: LogPos) -> bool {
+        self.lookup(key) == Some(pos)
+    }
+
+    ///
+    /// point `key` at `pos`, return the position it was at
+    ///
+    pub fn insert<R: KeyReader + ?Sized>(&mut self, key: Vec<u8>, pos: LogPos, reader: &R) -> Result<Option<LogPos>> {
+        if let Some(old_pos) = self.keys.get_mut(&key) {
+            return Ok(Some(mem::replace(old_pos, pos)));
+        }
+        let hash = hash_key(&key);
+        // whether the hash is free or taken by the key itself, and where the key was
+        let (own_hash, old_pos) = match self.hashed.as_ref() {
+            None => (false, None),
+            Some(hashed) => match hashed.get(&hash) {
+                None => (true, None),
+                Some(packed) if reader.read_key(packed.unpack())? == key => (true, Some(packed.unpack())),
+                Some(_) => (false, None),
+            },
+        };
+        match (own_hash, PackedPos::pack(pos), self.hashed.as_mut()) {
+            (true, Some(packed), Some(hashed)) => {
+                hashed.insert(hash, packed);
+            },
+            (true, _, Some(hashed)) => {
+                hashed.remove(&hash);
+                self.insert_whole(key, pos);
+            },
+            _ => self.insert_whole(key, pos),
+        }
+        Ok(old_pos)
+    }
+
+    ///
+    /// point `key` at `new_pos` if it's still at `old_pos`, return whether it was
+    ///
+    pub fn replace(&mut self, key: &[u8], old_pos: LogPos, new_pos: LogPos) -> bool {
+        if let Some(pos) = self.keys.get_mut(key) {
+            if *pos != old_pos {
+                return false;
+            }
+            *pos = new_pos;
+            return true;
+        }
+        if !self.is_at(key, old_pos) {
+            return false;
+        }
+        let hashed = self.hashed.as_mut().expect("Fail to find a hashed key");
+        match PackedPos::pack(new_pos) {
+            Some(packed) => {
+                hashed.insert(hash_key(key), packed);
+            },
+            None => {
+                hashed.remove(&hash_key(key));
+                self.insert_whole(key.to_vec(), new_pos);
+            },
+        }
+        true
+    }
+
+    ///
+    /// drop `key`, return the position it was at
+    ///
+    pub fn remove<R: KeyReader + ?Sized>(&mut self, key: &[u8], reader: &R) -> Result<Option<LogPos>> {
+        if let Some(pos) = self.keys.remove(key) {
+            self.key_bytes -= key.len() as u64;
+            return Ok(Some(pos));
+        }
+        match self.lookup(key) {
+            Some(pos) if reader.read_key(pos)? == key => {
+                self.remove_at(key, pos);
+                Ok(Some(pos))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    ///
+    /// drop `key` if it's still at `pos`, return whether it was
+    ///
+    pub fn remove_at(&mut self, key: &[u8], pos: LogPos) -> bool {
+        if self.keys.get(key) == Some(&pos) {
+            self.keys.remove(key);
+            self.key_bytes -= key.len() as u64;
+            return true;
+        }
+        if !self.is_at(key, pos) {
+            return false;
+        }
+        if let Some(hashed) = self.hashed.as_mut() {
+            hashed.remove(&hash_key(key));
+        }
+        true
+    }
+
+    fn insert_whole(&mut self, key: Vec<u8>, pos: LogPos) {
+        self.key_bytes += key.len() as u64;
+        self.keys.insert(key, pos);
+    }
+
+    ///
+    /// positions of all the keys, in no particular order
+    ///
+    pub fn positions<'a>(&'a self) -> impl Iterator<Item = LogPos> + 'a {
+        self.keys.values()
+            .cloned()
+            .chain(self.hashed.iter().flat_map(|hashed| hashed.values()).map(|packed| packed.unpack()))
+    }
+
+    ///
+    /// up to `limit` keys in `range` with their position, in order
+    /// in `IndexMode::Hashed` every key is read back from the log and sorted, a scan going
+    /// through pages rather does it once with `hashed_keys`
+    ///
+    pub fn scan<R: KeyReader + ?Sized>(&self, range: &(Bound<Vec<u8>>, Bound<Vec<u8>>), limit: usize,
+                                       reader: &R) -> Result<Vec<(Vec<u8>, LogPos)>> {
+        match self.hashed_keys(range) {
+            None => Ok(self.keys.range(range.clone()).take(limit).map(|(k, &pos)| (k.clone(), pos)).collect()),
+            Some(keys) => {
+                let mut pairs = keys.sort(range, reader)?;
+                pairs.truncate(limit);
+                Ok(pairs)
+            },
+        }
+    }
+
+    ///
+    /// what a scan of `range` needs from a hashed index, to read the keys back from the log
+    /// without it, None in `IndexMode::Full`
+    ///
+    pub fn hashed_keys(&self, range: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Option<HashedKeys> {
+        let hashed = self.hashed.as_ref()?;
+        Some(HashedKeys {
+            whole: self.keys.range(range.clone()).map(|(k, &pos)| (k.clone(), pos)).collect(),
+            hashed: hashed.values().map(|packed| packed.unpack()).collect(),
+        })
+    }
+
+    ///
+    /// rough estimate of the memory taken by the index, in bytes
+    /// nodes of a B-tree are about two thirds full, and every whole key is an allocation
+    /// of its own, hash tables are counted by their capacity along with their control bytes
+    ///
+    pub fn memory_bytes(&self) -> u64 {
+        let key_entry = (mem::size_of::<Vec<u8>>() + mem::size_of::<LogPos>()) * 3 / 2 + ALLOCATION_OVERHEAD;
+        let hashed_entry = mem::size_of::<u64>() + mem::size_of::<PackedPos>() + 1;
+        let hashed_bytes = self.hashed.as_ref().map_or(0, |hashed| hashed.capacity() * hashed_entry);
+        self.key_bytes + (self.keys.len() * key_entry + hashed_bytes) as u64
+    }
+}
+
+///
+/// keys of a hashed index as taken out of it for a scan: the ones in range kept whole,
+/// and the position of every hashed key, in range or not, whose key is in the log
+///
+pub struct HashedKeys {
+    whole: Vec<(Vec<u8>, LogPos)>,
+    hashed: Vec<LogPos>,
+}
+
+impl HashedKeys {
+
+    ///
+    /// read the hashed keys back through `reader`, return every key in `range`
+    /// with its position, in order
+    ///
+    pub fn sort<R: KeyReader + ?Sized>(self, range: &(Bound<Vec<u8>>, Bound<Vec<u8>>),
+                                       reader: &R) -> Result<Vec<(Vec<u8>, LogPos)>> {
+        let mut pairs = self.whole;
+        for pos in self.hashed {
+            let key = reader.read_key(pos)?;
+            if range.contains(&key) {
+                pairs.push((key, pos));
+            }
+        }
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(pairs)
+    }
+}
+
+///
+/// `LogPos` as kept in a hashed index, when it fits
+///
+#[derive(Debug, Clone, Copy)]
+struct PackedPos {
+    offset: u64,
+    version: u64,
+    gen: u32,
+    len: u32,
+    /// 0 if the value isn't stored apart, blob files are numbered from 1
+    blob_file: u32,
+    blob_len: u32,
+}
+
+impl PackedPos {
+
+    ///
+    /// None if a field doesn't fit
+    ///
+    fn pack(pos: LogPos) -> Option<Self> {
+        let (blob_file, blob_len) = pos.blob.unwrap_or((0, 0));
+        Some(PackedPos {
+            offset: pos.offset,
+            version: pos.version,
+            gen: u32::try_from(pos.gen).ok()?,
+            len: u32::try_from(pos.len).ok()?,
+            blob_file: u32::try_from(blob_file).ok()?,
+            blob_len: u32::try_from(blob_len).ok()?,
+        })
+    }
+
+    fn unpack(&self) -> LogPos {
+        LogPos {
+            gen: self.gen as u64,
+            offset: self.offset,
+            len: self.len as u64,
+            version: self.version,
+            blob: match self.blob_file {
+                0 => None,
+                blob_file => Some((blob_file as u64, self.blob_len as u64)),
+            },
+        }
+    }
+}
+
+///
+/// hash a key is kept under in `IndexMode::Hashed`
+///
+fn hash_key(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key);
+    hasher.finish()
+}
@@ -0,0 +1,2231 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::thread;
+
+use super::engine::{self, Result, KvsEngine, KvError, WriteBatch, BatchOp, Transaction, EngineKind};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::ops::{Bound, RangeBounds};
+
+use self::record::{LogEntry, Format};
+use self::hint::Hint;
+use self::commit::{GroupCommit, WriteOp};
+use self::crypto::Keyring;
+use self::compress::Compressor;
+use self::blob::{BlobFile, BlobRef, BLOB_HEADER_LEN};
+use self::index::{KeyIndex, KeyReader};
+use self::cache::{ValueCache, CachedValue};
+pub use self::options::{KvStoreOptions, SyncPolicy};
+pub use self::snapshot::KvSnapshot;
+pub use self::crypto::EncryptionKey;
+pub use self::compress::Compression;
+pub use self::index::IndexMode;
+
+/// binary, checksummed record format of the log
+mod record;
+/// hint files, which let a compacted segment be indexed without reading it
+mod hint;
+/// options to open a store with
+mod options;
+/// group commit of concurrent writes
+mod commit;
+/// point-in-time read-only views
+mod snapshot;
+/// authenticated encryption of records and hint files
+mod crypto;
+/// compression of values
+mod compress;
+/// blob files, which hold large values apart from the log
+mod blob;
+/// index of the keys, whole or hashed
+mod index;
+/// cache of the values read lately
+mod cache;
+
+/// default directory of the store
+pub const DEFAULT_PATH: &'static str = "./database";
+/// single log file written by earlier versions, adopted as the first segment on open
+const LEGACY_LOG_FILE: &'static str = "data.log";
+/// extension of segment files, which are named `<generation>.log`
+const SEGMENT_EXT: &'static str = "log";
+/// extension of hint files, which are named after the segment they describe
+const HINT_EXT: &'static str = "hint";
+/// extension of blob files, which are named `<id>.blob`
+const BLOB_EXT: &'static str = "blob";
+/// suffix of the files written by a compaction before they are swapped in
+const COMPACTION_TMP_SUFFIX: &'static str = ".tmp";
+/// how many times a read looks the key up again when its segment was compacted away meanwhile
+const READ_RETRIES: usize = 3;
+/// how many removed keys to remember the version of, for transactions to detect conflicts
+const MAX_REMOVED_VERSIONS: usize = 64 * 1024;
+/// size of the chunks the active segment is copied in by a checkpoint
+const CHECKPOINT_CHUNK_SIZE: usize = 64 * 1024;
+
+///
+/// position of a record in the log: which segment, where and how long,
+/// along with the version of the write, see `Store::seq`
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LogPos {
+    gen: u64,
+    offset: u64,
+    len: u64,
+    version: u64,
+    /// blob file the value is in and the length of the blob, if it's stored apart
+    blob: Option<(u64, u64)>,
+}
+
+///
+/// what to do when the tail of the log turns out to be truncated or corrupt,
+/// typically after a crash in the middle of a write
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryMode {
+    /// truncate the log back to the last valid record and carry on
+    TruncateTail,
+    /// refuse to open the store
+    Strict,
+}
+
+impl Default for RecoveryMode {
+    fn default() -> Self {
+        RecoveryMode::TruncateTail
+    }
+}
+
+///
+/// outcome of the tail recovery done when opening a store
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RecoveryReport {
+    /// segment whose tail was truncated, if any
+    pub truncated_segment: Option<u64>,
+    /// number of bytes discarded from that segment
+    pub discarded_bytes: u64,
+}
+
+///
+/// figures about the log of a store, see `KvStore::stats`
+///
+/// values are counted as they are encoded since the store was opened,
+/// by writes and by compactions, which rewrite every live value
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KvStoreStats {
+    /// bytes of all the segments
+    pub log_bytes: u64,
+    /// bytes taken by records which are overwritten, removed or expired
+    pub stale_bytes: u64,
+    /// values encoded
+    pub values: u64,
+    /// how many of them are stored compressed
+    pub compressed_values: u64,
+    /// their total size
+    pub value_bytes: u64,
+    /// their total size as stored
+    pub stored_value_bytes: u64,
+    /// bytes of all the blob files
+    pub blob_bytes: u64,
+    /// bytes taken by blobs whose record is overwritten, removed or expired
+    pub stale_blob_bytes: u64,
+    /// live keys
+    pub keys: u64,
+    /// rough estimate of the memory taken by the index of the keys
+    pub index_bytes: u64,
+    /// reads whose value was found in the value cache
+    pub cache_hits: u64,
+    /// reads whose value had to be read from disk, with the value cache on
+    pub cache_misses: u64,
+    /// bytes taken by the values cached
+    pub cache_bytes: u64,
+    /// times the log was synced: as the sync policy asks, on `KvsEngine::flush`, and when sealing a segment
+    pub syncs: u64,
+    /// writes appended since the log was last synced, a power loss may lose them
+    pub unsynced_writes: u64,
+    /// failures of the background work, see `KvStore::last_background_error`
+    pub background_errors: u64,
+}
+
+impl KvStoreStats {
+
+    ///
+    /// how many times smaller values are stored than they are, 1.0 if none was encoded yet
+    ///
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_value_bytes == 0 {
+            return 1.0;
+        }
+        self.value_bytes as f64 / self.stored_value_bytes as f64
+    }
+}
+
+///
+/// read-only handle of one segment, along with the format of its records
+///
+struct Segment {
+    file: File,
+    format: Format,
+    /// keys of the store, to decrypt the records with
+    keyring: Arc<Keyring>,
+    /// compression the values were written with, None if it's unknown
+    compression: Option<Compression>,
+}
+
+impl Segment {
+
+    ///
+    /// read and decode the record at `pos`
+    /// it's a positional read which leaves the file cursor alone, so any number of threads
+    /// can read the same segment at once
+    ///
+    fn read_entry(&self, pos: LogPos) -> Result<LogEntry> {
+        let mut raw = vec![0u8; pos.len as usize];
+        read_exact_at(&self.file, raw.as_mut_slice(), pos.offset)?;
+        record::decode(self.format, raw.as_slice(), pos.gen, pos.offset, &self.keyring)
+    }
+
+    ///
+    /// whether the segment holds no record, past its header
+    ///
+    fn is_empty(&self) -> Result<bool> {
+        let start = match self.format {
+            Format::Json => 0u64,
+            Format::Binary(_) => record::SEGMENT_HEADER_LEN,
+        };
+        Ok(self.file.metadata()?.len() <= start)
+    }
+}
+
+/// open segments by generation, shared between the writer and the readers
+type Segments = Arc<RwLock<BTreeMap<u64, Arc<Segment>>>>;
+/// position of the latest record of every live key, shared between the writer and the readers
+/// the lock is only held for the lookup, never while reading from disk
+type Index = Arc<RwLock<KeyIndex>>;
+/// open blob files by id, shared between the writer and the readers
+type Blobs = Arc<RwLock<BTreeMap<u64, Arc<BlobFile>>>>;
+
+impl KeyReader for Segments {
+    fn read_key(&self, pos: LogPos) -> Result<Vec<u8>> {
+        let segment = get_segment(self, pos.gen)?.ok_or(KvError::SegmentNotFound(pos.gen))?;
+        Ok(segment.read_entry(pos)?.key().to_vec())
+    }
+}
+
+///
+/// wrap Store with Arc & Mutex to make it share on multiple thread
+/// but with mutation support
+///
+/// reads don't go through `store` at all: they look the key up in the shared index
+/// and read the record from the shared segment handles, so they proceed in parallel
+/// with each other and with the single writer
+///
+#[derive(Clone)]
+pub struct KvStore {
+    index: Index,
+    segments: Segments,
+    blobs: Blobs,
+    store: Arc<Mutex<Store>>,
+    compressor: Arc<Compressor>,
+    cache: Arc<ValueCache>,
+    commit: Arc<GroupCommit>,
+    read_only: bool,
+    recovery: RecoveryReport,
+    background_errors: Arc<BackgroundErrors>,
+    /// the background threads stop once every clone of the store is dropped,
+    /// declared last so they're joined after the other handles on the store are dropped
+    _background: Arc<Background>,
+}
+
+///
+/// handle on the threads compacting and syncing the store in the background
+///
+/// dropping it tells them to stop, wakes them up and waits for them, after which they
+/// no longer hold the store, which is then synced and closed with the last handle on it
+///
+struct Background {
+    terminate: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Background {
+
+    ///
+    /// run `work` every `interval` on a new thread until the store is dropped
+    ///
+    fn spawn<F>(&mut self, interval: Duration, mut work: F) where F: FnMut() + Send + 'static {
+        let terminate = self.terminate.clone();
+        let handle = thread::spawn(move || loop {
+            let deadline = Instant::now() + interval;
+            loop {
+                if terminate.load(Ordering::SeqCst) {
+                    return;
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                // woken up early by `drop`, or spuriously, the deadline is checked again
+                thread::park_timeout(deadline - now);
+            }
+            work();
+        });
+        self.handles.push(handle);
+    }
+}
+
+///
+/// failures of the background work, which has no caller to return them to,
+/// nothing is lost by any of them, the work is tried again on the next round
+///
+#[derive(Default)]
+struct BackgroundErrors {
+    count: AtomicU64,
+    last: Mutex<Option<String>>,
+}
+
+impl BackgroundErrors {
+
+    ///
+    /// keep `err` of the background `work` if it's an error
+    ///
+    fn record(&self, work: &str, res: Result<()>) {
+        if let Err(err) = res {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            if let Ok(mut last) = self.last.lock() {
+                *last = Some(format!("{}: {:?}", work, err));
+            }
+        }
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        self.terminate.store(true, Ordering::SeqCst);
+        for handle in self.handles.iter() {
+            handle.thread().unpark();
+        }
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Default for KvStore {
+    fn default() -> Self {
+        KvStore::open(DEFAULT_PATH).expect("Fail to create default KvStore")
+    }
+}
+
+///
+/// implement KvStore
+///
+impl KvStore {
+
+    ///
+    /// return initialized KvStore
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    ///
+    /// options to open a KvStore with, see `KvStoreOptions`
+    ///
+    pub fn builder() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    ///
+    /// return initialized KvStore, configured by `options`
+    ///
+    fn open_with_options<P: AsRef<Path>>(path: P, options: KvStoreOptions) -> Result<Self> {
+        let inner_store = Store::open(path, options.clone())?;
+        let index = inner_store.data.clone();
+        let segments = inner_store.segments.clone();
+        let blobs = inner_store.blobs.clone();
+        let compressor = inner_store.compressor.clone();
+        let cache = inner_store.cache.clone();
+        let recovery = inner_store.recovery;
+        let read_only = options.read_only;
+        let store = Arc::new(Mutex::new(inner_store));
+        let mut background = Background { terminate: Arc::new(AtomicBool::new(false)), handles: Vec::new() };
+        let background_errors = Arc::new(BackgroundErrors::default());
+
+        if !read_only {
+            let store_cp = store.clone();
+            let errors = background_errors.clone();
+            background.spawn(options.compaction_interval, move || {
+                // the garbage stays until the next attempt
+                errors.record("compaction", check_and_do_compaction(store_cp.clone()));
+                errors.record("blob collection", check_and_do_blob_gc(store_cp.clone()));
+            });
+        }
+
+        // writes only check the clock when they come in, so the last ones before
+        // the store goes idle are synced from here
+        if let (false, SyncPolicy::Interval(interval)) = (read_only, options.sync_policy) {
+            let store_cp = store.clone();
+            let errors = background_errors.clone();
+            background.spawn(interval, move || {
+                if let Ok(mut guard) = store_cp.lock() {
+                    errors.record("sync", guard.sync_due());
+                }
+            });
+        }
+        Ok(KvStore {
+            index,
+            segments,
+            blobs,
+            store,
+            compressor,
+            cache,
+            commit: Arc::new(GroupCommit::new()),
+            read_only,
+            recovery,
+            background_errors,
+            _background: Arc::new(background),
+        })
+    }
+
+    ///
+    /// look `k` up and read its value along with its expiry and version,
+    /// None if it doesn't exist or expired
+    ///
+    fn read_latest(&self, k: &[u8]) -> Result<Option<(Vec<u8>, Option<u64>, u64)>> {
+        let mut missing_gen = 0;
+        for _ in 0..READ_RETRIES {
+            let pos = match self.index.read() {
+                Ok(guard) => match guard.lookup(k) {
+                    None => return Ok(None),
+                    Some(pos) => pos,
+                },
+                Err(_) => return Err(KvError::LockError),
+            };
+            // the segment may be compacted away after the lookup,
+            // by then the index points at the compacted copy
+            let value = match self.read_value(k, pos) {
+                // a record of another key with the same hash, see `IndexMode::Hashed`
+                Err(KvError::KeyNotFound) => return Ok(None),
+                value => value?,
+            };
+            match value {
+                Some((_, expires_at)) if is_expired(expires_at) => return Ok(None),
+                Some((value, expires_at)) => return Ok(Some((value, expires_at, pos.version))),
+                None => missing_gen = pos.gen,
+            }
+        }
+        Err(KvError::SegmentNotFound(missing_gen))
+    }
+
+    ///
+    /// read the value and expiry of `k` from the record at `pos`, from the value cache if it's
+    /// there, None if its segment is gone, or the blob file its value is in, which are both
+    /// collected the same way
+    /// the value of an expired record is left empty
+    /// `KeyNotFound` if the record isn't a set of `k`
+    ///
+    fn read_value(&self, k: &[u8], pos: LogPos) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        if let Some(cached) = self.cache.get(pos) {
+            if cached.key != k {
+                return Err(KvError::KeyNotFound);
+            }
+            return Ok(Some((cached.value.clone(), cached.expires_at)));
+        }
+        let read = self.read_value_from_disk(k, pos)?;
+        if let Some((value, expires_at)) = read.as_ref().filter(|(_, expires_at)| !is_expired(*expires_at)) {
+            self.cache.insert(pos, CachedValue { key: k.to_vec(), value: value.clone(), expires_at: *expires_at });
+        }
+        Ok(read)
+    }
+
+    fn read_value_from_disk(&self, k: &[u8], pos: LogPos) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        match get_segment(&self.segments, pos.gen)? {
+            Some(segment) => match segment.read_entry(pos)? {
+                ref entry if entry.key() != k => Err(KvError::KeyNotFound),
+                LogEntry::Set { value, expires_at, .. } => Ok(Some((value, expires_at))),
+                // the blob of an expired value may be collected already, it's not read at all
+                LogEntry::SetBlob { expires_at, .. } if is_expired(expires_at) => Ok(Some((Vec::new(), expires_at))),
+                LogEntry::SetBlob { blob, expires_at, .. } => match get_blob_file(&self.blobs, blob.file)? {
+                    Some(blob_file) => Ok(Some((blob_file.read(blob)?, expires_at))),
+                    None => Ok(None),
+                },
+                LogEntry::Remove(_) => Err(KvError::KeyNotFound),
+            },
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// report of the tail recovery done when the store was opened
+    ///
+    pub fn recovery_report(&self) -> RecoveryReport {
+        self.recovery
+    }
+
+    ///
+    /// latest failure of the background compaction, blob collection or sync, along with
+    /// which of them failed, None if none did since the store was opened
+    /// `KvStoreStats::background_errors` counts them
+    ///
+    pub fn last_background_error(&self) -> Option<String> {
+        self.background_errors.last.lock().ok().and_then(|last| last.clone())
+    }
+
+    ///
+    /// size of the log and the blob files, how much of them is garbage, how well values compress,
+    /// how much memory the index takes, and how well the value cache does
+    ///
+    pub fn stats(&self) -> Result<KvStoreStats> {
+        let ((log_bytes, stale_bytes), (blob_bytes, stale_blob_bytes), (syncs, unsynced_writes)) = match self.store.lock() {
+            Ok(guard) => (guard.stale_stats()?, guard.blob_stats()?, (guard.syncs, guard.unsynced_writes)),
+            Err(_) => return Err(KvError::LockError),
+        };
+        let (values, compressed_values, value_bytes, stored_value_bytes) = self.compressor.counts();
+        let (cache_hits, cache_misses, cache_bytes) = self.cache.counts();
+        let (keys, index_bytes) = match self.index.read() {
+            Ok(guard) => (guard.len() as u64, guard.memory_bytes()),
+            Err(_) => return Err(KvError::LockError),
+        };
+        Ok(KvStoreStats {
+            log_bytes,
+            stale_bytes,
+            values,
+            compressed_values,
+            value_bytes,
+            stored_value_bytes,
+            blob_bytes,
+            stale_blob_bytes,
+            keys,
+            index_bytes,
+            cache_hits,
+            cache_misses,
+            cache_bytes,
+            syncs,
+            unsynced_writes,
+            background_errors: self.background_errors.count.load(Ordering::SeqCst),
+        })
+    }
+
+}
+
+///
+/// core data structure for saving key/value pair
+///
+/// the log is split into numbered segments, only the one with the highest
+/// generation (the active segment) is appended to, the others are sealed
+///
+pub struct Store {
+    data: Index,
+    dir_path: PathBuf,
+    segments: Segments,
+    log_file: File,
+    current_gen: u64,
+    current_offset: u64,
+    /// bytes of every segment taken by records which are overwritten or removed
+    stale: BTreeMap<u64, u64>,
+    options: KvStoreOptions,
+    /// keys records are encrypted and decrypted with
+    keyring: Arc<Keyring>,
+    /// compresses the values written, and counts how well it does
+    compressor: Arc<Compressor>,
+    /// values read lately, shared with the readers
+    cache: Arc<ValueCache>,
+    /// segments holding records which aren't written the way the options ask for: encrypted
+    /// with another key than the current one, or compressed otherwise,
+    /// the next compaction rewrites them whatever the garbage
+    outdated: BTreeSet<u64>,
+    blobs: Blobs,
+    /// blob file values at least `blob_threshold` long are appended to, with its id,
+    /// created on the first such value
+    blob_file: Option<(u64, File)>,
+    blob_offset: u64,
+    /// id of the next blob file, whether it's written by a set or by a blob collection
+    next_blob_id: u64,
+    /// bytes of every blob file taken by blobs whose record is overwritten, removed or expired
+    blob_stale: BTreeMap<u64, u64>,
+    /// blob files not written the way the options ask for, like `outdated`,
+    /// the next blob collection rewrites them whatever the garbage
+    outdated_blobs: BTreeSet<u64>,
+    /// writes appended since the active segment was last synced
+    unsynced_writes: u64,
+    last_sync: Instant,
+    /// times the active segment was synced since the store was opened
+    syncs: u64,
+    /// sequence number of the latest write, stamped on the index entries as their version
+    /// versions only live in memory, whatever is loaded at open is version 0
+    seq: u64,
+    /// version at which keys were removed, the oldest are forgotten past `MAX_REMOVED_VERSIONS`
+    removed: BTreeMap<Vec<u8>, u64>,
+    /// removals up to this version may be forgotten
+    removed_floor: u64,
+    recovery: RecoveryReport,
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if !self.options.read_only {
+            self.sync().expect("Fail to drop KvStore before sync data")
+        }
+    }
+}
+
+///
+/// implementation of KvStore
+///
+impl Store {
+
+    ///
+    /// internal set without compaction, `expires_at` replaces any previous expiry of the key
+    ///
+    fn set_internal(&mut self, k: Vec<u8>, v: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        // create log entry, serialize, write to log file
+        let entry = self.separate_value(LogEntry::Set {
+            key: k.clone(),
+            value: v,
+            expires_at,
+        })?;
+        self.seq += 1;
+        let pos = self.append(&entry)?;
+        self.removed.remove(&k);
+        // set in-memory position
+        let old_pos = self.data.write().map_err(|_| KvError::LockError)?.insert(k, pos, &self.segments)?;
+        if let Some(old_pos) = old_pos {
+            self.add_stale(old_pos);
+        }
+        self.maybe_roll_segment()
+    }
+
+    ///
+    /// internal remove without compaction
+    ///
+    fn remove_internal(&mut self, k: Vec<u8>) -> Result<()> {
+        // an expired key is left for compaction to purge
+        if self.read_current(&k)?.is_none() {
+            return Err(KvError::KeyNotFound);
+        }
+        let entry = LogEntry::Remove(k.clone());
+        self.seq += 1;
+        let pos = self.append(&entry)?;
+        let old_pos = self.data.write().map_err(|_| KvError::LockError)?.remove(&k, &self.segments)?;
+        self.mark_removed(k);
+        if let Some(old_pos) = old_pos {
+            self.add_stale(old_pos);
+        }
+        // the tombstone itself is garbage as soon as it's written
+        self.add_stale(pos);
+        self.maybe_roll_segment()
+    }
+
+    ///
+    /// internal batch write without compaction, the batch is appended as one unit
+    /// and becomes visible to the readers all at once
+    ///
+    fn write_batch_internal(&mut self, batch: WriteBatch) -> Result<()> {
+        let index = self.data.clone();
+        let data = index.read().map_err(|_| KvError::LockError)?;
+        // whether each key touched so far exists after the preceding ops of the batch
+        let mut exists: BTreeMap<Vec<u8>, bool> = BTreeMap::new();
+        let mut entries = Vec::with_capacity(batch.len());
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set(key, value) => {
+                    exists.insert(key.clone(), true);
+                    entries.push(LogEntry::Set { key, value, expires_at: None });
+                },
+                BatchOp::Remove(key) => {
+                    let found = match exists.get(&key) {
+                        Some(&found) => found,
+                        None => data.get(&key, &self.segments)?.is_some(),
+                    };
+                    if found {
+                        exists.insert(key.clone(), false);
+                        entries.push(LogEntry::Remove(key));
+                    }
+                },
+            }
+        }
+        drop(data);
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let entries = entries.into_iter()
+            .map(|entry| self.separate_value(entry))
+            .collect::<Result<Vec<_>>>()?;
+
+        // the whole batch is one write, of a single version
+        self.seq += 1;
+        let positions = self.append_batch(&entries)?;
+        let mut data = index.write().map_err(|_| KvError::LockError)?;
+        for (entry, pos) in entries.into_iter().zip(positions) {
+            let old_pos = match entry {
+                LogEntry::Set { key, .. } | LogEntry::SetBlob { key, .. } => {
+                    self.removed.remove(&key);
+                    data.insert(key, pos, &self.segments)?
+                },
+                LogEntry::Remove(key) => {
+                    self.add_stale(pos);
+                    let old_pos = data.remove(&key, &self.segments)?;
+                    self.mark_removed(key);
+                    old_pos
+                },
+            };
+            if let Some(old_pos) = old_pos {
+                self.add_stale(old_pos);
+            }
+        }
+        drop(data);
+        self.maybe_roll_segment()
+    }
+
+    ///
+    /// internal compare-and-swap without compaction
+    ///
+    fn compare_and_swap_internal(&mut self, k: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        let current = self.read_current(&k)?.map(|(value, _)| value);
+        if current != expected {
+            return Err(KvError::CompareAndSwapFailed(current));
+        }
+        match new {
+            Some(v) => self.set_internal(k, v, None),
+            None if current.is_some() => self.remove_internal(k),
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// internal change of the expiry of an existing key without compaction
+    ///
+    fn expire_internal(&mut self, k: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        match self.read_current(&k)? {
+            None => Err(KvError::KeyNotFound),
+            Some((_, old_expires_at)) if old_expires_at == expires_at => Ok(()),
+            Some((value, _)) => self.set_internal(k, value, expires_at),
+        }
+    }
+
+    ///
+    /// read the value and expiry of `k`, None if it doesn't exist or expired
+    /// segments can't be compacted away under the store lock
+    ///
+    fn read_current(&self, k: &[u8]) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let pos = match self.data.read().map_err(|_| KvError::LockError)?.lookup(k) {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        match self.cache.get(pos) {
+            Some(ref cached) if cached.key != k => return Ok(None),
+            Some(ref cached) if is_expired(cached.expires_at) => return Ok(None),
+            Some(cached) => return Ok(Some((cached.value.clone(), cached.expires_at))),
+            None => {},
+        }
+        let segment = get_segment(&self.segments, pos.gen)?
+            .ok_or(KvError::SegmentNotFound(pos.gen))?;
+        match segment.read_entry(pos)? {
+            // a record of another key with the same hash, see `IndexMode::Hashed`
+            ref entry if entry.key() != k => Ok(None),
+            LogEntry::Set { expires_at, .. } |
+            LogEntry::SetBlob { expires_at, .. } if is_expired(expires_at) => Ok(None),
+            LogEntry::Set { value, expires_at, .. } => Ok(Some((value, expires_at))),
+            LogEntry::SetBlob { blob, expires_at, .. } => {
+                let blob_file = get_blob_file(&self.blobs, blob.file)?
+                    .ok_or(KvError::BlobNotFound(blob.file))?;
+                Ok(Some((blob_file.read(blob)?, expires_at)))
+            },
+            LogEntry::Remove(_) => Err(KvError::KeyNotFound),
+        }
+    }
+
+    ///
+    /// internal transaction commit without compaction: check that no key the transaction
+    /// depends on was written since it began, then apply its writes as a batch
+    ///
+    fn commit_internal(&mut self, txn: Transaction) -> Result<()> {
+        let data = self.data.read().map_err(|_| KvError::LockError)?;
+        for key in txn.reads.keys().chain(txn.writes.keys()) {
+            let version = match (data.get(key, &self.segments)?, self.removed.get(key)) {
+                (Some(pos), _) => pos.version,
+                (None, Some(&version)) => version,
+                // never written since open, or removed so long ago it's forgotten
+                (None, None) => self.removed_floor,
+            };
+            if version > txn.start {
+                return Err(KvError::TransactionConflict);
+            }
+        }
+        drop(data);
+        self.write_batch_internal(txn.write_batch())
+    }
+
+    ///
+    /// remember the version `key` is removed at
+    ///
+    fn mark_removed(&mut self, key: Vec<u8>) {
+        if self.removed.len() >= MAX_REMOVED_VERSIONS {
+            self.removed.clear();
+            self.removed_floor = self.seq;
+        }
+        self.removed.insert(key, self.seq);
+    }
+
+    ///
+    /// account the record at `pos` as garbage, along with its blob if its value is stored apart,
+    /// and drop its value from the cache
+    ///
+    fn add_stale(&mut self, pos: LogPos) {
+        self.cache.remove(pos);
+        *self.stale.entry(pos.gen).or_insert(0) += pos.len;
+        if let Some((blob_id, blob_len)) = pos.blob {
+            *self.blob_stale.entry(blob_id).or_insert(0) += blob_len;
+        }
+    }
+
+    ///
+    /// store the value of a set apart in a blob file if it's at least `blob_threshold` long,
+    /// the record then only points at it
+    ///
+    fn separate_value(&mut self, entry: LogEntry) -> Result<LogEntry> {
+        match entry {
+            LogEntry::Set { key, value, expires_at }
+                if self.options.blob_threshold.map_or(false, |threshold| value.len() >= threshold) => {
+                let blob = self.append_blob(&value)?;
+                Ok(LogEntry::SetBlob { key, blob, expires_at })
+            },
+            entry => Ok(entry),
+        }
+    }
+
+    ///
+    /// encode `value` as a blob and append it to the active blob file, return where it is
+    /// the active blob file is sealed once it reaches `blob_file_size`, and a new one is
+    /// started on the next value
+    ///
+    fn append_blob(&mut self, value: &[u8]) -> Result<BlobRef> {
+        if self.blob_offset >= self.options.blob_file_size {
+            if let Some((_, file)) = self.blob_file.take() {
+                if self.options.sync_policy != SyncPolicy::Never {
+                    file.sync_data()?;
+                }
+            }
+        }
+        if self.blob_file.is_none() {
+            let id = self.next_blob_id;
+            let path = blob_path(&self.dir_path, id);
+            let mut file = Self::open_file(&path)?;
+            file.write_all(&blob::header(self.options.compression, &self.keyring))?;
+            file.flush()?;
+            let blob_file = BlobFile::open(&path, &self.keyring)?;
+            self.blobs.write().map_err(|_| KvError::LockError)?.insert(id, Arc::new(blob_file));
+            self.next_blob_id += 1;
+            self.blob_file = Some((id, file));
+            self.blob_offset = BLOB_HEADER_LEN;
+        }
+        let raw = blob::encode(value, &self.compressor, &self.keyring);
+        let (id, file) = self.blob_file.as_mut().expect("Fail to start a blob file");
+        file.write_all(raw.as_slice())?;
+        let blob = BlobRef { file: *id, offset: self.blob_offset, len: raw.len() as u64 };
+        self.blob_offset += blob.len;
+        Ok(blob)
+    }
+
+    ///
+    /// encode entry, append it to the active segment and return its position
+    ///
+    fn append(&mut self, entry: &LogEntry) -> Result<LogPos> {
+        let raw = record::encode(entry, self.current_gen, self.current_offset, &self.compressor, &self.keyring);
+        self.log_file.write_all(raw.as_slice())?;
+        self.unsynced_writes += 1;
+        let pos = LogPos {
+            gen: self.current_gen,
+            offset: self.current_offset,
+            len: raw.len() as u64,
+            version: self.seq,
+            blob: blob_of(entry),
+        };
+        self.current_offset += pos.len;
+        Ok(pos)
+    }
+
+    ///
+    /// encode entries as a batch, append it to the active segment with a single write
+    /// and return the position of every entry
+    ///
+    fn append_batch(&mut self, entries: &[LogEntry]) -> Result<Vec<LogPos>> {
+        let records = record::encode_batch(entries, self.current_gen, self.current_offset, &self.compressor,
+                                           &self.keyring);
+        self.log_file.write_all(records.concat().as_slice())?;
+        self.unsynced_writes += 1;
+        let mut positions = Vec::with_capacity(records.len());
+        for (raw, entry) in records.into_iter().zip(entries) {
+            let pos = LogPos {
+                gen: self.current_gen,
+                offset: self.current_offset,
+                len: raw.len() as u64,
+                version: self.seq,
+                blob: blob_of(entry),
+            };
+            self.current_offset += pos.len;
+            positions.push(pos);
+        }
+        Ok(positions)
+    }
+
+    ///
+    /// sync the active segment if the sync policy asks for it by now,
+    /// called once per batch of writes, see `GroupCommit`
+    ///
+    fn sync_due(&mut self) -> Result<()> {
+        let due = match self.options.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryWrites(n) => self.unsynced_writes >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if due && self.unsynced_writes > 0 {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// force everything appended so far to disk, blobs first as the records point at them
+    ///
+    fn sync(&mut self) -> Result<()> {
+        if let Some((_, file)) = self.blob_file.as_ref() {
+            file.sync_data()?;
+        }
+        self.log_file.sync_data()?;
+        self.unsynced_writes = 0;
+        self.last_sync = Instant::now();
+        self.syncs += 1;
+        Ok(())
+    }
+
+    ///
+    /// seal the active segment once it reaches the segment size
+    ///
+    fn maybe_roll_segment(&mut self) -> Result<()> {
+        if self.current_offset >= self.options.segment_size {
+            let next_gen = self.current_gen + 1;
+            self.roll_segment(next_gen)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// seal the active segment and start appending to segment `gen`
+    ///
+    fn roll_segment(&mut self, gen: u64) -> Result<()> {
+        // pending writes of a sealed segment would never be synced by the policy otherwise
+        if self.options.sync_policy != SyncPolicy::Never && self.unsynced_writes > 0 {
+            self.sync()?;
+        }
+        let (log_file, segment) = Self::open_segment(&self.dir_path, gen, self.options.compression, &self.keyring)?;
+        self.log_file = log_file;
+        self.add_segment(gen, segment)?;
+        self.current_gen = gen;
+        self.current_offset = record::SEGMENT_HEADER_LEN;
+        Ok(())
+    }
+
+    ///
+    /// make segment `gen` visible to the readers
+    ///
+    fn add_segment(&self, gen: u64, segment: Segment) -> Result<()> {
+        match self.segments.write() {
+            Ok(mut guard) => {
+                guard.insert(gen, Arc::new(segment));
+                Ok(())
+            },
+            Err(_) => Err(KvError::LockError),
+        }
+    }
+
+    ///
+    /// generations of all the segments, in ascending order
+    ///
+    fn gens(&self) -> Result<Vec<u64>> {
+        match self.segments.read() {
+            Ok(guard) => Ok(guard.keys().cloned().collect()),
+            Err(_) => Err(KvError::LockError),
+        }
+    }
+
+    ///
+    /// return initialized Store
+    ///
+    pub fn open<P: AsRef<Path>>(dir: P, options: KvStoreOptions) -> Result<Self> {
+        let dir = dir.as_ref();
+        Self::ensure_path(dir, &options)?;
+        let legacy = Self::adopt_legacy_log(dir, &options)?;
+        // the file segment `gen` is read from, the legacy log stands for the first one
+        // as long as it isn't adopted
+        let segment_file = |gen: u64| if legacy { dir.join(LEGACY_LOG_FILE) } else { segment_path(dir, gen) };
+
+        let keyring = Arc::new(Keyring::new(options.encryption_key.as_ref(), &options.old_encryption_keys));
+        let compression = options.compression;
+        let compressor = Arc::new(Compressor::new(compression, options.compression_threshold));
+        let index_mode = options.index_mode;
+        let cache = Arc::new(ValueCache::new(options.value_cache_size));
+        let mut gens = Self::list_gens(dir, SEGMENT_EXT)?;
+        if gens.is_empty() {
+            gens.push(1);
+        }
+        let mut segments = BTreeMap::new();
+        let mut outdated = BTreeSet::new();
+        for &gen in gens.iter() {
+            let segment = if options.read_only {
+                Self::open_segment_reader(&segment_file(gen), &keyring)?
+            } else {
+                Self::open_segment(dir, gen, compression, &keyring)?.1
+            };
+            // compaction recompresses the values, or stores them raw, as the options now ask
+            if segment.compression != Some(compression) {
+                outdated.insert(gen);
+            }
+            segments.insert(gen, Arc::new(segment));
+        }
+
+        // a new blob file is started rather than appended to, the last one may be outdated
+        let blob_ids = Self::list_gens(dir, BLOB_EXT)?;
+        let mut blobs = BTreeMap::new();
+        let mut outdated_blobs = BTreeSet::new();
+        for &id in blob_ids.iter() {
+            let blob_file = BlobFile::open(&blob_path(dir, id), &keyring)?;
+            if blob_file.is_outdated(compression) {
+                outdated_blobs.insert(id);
+            }
+            blobs.insert(id, Arc::new(blob_file));
+        }
+        let next_blob_id = blob_ids.last().map_or(1, |id| id + 1);
+
+        // keep appending to the last segment unless it's already full,
+        // or it's a legacy json log which is never appended to
+        // a read-only store never appends, its handle on the last segment is just never used
+        let mut current_gen = gens[gens.len() - 1];
+        // a crash can only tear the last segment holding records, the empty ones after it
+        // are left by a roll, or by an open which failed after it started a new segment
+        let mut tail_gen = current_gen;
+        for &gen in gens.iter().rev() {
+            tail_gen = gen;
+            if !segments[&gen].is_empty()? {
+                break;
+            }
+        }
+        let log_file = if options.read_only {
+            File::open(segment_file(current_gen))?
+        } else {
+            if fs::metadata(segment_path(dir, current_gen))?.len() >= options.segment_size ||
+                segments[&current_gen].format != Format::Binary(record::FORMAT_VERSION) {
+                current_gen += 1;
+                let (_, segment) = Self::open_segment(dir, current_gen, compression, &keyring)?;
+                segments.insert(current_gen, Arc::new(segment));
+            }
+            Self::open_segment(dir, current_gen, compression, &keyring)?.0
+        };
+
+        let mut kv_store = Store {
+            data: Arc::new(RwLock::new(KeyIndex::new(index_mode))),
+            dir_path: PathBuf::from(dir),
+            segments: Arc::new(RwLock::new(segments)),
+            log_file,
+            current_gen,
+            current_offset: 0u64,
+            stale: BTreeMap::new(),
+            options,
+            keyring,
+            compressor,
+            cache,
+            outdated,
+            blobs: Arc::new(RwLock::new(blobs)),
+            blob_file: None,
+            blob_offset: 0,
+            next_blob_id,
+            blob_stale: BTreeMap::new(),
+            outdated_blobs,
+            unsynced_writes: 0,
+            last_sync: Instant::now(),
+            syncs: 0,
+            seq: 0,
+            removed: BTreeMap::new(),
+            removed_floor: 0,
+            recovery: RecoveryReport::default(),
+        };
+        kv_store.load_data(tail_gen)?;
+        kv_store.count_stale()?;
+        // the active segment only gets records encrypted with the current key and compressed
+        // as the options ask, so that the outdated ones can be told by segment
+        if !kv_store.options.read_only && kv_store.outdated.contains(&kv_store.current_gen) {
+            let next_gen = kv_store.current_gen + 1;
+            kv_store.roll_segment(next_gen)?;
+        }
+        Ok(kv_store)
+    }
+
+    ///
+    /// Prepare the directory
+    /// In order not to mess up with other engine dir
+    /// Path must meet
+    /// 1. not exist
+    /// 2. exist but not a file and
+    ///   a. must be empty
+    ///   b. must not be marked as belonging to another engine
+    ///   c. if non-empty, must ONLY contain segment files, hint files, blob files or
+    ///      `LEGACY_LOG_FILE`, besides the engine marker and files left over by an interrupted
+    ///      compaction or blob collection, which are removed
+    ///   d. return Err for another case
+    /// then whether a store already exists is checked against `create_if_missing`,
+    /// `error_if_exists` and `read_only`, and a directory opened for writing gets marked
+    ///
+    fn ensure_path(path: &Path, options: &KvStoreOptions) -> Result<()> {
+        let mut exists = false;
+        if path.exists() {
+            if path.is_file() {
+                return Err(KvError::DirPathExpected);
+            }
+            engine::check_engine_marker(path, EngineKind::Kvs)?;
+
+            for dir_entry in fs::read_dir(path)? {
+                let dir_entry = dir_entry?;
+                let file_name = dir_entry.file_name();
+                let mut name = file_name.to_str().unwrap_or("");
+                if name == engine::ENGINE_MARKER_FILE {
+                    continue;
+                }
+                let leftover = name.ends_with(COMPACTION_TMP_SUFFIX);
+                if leftover {
+                    name = &name[..name.len() - COMPACTION_TMP_SUFFIX.len()];
+                }
+                if name != LEGACY_LOG_FILE &&
+                    parse_gen(name, SEGMENT_EXT).is_none() &&
+                    parse_gen(name, HINT_EXT).is_none() &&
+                    parse_gen(name, BLOB_EXT).is_none() {
+                    return Err(KvError::FileMismatchInPath);
+                }
+                if leftover {
+                    if !options.read_only {
+                        fs::remove_file(dir_entry.path())?;
+                    }
+                } else if name == LEGACY_LOG_FILE || parse_gen(name, SEGMENT_EXT).is_some() {
+                    exists = true;
+                }
+            }
+        }
+        if exists && options.error_if_exists {
+            return Err(KvError::StoreExists);
+        }
+        if !exists && (options.read_only || !options.create_if_missing) {
+            return Err(KvError::StoreNotFound);
+        }
+        if !options.read_only {
+            fs::create_dir_all(path)?;
+            engine::write_engine_marker(path, EngineKind::Kvs)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// a directory written before segments were introduced only holds `LEGACY_LOG_FILE`,
+    /// rename it so that it's picked up as the first segment
+    /// a read-only store leaves it alone and reads it in place as the first segment,
+    /// return whether it does so
+    ///
+    fn adopt_legacy_log(dir: &Path, options: &KvStoreOptions) -> Result<bool> {
+        let legacy_path = dir.join(LEGACY_LOG_FILE);
+        if legacy_path.exists() {
+            if !Self::list_gens(dir, SEGMENT_EXT)?.is_empty() {
+                return Err(KvError::UnexpectedLogFile);
+            }
+            if options.read_only {
+                return Ok(true);
+            }
+            fs::rename(legacy_path, segment_path(dir, 1))?;
+        }
+        Ok(false)
+    }
+
+    ///
+    /// generations of the `<generation>.<ext>` files in `dir`, segments or blob files,
+    /// in ascending order
+    ///
+    fn list_gens(dir: &Path, ext: &str) -> Result<Vec<u64>> {
+        let mut gens = vec![];
+        for dir_entry in fs::read_dir(dir)? {
+            let file_name = dir_entry?.file_name();
+            if let Some(gen) = parse_gen(file_name.to_str().unwrap_or(""), ext) {
+                gens.push(gen);
+            }
+        }
+        gens.sort();
+        Ok(gens)
+    }
+
+    ///
+    /// open (or create) the segment `gen`, return a handle to append to it and one to read it
+    /// a new segment starts with the binary format header, telling values are compressed
+    /// with `compression`
+    ///
+    fn open_segment(dir: &Path, gen: u64, compression: Compression, keyring: &Arc<Keyring>) -> Result<(File, Segment)> {
+        let path = segment_path(dir, gen);
+        let mut file = Self::open_file(&path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(&record::segment_header(compression))?;
+            file.flush()?;
+        }
+        Ok((file, Self::open_segment_reader(&path, keyring)?))
+    }
+
+    ///
+    /// open the existing segment at `path` for reading
+    ///
+    fn open_segment_reader(path: &Path, keyring: &Arc<Keyring>) -> Result<Segment> {
+        let mut file = File::open(path)?;
+        let (format, compression) = record::read_header(&mut file)?;
+        Ok(Segment { file, format, keyring: keyring.clone(), compression })
+    }
+
+    ///
+    /// Open file with Read + Append + Create
+    ///
+    fn open_file<P: AsRef<Path>>(path: P) -> io::Result<File> { // FIXME: convert to local Result
+        OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path.as_ref())
+    }
+
+    ///
+    /// load all the segments, from their hint file when there is a valid one,
+    /// otherwise by replaying all the records
+    /// only a corrupt tail in `tail_gen`, the last segment holding records, can be recovered
+    ///
+    fn load_data(&mut self, tail_gen: u64) -> Result<()> {
+        for gen in self.gens()? {
+            if let Some(offset) = self.load_hints(gen)? {
+                if gen == self.current_gen {
+                    self.current_offset = offset;
+                }
+                continue;
+            }
+            let offset = match self.load_segment(gen) {
+                Ok(offset) => offset,
+                Err(KvError::CorruptedLog(_, offset))
+                    if gen == tail_gen && self.options.recovery_mode == RecoveryMode::TruncateTail => {
+                    let discarded_bytes = self.truncate_segment(gen, offset)?;
+                    self.recovery = RecoveryReport {
+                        truncated_segment: Some(gen),
+                        discarded_bytes,
+                    };
+                    offset
+                },
+                Err(err) => return Err(err),
+            };
+            if gen == self.current_gen {
+                self.current_offset = offset;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// garbage of every segment and blob file is whatever the index doesn't point at
+    ///
+    fn count_stale(&mut self) -> Result<()> {
+        let mut live = BTreeMap::new();
+        let mut live_blobs = BTreeMap::new();
+        for pos in self.data.read().map_err(|_| KvError::LockError)?.positions() {
+            *live.entry(pos.gen).or_insert(0) += pos.len;
+            if let Some((blob_id, blob_len)) = pos.blob {
+                *live_blobs.entry(blob_id).or_insert(0) += blob_len;
+            }
+        }
+        let blobs: Vec<(u64, Arc<BlobFile>)> = self.blobs.read().map_err(|_| KvError::LockError)?
+            .iter()
+            .map(|(&id, blob_file)| (id, blob_file.clone()))
+            .collect();
+        for (id, blob_file) in blobs {
+            let blobs_len = blob_file.len()?.saturating_sub(BLOB_HEADER_LEN);
+            let live_len = live_blobs.get(&id).cloned().unwrap_or(0);
+            self.blob_stale.insert(id, blobs_len.saturating_sub(live_len));
+        }
+        for gen in self.gens()? {
+            let segment = get_segment(&self.segments, gen)?
+                .ok_or(KvError::SegmentNotFound(gen))?;
+            let header_len = match segment.format {
+                Format::Json => 0,
+                Format::Binary(_) => record::SEGMENT_HEADER_LEN,
+            };
+            let records_len = segment.file.metadata()?.len().saturating_sub(header_len);
+            let live_len = live.get(&gen).cloned().unwrap_or(0);
+            self.stale.insert(gen, records_len.saturating_sub(live_len));
+        }
+        Ok(())
+    }
+
+    ///
+    /// total bytes of the log and how many of them are garbage
+    ///
+    fn stale_stats(&self) -> Result<(u64, u64)> {
+        let mut total = 0;
+        for gen in self.gens()? {
+            if gen == self.current_gen {
+                total += self.current_offset;
+            } else if let Some(segment) = get_segment(&self.segments, gen)? {
+                total += segment.file.metadata()?.len();
+            }
+        }
+        Ok((total, self.stale.values().sum()))
+    }
+
+    ///
+    /// total bytes of the blob files and how many of them are garbage
+    ///
+    fn blob_stats(&self) -> Result<(u64, u64)> {
+        let mut total = 0;
+        for (&id, blob_file) in self.blobs.read().map_err(|_| KvError::LockError)?.iter() {
+            total += match self.blob_file {
+                Some((active_id, _)) if active_id == id => self.blob_offset,
+                _ => blob_file.len()?,
+            };
+        }
+        Ok((total, self.blob_stale.values().sum()))
+    }
+
+    ///
+    /// first step of a compaction, under the store lock: check whether there is enough garbage
+    /// and if so, seal the active segment so that every existing segment is an input
+    /// return the generation of the segment to compact into, which sorts between the inputs
+    /// and the new active segment
+    ///
+    fn start_compaction(&mut self) -> Result<Option<u64>> {
+        let (total, stale) = self.stale_stats()?;
+        if self.outdated.is_empty() && (stale < self.options.compaction_min_stale_bytes ||
+            (stale as f64) < (total as f64) * self.options.compaction_stale_ratio) {
+            return Ok(None);
+        }
+        let compaction_gen = self.current_gen + 1;
+        self.roll_segment(compaction_gen + 1)?;
+        Ok(Some(compaction_gen))
+    }
+
+    ///
+    /// last step of a compaction, under the store lock: point the index at the copied records,
+    /// unless they were overwritten or removed while copying, drop the expired keys which
+    /// weren't copied, then drop the inputs
+    ///
+    fn finish_compaction(&mut self, compaction_gen: u64, copied: CompactionOutput) -> Result<()> {
+        let mut stale = 0;
+        let mut data = self.data.write().map_err(|_| KvError::LockError)?;
+        for (key, old_pos, new_pos) in copied.moved {
+            if !data.replace(&key, old_pos, new_pos) {
+                stale += new_pos.len;
+            }
+        }
+        let mut expired_blobs = vec![];
+        for (key, old_pos) in copied.expired {
+            if data.remove_at(&key, old_pos) {
+                expired_blobs.extend(old_pos.blob);
+            }
+        }
+        drop(data);
+        self.stale.insert(compaction_gen, stale);
+        // the record goes with its input, its blob is left to the blob collection
+        for (blob_id, blob_len) in expired_blobs {
+            *self.blob_stale.entry(blob_id).or_insert(0) += blob_len;
+        }
+
+        let stale_gens: Vec<u64> = self.gens()?.into_iter()
+            .filter(|&gen| gen < compaction_gen)
+            .collect();
+        for gen in stale_gens {
+            match self.segments.write() {
+                Ok(mut segments) => segments.remove(&gen),
+                Err(_) => return Err(KvError::LockError),
+            };
+            self.stale.remove(&gen);
+            self.outdated.remove(&gen);
+            self.cache.remove_segment(gen);
+            // hint goes first, a segment left without one is just replayed
+            remove_file_if_exists(&hint_path(&self.dir_path, gen))?;
+            fs::remove_file(segment_path(&self.dir_path, gen))?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// index segment `gen` from its hint file, return the offset of its end
+    /// `None` if it has no usable hint file and must be replayed
+    ///
+    fn load_hints(&mut self, gen: u64) -> Result<Option<u64>> {
+        let segment = get_segment(&self.segments, gen)?
+            .ok_or(KvError::SegmentNotFound(gen))?;
+        let segment_len = segment.file.metadata()?.len();
+        let (hints, key_id) = match hint::read_hints(&hint_path(&self.dir_path, gen), segment_len, &self.keyring)? {
+            Some(hints) => hints,
+            None => return Ok(None),
+        };
+        // a hint file is encrypted with the key of the records of its segment
+        if key_id != self.keyring.current_id() {
+            self.outdated.insert(gen);
+        }
+        let mut data = self.data.write().map_err(|_| KvError::LockError)?;
+        for Hint { key, offset, len, blob } in hints {
+            data.insert(key, LogPos { gen, offset, len, version: 0, blob }, &self.segments)?;
+        }
+        Ok(Some(segment_len))
+    }
+
+    ///
+    /// replay the records of segment `gen`, return the offset of its end
+    /// `CorruptedLog` points at the first record that can't be decoded,
+    /// or at the start of a batch which is cut short, so that it's dropped as a whole
+    ///
+    fn load_segment(&mut self, gen: u64) -> Result<u64> {
+        let segment = get_segment(&self.segments, gen)?
+            .ok_or(KvError::SegmentNotFound(gen))?;
+        let mut offset = match segment.format {
+            Format::Json => 0u64,
+            Format::Binary(_) => record::SEGMENT_HEADER_LEN,
+        };
+        let mut reader = BufReader::new(&segment.file);
+        reader.seek(SeekFrom::Start(offset))?;
+        let index = self.data.clone();
+        let mut data = index.write().map_err(|_| KvError::LockError)?;
+        // records of a batch are held back until its last record shows up
+        let mut batch = Vec::new();
+        let mut batch_start = offset;
+        loop {
+            let next = match record::read_next(segment.format, &mut reader, gen, offset, &self.keyring) {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(KvError::CorruptedRecord) |
+                Err(KvError::ChecksumMismatch) |
+                Err(KvError::SerdeJsonError(_)) => {
+                    return Err(KvError::CorruptedLog(gen, batch_start));
+                },
+                Err(err) => return Err(err),
+            };
+            if next.key_id != self.keyring.current_id() {
+                self.outdated.insert(gen);
+            }
+            let blob = blob_of(&next.entry);
+            batch.push((next.entry, LogPos { gen, offset, len: next.len, version: 0, blob }));
+            offset += next.len;
+            if next.in_batch {
+                continue;
+            }
+            for (entry, pos) in batch.drain(..) {
+                match entry {
+                    LogEntry::Set { key, .. } | LogEntry::SetBlob { key, .. } => {
+                        data.insert(key, pos, &self.segments)?;
+                    },
+                    LogEntry::Remove(key) => {
+                        data.remove(&key, &self.segments)?;
+                    },
+                };
+            }
+            batch_start = offset;
+        }
+        if !batch.is_empty() {
+            return Err(KvError::CorruptedLog(gen, batch_start));
+        }
+        Ok(offset)
+    }
+
+    ///
+    /// cut segment `gen` at `offset`, return the number of bytes discarded
+    /// a read-only store leaves the file alone, the bytes are only skipped
+    ///
+    fn truncate_segment(&mut self, gen: u64, offset: u64) -> Result<u64> {
+        let len = get_segment(&self.segments, gen)?
+            .ok_or(KvError::SegmentNotFound(gen))?
+            .file.metadata()?.len();
+        if !self.options.read_only {
+            let file = OpenOptions::new().write(true).open(segment_path(&self.dir_path, gen))?;
+            file.set_len(offset)?;
+            file.sync_all()?;
+        }
+        Ok(len - offset)
+    }
+
+    ///
+    /// first step of a checkpoint into `dest`, under the store lock: hard-link every sealed
+    /// segment along with its hint file, and every sealed blob file, which are never written
+    /// again, and hand back the active ones with how much of them is written, to be copied
+    /// without the lock
+    ///
+    fn link_sealed(&self, dest: &Path) -> Result<CheckpointFiles> {
+        let mut linked = vec![];
+        let active_blob = self.blob_file.as_ref().map(|(id, _)| *id);
+        let mut active_blob_file = None;
+        for (&id, blob_file) in self.blobs.read().map_err(|_| KvError::LockError)?.iter() {
+            if Some(id) == active_blob {
+                active_blob_file = Some((id, blob_file.clone(), self.blob_offset));
+                continue;
+            }
+            let path = blob_path(dest, id);
+            link_or_copy(&blob_path(&self.dir_path, id), &path)?;
+            linked.push(path);
+        }
+        for gen in self.gens()? {
+            if gen == self.current_gen {
+                continue;
+            }
+            let path = segment_path(dest, gen);
+            link_or_copy(&segment_path(&self.dir_path, gen), &path)?;
+            linked.push(path);
+            let hint_file = hint_path(&self.dir_path, gen);
+            if hint_file.exists() {
+                link_or_copy(&hint_file, &hint_path(dest, gen))?;
+            }
+        }
+        let active = get_segment(&self.segments, self.current_gen)?
+            .ok_or(KvError::SegmentNotFound(self.current_gen))?;
+        Ok(CheckpointFiles {
+            linked,
+            active: (self.current_gen, active, self.current_offset),
+            active_blob: active_blob_file,
+        })
+    }
+
+    ///
+    /// first step of a blob collection, under the store lock: pick a sealed blob file which
+    /// is outdated, or else the one with the most garbage if it's over `compaction_stale_ratio`,
+    /// and list the live keys whose value is in it
+    /// return the victim, the id of the blob file to copy its live blobs into,
+    /// and the position of the keys
+    ///
+    fn start_blob_gc(&mut self) -> Result<Option<(u64, u64, Vec<LogPos>)>> {
+        let active_blob = self.blob_file.as_ref().map(|(id, _)| *id);
+        let mut victim = None;
+        let mut most_stale = 0;
+        for (&id, blob_file) in self.blobs.read().map_err(|_| KvError::LockError)?.iter() {
+            if Some(id) == active_blob {
+                continue;
+            }
+            if self.outdated_blobs.contains(&id) {
+                victim = Some(id);
+                break;
+            }
+            let blobs_len = blob_file.len()?.saturating_sub(BLOB_HEADER_LEN);
+            let stale = self.blob_stale.get(&id).cloned().unwrap_or(0);
+            if stale > most_stale && (stale as f64) >= (blobs_len as f64) * self.options.compaction_stale_ratio {
+                most_stale = stale;
+                victim = Some(id);
+            }
+        }
+        let victim = match victim {
+            Some(victim) => victim,
+            None => return Ok(None),
+        };
+        let entries = self.data.read().map_err(|_| KvError::LockError)?
+            .positions()
+            .filter(|pos| pos.blob.map(|(blob_id, _)| blob_id) == Some(victim))
+            .collect();
+        let target = self.next_blob_id;
+        self.next_blob_id += 1;
+        Ok(Some((victim, target, entries)))
+    }
+
+    ///
+    /// last step of a blob collection, under the store lock: append a record pointing at
+    /// the copy of every blob, unless the key was written, removed or compacted while copying,
+    /// remove the expired keys which weren't copied, then drop the victim unless a key still
+    /// points into it, it's then collected again
+    ///
+    fn finish_blob_gc(&mut self, victim: u64, target: u64, copied: BlobGcOutput) -> Result<()> {
+        let mut appended = false;
+        for (key, old_pos, blob, expires_at) in copied.moved {
+            if !self.data.read().map_err(|_| KvError::LockError)?.is_at(&key, old_pos) {
+                *self.blob_stale.entry(target).or_insert(0) += blob.len;
+                continue;
+            }
+            // the value itself doesn't change, nor does its version
+            let mut pos = self.append(&LogEntry::SetBlob { key: key.clone(), blob, expires_at })?;
+            pos.version = old_pos.version;
+            self.data.write().map_err(|_| KvError::LockError)?.replace(&key, old_pos, pos);
+            self.add_stale(old_pos);
+            appended = true;
+            self.maybe_roll_segment()?;
+        }
+        for (key, old_pos) in copied.expired {
+            if !self.data.read().map_err(|_| KvError::LockError)?.is_at(&key, old_pos) {
+                continue;
+            }
+            // without a tombstone the record would be indexed again on reopen,
+            // pointing at a blob which is gone
+            let pos = self.append(&LogEntry::Remove(key.clone()))?;
+            self.data.write().map_err(|_| KvError::LockError)?.remove_at(&key, old_pos);
+            self.add_stale(old_pos);
+            self.add_stale(pos);
+            appended = true;
+            self.maybe_roll_segment()?;
+        }
+        let in_use = self.data.read().map_err(|_| KvError::LockError)?
+            .positions()
+            .any(|pos| pos.blob.map(|(blob_id, _)| blob_id) == Some(victim));
+        if in_use {
+            return Ok(());
+        }
+        // the records pointing at the copies and the tombstones must be on disk before the blobs go
+        if appended {
+            self.sync()?;
+        }
+        self.blobs.write().map_err(|_| KvError::LockError)?.remove(&victim);
+        self.blob_stale.remove(&victim);
+        self.outdated_blobs.remove(&victim);
+        fs::remove_file(blob_path(&self.dir_path, victim))?;
+        Ok(())
+    }
+
+}
+
+///
+/// look up the segment `gen`, `None` if it doesn't exist (anymore)
+///
+fn get_segment(segments: &Segments, gen: u64) -> Result<Option<Arc<Segment>>> {
+    match segments.read() {
+        Ok(guard) => Ok(guard.get(&gen).cloned()),
+        Err(_) => Err(KvError::LockError),
+    }
+}
+
+///
+/// look up the blob file `id`, `None` if it doesn't exist (anymore)
+///
+fn get_blob_file(blobs: &Blobs, id: u64) -> Result<Option<Arc<BlobFile>>> {
+    match blobs.read() {
+        Ok(guard) => Ok(guard.get(&id).cloned()),
+        Err(_) => Err(KvError::LockError),
+    }
+}
+
+///
+/// blob file and length of the blob of `entry`, if its value is stored apart
+///
+fn blob_of(entry: &LogEntry) -> Option<(u64, u64)> {
+    match entry {
+        LogEntry::SetBlob { blob, .. } => Some((blob.file, blob.len)),
+        _ => None,
+    }
+}
+
+///
+/// fill `buf` from `offset` of `file` without moving its cursor
+///
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+///
+/// fill `buf` from `offset` of `file`, every call carries its own offset
+///
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+///
+/// path of the segment `gen` in `dir`
+///
+fn segment_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, SEGMENT_EXT))
+}
+
+///
+/// path of the hint file of segment `gen` in `dir`
+///
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.{}", gen, HINT_EXT))
+}
+
+///
+/// path of the blob file `id` in `dir`
+///
+fn blob_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.{}", id, BLOB_EXT))
+}
+
+///
+/// parse the generation out of a `<generation>.<ext>` file name, `None` if it doesn't match
+///
+pub(crate) fn parse_gen(file_name: &str, ext: &str) -> Option<u64> {
+    let path = Path::new(file_name);
+    if path.extension() != Some(ext.as_ref()) {
+        return None;
+    }
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.parse::<u64>().ok())
+}
+
+///
+/// hard-link `src` to `dest`, or copy it if it can't be linked, across file systems for one
+///
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
+    }
+    Ok(())
+}
+
+///
+/// copy the first `len` bytes of `file`, a segment or a blob file, into `dest` and sync it
+///
+pub(crate) fn copy_file_prefix(file: &File, len: u64, dest: &Path) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(dest)?);
+    let mut buf = vec![0u8; CHECKPOINT_CHUNK_SIZE];
+    let mut offset = 0u64;
+    while offset < len {
+        let n = CHECKPOINT_CHUNK_SIZE.min((len - offset) as usize);
+        read_exact_at(file, &mut buf[..n], offset)?;
+        writer.write_all(&buf[..n])?;
+        offset += n as u64;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+///
+/// remove a file which may not exist
+///
+pub(crate) fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+///
+/// check the garbage in the log and do compaction if there is too much of it in a separate thread,
+/// or if some segments hold records which aren't encrypted with the current key,
+/// or compressed otherwise than the options ask
+/// action:
+/// - under the store lock, seal the active segment so that every existing segment is an input
+/// - without any lock, copy every live record of the inputs into `<compaction gen>.log.tmp`,
+///   which sorts between the inputs and the new active segment, along with its hint file
+/// - swap the new files in by renaming them, make the new segment visible to the readers
+/// - under the store lock, point the index at the copied records and delete the inputs
+/// - return
+///
+/// readers and writers carry on while records are copied, a reader that still holds a position
+/// in a deleted input looks the key up again
+/// values stored apart are not copied, only the records pointing at them, see `check_and_do_blob_gc`
+///
+fn check_and_do_compaction(store: Arc<Mutex<Store>>) -> Result<()> {
+    let (compaction_gen, dir_path, index, segments, compressor, keyring) = match store.lock() {
+        Ok(mut guard) => match guard.start_compaction()? {
+            None => return Ok(()),
+            Some(gen) => (gen, guard.dir_path.clone(), guard.data.clone(), guard.segments.clone(),
+                          guard.compressor.clone(), guard.keyring.clone()),
+        },
+        Err(_) => return Err(KvError::LockError),
+    };
+
+    let copied = match copy_live_records(&dir_path, &index, &segments, &compressor, &keyring, compaction_gen) {
+        Ok(copied) => copied,
+        Err(err) => {
+            let _ = fs::remove_file(tmp_path(&segment_path(&dir_path, compaction_gen)));
+            let _ = fs::remove_file(tmp_path(&hint_path(&dir_path, compaction_gen)));
+            return Err(err);
+        },
+    };
+
+    match store.lock() {
+        Ok(mut guard) => guard.finish_compaction(compaction_gen, copied),
+        Err(_) => Err(KvError::LockError),
+    }
+}
+
+///
+/// check the garbage in the blob files and collect one of them if there is too much of it,
+/// or if it's not encrypted with the current key, or compressed otherwise than the options ask
+/// action:
+/// - under the store lock, pick the victim and list the keys whose value is in it
+/// - without any lock, copy their blobs into `<target id>.blob.tmp`, swap it in by renaming it
+///   and make it visible to the readers
+/// - under the store lock, append a record pointing at every copy, and delete the victim
+/// - return
+///
+/// a reader that still holds a position in the victim once it's deleted looks the key up again
+///
+fn check_and_do_blob_gc(store: Arc<Mutex<Store>>) -> Result<()> {
+    let (victim, target, entries, dir_path, segments, blobs, compressor, keyring) = match store.lock() {
+        Ok(mut guard) => match guard.start_blob_gc()? {
+            None => return Ok(()),
+            Some((victim, target, entries)) => (victim, target, entries, guard.dir_path.clone(),
+                guard.segments.clone(), guard.blobs.clone(), guard.compressor.clone(), guard.keyring.clone()),
+        },
+        Err(_) => return Err(KvError::LockError),
+    };
+
+    let copied = match copy_live_blobs(&dir_path, &segments, &blobs, &compressor, &keyring, victim, target, entries) {
+        Ok(copied) => copied,
+        Err(err) => {
+            let _ = fs::remove_file(tmp_path(&blob_path(&dir_path, target)));
+            return Err(err);
+        },
+    };
+
+    match store.lock() {
+        Ok(mut guard) => guard.finish_blob_gc(victim, target, copied),
+        Err(_) => Err(KvError::LockError),
+    }
+}
+
+///
+/// what a blob collection did with the live keys of its victim
+///
+struct BlobGcOutput {
+    /// keys whose blob is copied with their old position, the copy and their expiry
+    moved: Vec<(Vec<u8>, LogPos, BlobRef, Option<u64>)>,
+    /// expired keys left out with their old position
+    expired: Vec<(Vec<u8>, LogPos)>,
+}
+
+///
+/// copy the live blobs of `entries` from blob file `victim` into blob file `target`, and make it
+/// visible to the readers, blobs of expired keys are purged
+/// every blob is encrypted with the current key and compressed as the options now ask on the way
+/// a key whose segment was compacted away meanwhile is skipped, its victim is kept
+///
+fn copy_live_blobs(dir: &Path, segments: &Segments, blobs: &Blobs, compressor: &Arc<Compressor>,
+                   keyring: &Arc<Keyring>, victim: u64, target: u64,
+                   entries: Vec<LogPos>) -> Result<BlobGcOutput> {
+    let victim_file = get_blob_file(blobs, victim)?.ok_or(KvError::BlobNotFound(victim))?;
+    let path = blob_path(dir, target);
+    let mut writer = None;
+    let mut offset = BLOB_HEADER_LEN;
+    let mut moved = vec![];
+    let mut expired = vec![];
+    for old_pos in entries {
+        let segment = match get_segment(segments, old_pos.gen)? {
+            Some(segment) => segment,
+            None => continue,
+        };
+        let entry = segment.read_entry(old_pos)?;
+        let key = entry.key().to_vec();
+        let (blob, expires_at) = match entry {
+            LogEntry::SetBlob { expires_at, .. } if is_expired(expires_at) => {
+                expired.push((key, old_pos));
+                continue;
+            },
+            LogEntry::SetBlob { blob, expires_at, .. } => (blob, expires_at),
+            _ => return Err(KvError::CorruptedRecord),
+        };
+        let raw = blob::encode(&victim_file.read(blob)?, compressor, keyring);
+        if writer.is_none() {
+            let mut file = BufWriter::new(File::create(tmp_path(&path))?);
+            file.write_all(&blob::header(compressor.compression(), keyring))?;
+            writer = Some(file);
+        }
+        writer.as_mut().expect("Fail to start a blob file").write_all(raw.as_slice())?;
+        let new_blob = BlobRef { file: target, offset, len: raw.len() as u64 };
+        offset += new_blob.len;
+        moved.push((key, old_pos, new_blob, expires_at));
+    }
+    // a victim left with no live blob isn't copied at all
+    if let Some(mut writer) = writer {
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        fs::rename(tmp_path(&path), &path)?;
+        let blob_file = BlobFile::open(&path, keyring)?;
+        blobs.write().map_err(|_| KvError::LockError)?.insert(target, Arc::new(blob_file));
+    }
+    Ok(BlobGcOutput { moved, expired })
+}
+
+///
+/// what a checkpoint linked under the store lock, and what it's left to copy
+///
+struct CheckpointFiles {
+    /// linked files, which may not be synced yet
+    linked: Vec<PathBuf>,
+    /// active segment with how much of it is written
+    active: (u64, Arc<Segment>, u64),
+    /// active blob file, if any, with how much of it is written
+    active_blob: Option<(u64, Arc<BlobFile>, u64)>,
+}
+
+///
+/// what a compaction did with the live keys of its inputs
+///
+struct CompactionOutput {
+    /// keys copied with their old and new position
+    moved: Vec<(Vec<u8>, LogPos, LogPos)>,
+    /// expired keys left out with their old position
+    expired: Vec<(Vec<u8>, LogPos)>,
+}
+
+///
+/// copy the live records of the segments before `compaction_gen` into segment `compaction_gen`,
+/// and make it visible to the readers, records which expired are purged
+/// every record is encrypted with the current key on the way, which is how keys are rotated,
+/// and its value compressed as the options now ask
+///
+fn copy_live_records(dir: &Path, index: &Index, segments: &Segments, compressor: &Arc<Compressor>,
+                     keyring: &Arc<Keyring>, compaction_gen: u64) -> Result<CompactionOutput> {
+    let entries: Vec<LogPos> = index.read()
+        .map_err(|_| KvError::LockError)?
+        .positions()
+        .filter(|pos| pos.gen < compaction_gen)
+        .collect();
+
+    let path = segment_path(dir, compaction_gen);
+    let mut writer = BufWriter::new(File::create(tmp_path(&path))?);
+    writer.write_all(&record::segment_header(compressor.compression()))?;
+    let mut offset = record::SEGMENT_HEADER_LEN;
+    let mut moved = vec![];
+    let mut expired = vec![];
+    let mut hints = vec![];
+    for old_pos in entries {
+        let segment = get_segment(segments, old_pos.gen)?
+            .ok_or(KvError::SegmentNotFound(old_pos.gen))?;
+        let entry = segment.read_entry(old_pos)?;
+        let key = entry.key().to_vec();
+        match entry {
+            LogEntry::Set { expires_at, .. } |
+            LogEntry::SetBlob { expires_at, .. } if is_expired(expires_at) => {
+                expired.push((key, old_pos));
+                continue;
+            },
+            _ => {},
+        }
+        // re-encode rather than copy, legacy json records get converted on the way
+        // a value stored apart stays where it is, only the record pointing at it is copied
+        let raw = record::encode(&entry, compaction_gen, offset, compressor, keyring);
+        writer.write_all(raw.as_slice())?;
+        let len = raw.len() as u64;
+        let blob = old_pos.blob;
+        hints.push(Hint { key: key.clone(), offset, len, blob });
+        moved.push((key, old_pos, LogPos { gen: compaction_gen, offset, len, version: old_pos.version, blob }));
+        offset += len;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    let hint_file = hint_path(dir, compaction_gen);
+    hint::write_hints(&tmp_path(&hint_file), offset, &hints, keyring)?;
+    // segment goes first, a segment left without its hint is just replayed
+    fs::rename(tmp_path(&path), &path)?;
+    fs::rename(tmp_path(&hint_file), &hint_file)?;
+
+    let (_, segment) = Store::open_segment(dir, compaction_gen, compressor.compression(), keyring)?;
+    match segments.write() {
+        Ok(mut guard) => guard.insert(compaction_gen, Arc::new(segment)),
+        Err(_) => return Err(KvError::LockError),
+    };
+    Ok(CompactionOutput { moved, expired })
+}
+
+///
+/// milliseconds since the unix epoch, what expiries are expressed in
+///
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+///
+/// expiry of a key living for `ttl` from now
+///
+pub(crate) fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+///
+/// whether a record with expiry `expires_at` is gone by now
+///
+pub(crate) fn is_expired(expires_at: Option<u64>) -> bool {
+    is_expired_at(expires_at, now_millis())
+}
+
+///
+/// whether a record with expiry `expires_at` is gone at time `now`
+///
+pub(crate) fn is_expired_at(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.map_or(false, |expires_at| expires_at <= now)
+}
+
+///
+/// path a compaction writes `path` to before swapping it in
+///
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(COMPACTION_TMP_SUFFIX);
+    PathBuf::from(name)
+}
+
+impl KvsEngine for KvStore {
+
+    type Snapshot = KvSnapshot;
+
+    ///
+    /// save key/value pair
+    ///
+    fn set(&self, k: impl Into<Vec<u8>>, v: impl Into<Vec<u8>>) -> Result<()> {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.commit.write(&self.store, WriteOp::Set(k.into(), v.into(), None))
+    }
+
+    ///
+    /// save key/value pair expiring after `ttl`
+    ///
+    fn set_with_ttl(&self, k: impl Into<Vec<u8>>, v: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.commit.write(&self.store, WriteOp::Set(k.into(), v.into(), Some(expiry_after(ttl))))
+    }
+
+    ///
+    /// make an existing key expire after `ttl`
+    ///
+    fn expire(&self, k: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.commit.write(&self.store, WriteOp::Expire(k.into(), Some(expiry_after(ttl))))
+    }
+
+    ///
+    /// time left before a key expires, without taking the store lock
+    ///
+    fn ttl(&self, k: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        match self.read_latest(k.as_ref())? {
+            None => Err(KvError::KeyNotFound),
+            Some((_, expires_at, _)) => Ok(expires_at.map(|expires_at|
+                Duration::from_millis(expires_at.saturating_sub(now_millis()))
+            )),
+        }
+    }
+
+    ///
+    /// make an existing key never expire
+    ///
+    fn persist(&self, k: impl Into<Vec<u8>>) -> Result<()> {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.commit.write(&self.store, WriteOp::Expire(k.into(), None))
+    }
+
+    ///
+    /// get value by key, without taking the store lock
+    ///
+    fn get(&self, k: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_latest(k.as_ref())?.map(|(value, _, _)| value))
+    }
+
+    ///
+    /// scan the ordered index, then read the values without holding its lock
+    /// expired keys are left out, the index is scanned further until `limit` is reached
+    /// a hashed index only hands out the positions of its keys, which are read back from the log
+    /// and sorted once for the whole scan, without its lock, see `IndexMode::Hashed`
+    ///
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if engine::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        // the segments are held on to along with the positions, a compaction may delete them meanwhile
+        let hashed = match self.index.read() {
+            Ok(guard) => match guard.hashed_keys(&range) {
+                Some(keys) => Some((keys, self.segments.read().map_err(|_| KvError::LockError)?.clone())),
+                None => None,
+            },
+            Err(_) => return Err(KvError::LockError),
+        };
+        let mut sorted = match hashed {
+            Some((keys, segments)) => Some(keys.sort(&range, &segments)?.into_iter()),
+            None => None,
+        };
+        let mut pairs = Vec::new();
+        loop {
+            let wanted = limit.map_or(usize::max_value(), |limit| limit - pairs.len());
+            if wanted == 0 || engine::is_empty_range(&range) {
+                break;
+            }
+            let positions = match sorted.as_mut() {
+                Some(sorted) => sorted.by_ref().take(wanted).collect(),
+                None => match self.index.read() {
+                    Ok(guard) => guard.scan(&range, wanted, &self.segments)?,
+                    Err(_) => return Err(KvError::LockError),
+                },
+            };
+            let exhausted = positions.len() < wanted;
+            if let Some((last, _)) = positions.last() {
+                range.0 = Bound::Excluded(last.clone());
+            }
+            for (k, pos) in positions {
+                let value = match self.read_value(&k, pos)? {
+                    Some((_, expires_at)) if is_expired(expires_at) => None,
+                    Some((value, _)) => Some(value),
+                    // compacted away since the scan, look the key up again
+                    None => self.get(&k)?,
+                };
+                // a key removed since the scan is just left out
+                if let Some(value) = value {
+                    pairs.push((k, value));
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+        Ok(pairs)
+    }
+
+    ///
+    /// force the writes acknowledged so far to disk, whatever the sync policy
+    ///
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        match self.store.lock() {
+            Ok(mut guard) => guard.sync(),
+            Err(_) => Err(KvError::LockError),
+        }
+    }
+
+    ///
+    /// remove key/value pair from KvStore
+    ///
+    fn remove(&self, k: impl Into<Vec<u8>>) -> Result<()> {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.commit.write(&self.store, WriteOp::Remove(k.into()))
+    }
+
+    ///
+    /// apply a batch of sets and removes atomically
+    ///
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.commit.write(&self.store, WriteOp::Batch(batch))
+    }
+
+    ///
+    /// compare-and-swap through the group commit, so that it's checked under the store lock
+    ///
+    fn compare_and_swap(&self, k: impl Into<Vec<u8>>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.commit.write(&self.store, WriteOp::CompareAndSwap(k.into(), expected, new))
+    }
+
+    ///
+    /// begin a transaction on the latest write
+    ///
+    fn begin(&self) -> Result<Transaction> {
+        match self.store.lock() {
+            Ok(guard) => Ok(Transaction::new(guard.seq)),
+            Err(_) => Err(KvError::LockError),
+        }
+    }
+
+    ///
+    /// get value by key within a transaction, without taking the store lock
+    ///
+    fn txn_get(&self, txn: &mut Transaction, k: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let k = k.into();
+        if let Some(value) = txn.pending(&k) {
+            return Ok(value);
+        }
+        if let Some((value, _)) = txn.reads.get(&k) {
+            return Ok(value.clone());
+        }
+        let (value, version) = match self.read_latest(&k)? {
+            Some((value, _, version)) => (Some(value), version),
+            None => (None, 0),
+        };
+        // the value on the snapshot the transaction began on is gone
+        if version > txn.start {
+            return Err(KvError::TransactionConflict);
+        }
+        txn.reads.insert(k, (value.clone(), version));
+        Ok(value)
+    }
+
+    ///
+    /// snapshot the index along with the segments and blob files it points into
+    /// under the store lock, no compaction or segment roll is halfway through
+    /// the index is copied whole, which takes time and memory in proportion to the number
+    /// of keys, about `KvStoreStats::index_bytes`, while every write waits for the lock
+    ///
+    fn snapshot(&self) -> Result<KvSnapshot> {
+        let _guard = self.store.lock().map_err(|_| KvError::LockError)?;
+        let segments = self.segments.read().map_err(|_| KvError::LockError)?.clone();
+        let blobs = self.blobs.read().map_err(|_| KvError::LockError)?.clone();
+        let index = self.index.read().map_err(|_| KvError::LockError)?.clone();
+        Ok(KvSnapshot::new(index, segments, blobs, now_millis()))
+    }
+
+    ///
+    /// link the sealed segments and blob files under the store lock, then copy the written part
+    /// of the active ones while writes carry on after it
+    /// a compaction can't delete the linked files, and the active segment
+    /// is read through its handle even if it's compacted away meanwhile
+    ///
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        engine::prepare_empty_dir(dest)?;
+        engine::write_engine_marker(dest, EngineKind::Kvs)?;
+        let files = match self.store.lock() {
+            Ok(guard) => guard.link_sealed(dest)?,
+            Err(_) => return Err(KvError::LockError),
+        };
+        let (active_gen, active, active_len) = files.active;
+        // blobs go first, the records copied may point at them
+        if let Some((blob_id, blob_file, blob_len)) = files.active_blob {
+            copy_file_prefix(blob_file.file(), blob_len, &blob_path(dest, blob_id))?;
+        }
+        copy_file_prefix(&active.file, active_len, &segment_path(dest, active_gen))?;
+        // the records of the linked files may not have reached the disk yet
+        for path in files.linked {
+            File::open(path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// commit a transaction through the group commit
+    ///
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        if self.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.commit.write(&self.store, WriteOp::Commit(txn))
+    }
+
+}
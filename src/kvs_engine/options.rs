@@ -0,0 +1,253 @@
+use std::path::Path;
+use std::time::Duration;
+
+use super::{Result, KvStore, RecoveryMode, EncryptionKey, Compression, IndexMode};
+
+/// max segment size (in bytes) before sealing it and rolling to a new one
+const DEFAULT_SEGMENT_SIZE: u64 = 1024 * 1024;
+/// schedule interval for compaction
+const DEFAULT_COMPACTION_INTERVAL: Duration = Duration::from_secs(5);
+/// compaction kicks in once this share of the bytes in the log is garbage
+const DEFAULT_COMPACTION_STALE_RATIO: f64 = 0.5;
+/// and there is at least that much garbage, not to rewrite small logs over and over
+const DEFAULT_COMPACTION_MIN_STALE_BYTES: u64 = DEFAULT_SEGMENT_SIZE;
+/// values shorter than that are stored raw, they hardly compress and it's not worth the time
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+/// max blob file size (in bytes) before sealing it and starting a new one
+const DEFAULT_BLOB_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+///
+/// when writes are forced to disk
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// leave it to the OS, a power loss may lose acknowledged writes
+    Never,
+    /// sync after every write before acknowledging it
+    Always,
+    /// sync at most this long after a write, up to that much of the latest writes may be lost
+    Interval(Duration),
+    /// sync once every this many writes, up to that many of the latest writes may be lost
+    EveryWrites(u64),
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::Never
+    }
+}
+
+///
+/// options to open a KvStore with, created by `KvStore::builder()`
+///
+/// ```ignore
+/// let store = KvStore::builder()
+///     .segment_size(16 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::Always)
+///     .open("./database")?;
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) segment_size: u64,
+    pub(super) compaction_stale_ratio: f64,
+    pub(super) compaction_min_stale_bytes: u64,
+    pub(super) compaction_interval: Duration,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) recovery_mode: RecoveryMode,
+    pub(super) read_only: bool,
+    pub(super) create_if_missing: bool,
+    pub(super) error_if_exists: bool,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    pub(super) old_encryption_keys: Vec<EncryptionKey>,
+    pub(super) compression: Compression,
+    pub(super) compression_threshold: usize,
+    pub(super) blob_threshold: Option<usize>,
+    pub(super) blob_file_size: u64,
+    pub(super) index_mode: IndexMode,
+    pub(super) value_cache_size: u64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            compaction_stale_ratio: DEFAULT_COMPACTION_STALE_RATIO,
+            compaction_min_stale_bytes: DEFAULT_COMPACTION_MIN_STALE_BYTES,
+            compaction_interval: DEFAULT_COMPACTION_INTERVAL,
+            sync_policy: SyncPolicy::default(),
+            recovery_mode: RecoveryMode::default(),
+            read_only: false,
+            create_if_missing: true,
+            error_if_exists: false,
+            encryption_key: None,
+            old_encryption_keys: vec![],
+            compression: Compression::default(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            blob_threshold: None,
+            blob_file_size: DEFAULT_BLOB_FILE_SIZE,
+            index_mode: IndexMode::default(),
+            value_cache_size: 0,
+        }
+    }
+}
+
+impl KvStoreOptions {
+
+    ///
+    /// size (in bytes) a segment grows to before it's sealed
+    ///
+    pub fn segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    ///
+    /// compact once this share (0.0 to 1.0) of the bytes in the log is garbage
+    ///
+    pub fn compaction_stale_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_stale_ratio = ratio;
+        self
+    }
+
+    ///
+    /// don't compact before there is at least that many bytes of garbage
+    ///
+    pub fn compaction_min_stale_bytes(mut self, bytes: u64) -> Self {
+        self.compaction_min_stale_bytes = bytes;
+        self
+    }
+
+    ///
+    /// how often the background thread checks whether to compact
+    ///
+    pub fn compaction_interval(mut self, interval: Duration) -> Self {
+        self.compaction_interval = interval;
+        self
+    }
+
+    ///
+    /// when writes are forced to disk
+    ///
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    ///
+    /// what to do with a torn or corrupt log tail
+    ///
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    ///
+    /// open without ever writing to the directory: writes fail with `ReadOnly`,
+    /// there is no compaction and a torn tail is skipped rather than truncated,
+    /// the log of a store written before segments were introduced is read where it is
+    ///
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    ///
+    /// create the store if the directory doesn't hold one yet, on by default
+    ///
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+
+    ///
+    /// fail if the directory already holds a store
+    ///
+    pub fn error_if_exists(mut self, error: bool) -> Self {
+        self.error_if_exists = error;
+        self
+    }
+
+    ///
+    /// encrypt every record written from now on with `key`, records are authenticated as well,
+    /// along with where they are written, a wrong key or a record tampered with or moved
+    /// elsewhere in the log fails with `DecryptionFailed`, but cutting records off the end of
+    /// the log or putting back an older copy of a whole segment goes unnoticed
+    /// records written in plain or with an old key are rewritten with it by the next compaction
+    ///
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    ///
+    /// a key records may still be encrypted with, only to read them until they are rewritten,
+    /// to rotate keys open the store with the new key along with the old one
+    ///
+    pub fn old_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.old_encryption_keys.push(key);
+        self
+    }
+
+    ///
+    /// compress the values written from now on with `compression`, none by default,
+    /// values are only stored compressed when it saves space
+    /// records written otherwise are rewritten as asked by the next compaction
+    ///
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    ///
+    /// values shorter than that many bytes are stored raw whatever the compression
+    ///
+    pub fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    ///
+    /// store values at least that many bytes long apart in blob files, the log only points
+    /// at them so that compactions don't copy them, off by default
+    /// a blob file is collected once `compaction_stale_ratio` of it is garbage
+    ///
+    pub fn blob_threshold(mut self, bytes: usize) -> Self {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    ///
+    /// size (in bytes) a blob file grows to before it's sealed
+    ///
+    pub fn blob_file_size(mut self, bytes: u64) -> Self {
+        self.blob_file_size = bytes;
+        self
+    }
+
+    ///
+    /// how keys are kept in memory, whole by default, `IndexMode::Hashed` bounds the memory
+    /// per key whatever its length, at the cost of scans, which read every key of the store
+    /// from disk and hold the ones in range in memory
+    ///
+    pub fn index_mode(mut self, mode: IndexMode) -> Self {
+        self.index_mode = mode;
+        self
+    }
+
+    ///
+    /// keep up to that many bytes of the values read lately in memory, least recently used
+    /// ones are evicted first, off by default
+    ///
+    pub fn value_cache_size(mut self, bytes: u64) -> Self {
+        self.value_cache_size = bytes;
+        self
+    }
+
+    ///
+    /// open the store in `path` with these options
+    ///
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<KvStore> {
+        KvStore::open_with_options(path, self.clone())
+    }
+}
@@ -0,0 +1,397 @@
+use std::io;
+use std::io::prelude::*;
+
+use serde::Deserialize;
+
+use super::{Result, KvError};
+use super::crypto::{self, Keyring};
+use super::compress::{self, Compression, Compressor};
+use super::blob::BlobRef;
+
+/// magic bytes at the start of every binary segment
+const SEGMENT_MAGIC: [u8; 4] = *b"KVSG";
+/// version of the binary record format
+/// 2: records may carry an expiry
+/// 3: records may be encrypted, bound to where they are written and to their key
+/// 4: values may be compressed, the segment header tells how its records were written
+/// 5: values may be stored apart in blob files
+pub const FORMAT_VERSION: u16 = 5;
+/// segment header: magic + format version + compression + reserved byte
+pub const SEGMENT_HEADER_LEN: u64 = 8;
+/// record prefix: length of the body + crc32 of the body
+const RECORD_PREFIX_LEN: usize = 8;
+/// record body header: flags + key length + value length
+const BODY_HEADER_LEN: usize = 9;
+/// flag marking a tombstone
+const FLAG_TOMBSTONE: u8 = 0x01;
+/// flag marking a record of a batch which more records of the same batch follow,
+/// the last record of a batch doesn't carry it and so commits the whole batch
+const FLAG_BATCH: u8 = 0x02;
+/// flag marking a record with an expiry, stored right after the body header
+const FLAG_EXPIRY: u8 = 0x04;
+/// flag marking an encrypted record, whose body past the flags is sealed by the keyring,
+/// only the batch flag is kept in plain next to it
+const FLAG_ENCRYPTED: u8 = 0x08;
+/// flag marking a record whose value is compressed with LZ4, value length is the compressed one
+const FLAG_LZ4: u8 = 0x10;
+/// flag marking a record whose value is stored in a blob file, the value is a `BlobRef` to it
+const FLAG_BLOB: u8 = 0x20;
+/// length of an expiry: milliseconds since the unix epoch
+const EXPIRY_LEN: usize = 8;
+/// associated data of an encrypted record: flags + generation + offset + key id
+const RECORD_AAD_LEN: usize = 21;
+
+///
+/// an entry of the log
+///
+#[derive(Debug)]
+pub enum LogEntry {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// when the key expires, in milliseconds since the unix epoch
+        expires_at: Option<u64>,
+    },
+    /// a set whose value is stored apart in a blob file
+    SetBlob {
+        key: Vec<u8>,
+        blob: BlobRef,
+        expires_at: Option<u64>,
+    },
+    Remove(Vec<u8>),
+}
+
+impl LogEntry {
+
+    ///
+    /// key the entry is about
+    ///
+    pub fn key(&self) -> &[u8] {
+        match self {
+            LogEntry::Set { key, .. } | LogEntry::SetBlob { key, .. } | LogEntry::Remove(key) => key,
+        }
+    }
+}
+
+///
+/// an entry of a legacy json log, which only held strings
+///
+#[derive(Deserialize)]
+enum JsonEntry {
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove(String),
+}
+
+impl From<JsonEntry> for LogEntry {
+    fn from(entry: JsonEntry) -> LogEntry {
+        match entry {
+            JsonEntry::Set { key, value } => LogEntry::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expires_at: None,
+            },
+            JsonEntry::Remove(key) => LogEntry::Remove(key.into_bytes()),
+        }
+    }
+}
+
+///
+/// how the records of a segment are encoded
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// one json line per record, written by earlier versions
+    Json,
+    /// checksummed binary records after a segment header of the given format version
+    Binary(u16),
+}
+
+///
+/// header written at the start of a new segment whose values are compressed with `compression`
+///
+pub fn segment_header(compression: Compression) -> [u8; SEGMENT_HEADER_LEN as usize] {
+    let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+    header[..4].copy_from_slice(&SEGMENT_MAGIC);
+    header[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header[6] = compression.id();
+    header
+}
+
+///
+/// detect the format of a segment from its first bytes, along with the compression
+/// its values were written with, None if it's unknown
+/// anything without the magic is treated as a legacy json log, which is never compressed,
+/// and so are segments written before compression was introduced
+///
+pub fn read_header<R: Read>(reader: &mut R) -> Result<(Format, Option<Compression>)> {
+    let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+    let n = read_full(reader, &mut header)?;
+    if n < SEGMENT_HEADER_LEN as usize || header[..4] != SEGMENT_MAGIC {
+        return Ok((Format::Json, Some(Compression::None)));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > FORMAT_VERSION {
+        return Err(KvError::UnsupportedFormatVersion(version));
+    }
+    Ok((Format::Binary(version), Compression::from_id(header[6])))
+}
+
+///
+/// a record read back from a segment
+///
+pub struct Record {
+    pub entry: LogEntry,
+    /// length on disk
+    pub len: u64,
+    /// whether more records of its batch follow
+    pub in_batch: bool,
+    /// id of the key it's encrypted with, None if it's in plain
+    pub key_id: Option<u32>,
+}
+
+///
+/// encode an entry into a binary record, its value compressed if the compressor finds it
+/// worth it, then encrypted if the keyring has a current key
+/// layout: | body len: u32 | crc32: u32 | flags: u8 | key len: u32 | value len: u32 |
+///         | expiry: u64, only with `FLAG_EXPIRY` | key | value |
+/// encrypted: | body len: u32 | crc32: u32 | flags: u8 | sealed rest of the plain body |
+/// the checksum covers the body as written, so a torn write is told apart from a wrong key
+/// an encrypted record is sealed for offset `offset` of segment `gen`, see `record_aad`
+///
+pub fn encode(entry: &LogEntry, gen: u64, offset: u64, compressor: &Compressor, keyring: &Keyring) -> Vec<u8> {
+    encode_flagged(entry, 0, gen, offset, compressor, keyring)
+}
+
+///
+/// encode the entries of a batch into back to back records starting at offset `offset`
+/// of segment `gen`, all of them but the last flagged as followed by more records of the batch
+///
+pub fn encode_batch(entries: &[LogEntry], gen: u64, offset: u64, compressor: &Compressor,
+                    keyring: &Keyring) -> Vec<Vec<u8>> {
+    let mut offset = offset;
+    entries.iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let flags = if i + 1 < entries.len() { FLAG_BATCH } else { 0 };
+            let raw = encode_flagged(entry, flags, gen, offset, compressor, keyring);
+            offset += raw.len() as u64;
+            raw
+        })
+        .collect()
+}
+
+fn encode_flagged(entry: &LogEntry, flags: u8, gen: u64, offset: u64, compressor: &Compressor,
+                  keyring: &Keyring) -> Vec<u8> {
+    let blob_ref;
+    let (flags, key, value, expires_at) = match entry {
+        LogEntry::Set { key, value, expires_at } =>
+            (flags, key.as_slice(), value.as_slice(), *expires_at),
+        LogEntry::SetBlob { key, blob, expires_at } => {
+            blob_ref = blob.encode();
+            (flags | FLAG_BLOB, key.as_slice(), &blob_ref[..], *expires_at)
+        },
+        LogEntry::Remove(key) => (flags | FLAG_TOMBSTONE, key.as_slice(), &[][..], None),
+    };
+    let flags = if expires_at.is_some() { flags | FLAG_EXPIRY } else { flags };
+    // a blob is compressed in its blob file
+    let compressed = match entry {
+        LogEntry::Set { .. } => compressor.compress(value),
+        LogEntry::SetBlob { .. } | LogEntry::Remove(_) => None,
+    };
+    let (flags, value) = match compressed.as_ref() {
+        Some(compressed) => (flags | FLAG_LZ4, compressed.as_slice()),
+        None => (flags, value),
+    };
+    let expiry_len = if expires_at.is_some() { EXPIRY_LEN } else { 0 };
+    let mut body = Vec::with_capacity(BODY_HEADER_LEN + expiry_len + key.len() + value.len());
+    body.push(flags);
+    body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    if let Some(expires_at) = expires_at {
+        body.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    body.extend_from_slice(key);
+    body.extend_from_slice(value);
+
+    let encrypted_flags = FLAG_ENCRYPTED | (flags & FLAG_BATCH);
+    let sealed = keyring.current_id()
+        .and_then(|key_id| keyring.seal(&body, &record_aad(encrypted_flags, gen, offset, key_id)));
+    if let Some(sealed) = sealed {
+        body.clear();
+        body.push(encrypted_flags);
+        body.extend_from_slice(&sealed);
+    }
+    let mut buf = Vec::with_capacity(RECORD_PREFIX_LEN + body.len());
+    buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    buf.extend_from_slice(&body);
+    buf
+}
+
+///
+/// associated data an encrypted record is sealed with: its plain flags, where it's written
+/// and the id of the key, so that it can't be moved or copied elsewhere in the log, which
+/// would make a stale value or a removed key live again, nor pass for a record of another key
+/// layout: | flags: u8 | gen: u64 | offset: u64 | key id: u32 |
+///
+fn record_aad(flags: u8, gen: u64, offset: u64, key_id: u32) -> [u8; RECORD_AAD_LEN] {
+    let mut aad = [0u8; RECORD_AAD_LEN];
+    aad[0] = flags;
+    aad[1..9].copy_from_slice(&gen.to_le_bytes());
+    aad[9..17].copy_from_slice(&offset.to_le_bytes());
+    aad[17..].copy_from_slice(&key_id.to_le_bytes());
+    aad
+}
+
+///
+/// decode a whole record (as located by the index) in the given format,
+/// read from offset `offset` of segment `gen`
+///
+pub fn decode(format: Format, raw: &[u8], gen: u64, offset: u64, keyring: &Keyring) -> Result<LogEntry> {
+    match format {
+        Format::Json => Ok(serde_json::from_slice::<JsonEntry>(raw)?.into()),
+        Format::Binary(_) => {
+            if raw.len() < RECORD_PREFIX_LEN {
+                return Err(KvError::CorruptedRecord);
+            }
+            let body_len = read_u32(&raw[0..4]) as usize;
+            let crc = read_u32(&raw[4..8]);
+            let body = &raw[RECORD_PREFIX_LEN..];
+            if body.len() != body_len {
+                return Err(KvError::CorruptedRecord);
+            }
+            if crc32fast::hash(body) != crc {
+                return Err(KvError::ChecksumMismatch);
+            }
+            decode_body(body, gen, offset, keyring)
+        }
+    }
+}
+
+///
+/// read the next record of segment `gen`, which starts at offset `offset`,
+/// `None` at the end of the segment
+///
+pub fn read_next<R: BufRead>(format: Format, reader: &mut R, gen: u64, offset: u64,
+                             keyring: &Keyring) -> Result<Option<Record>> {
+    match format {
+        Format::Json => {
+            let mut row = String::new();
+            let n = reader.read_line(&mut row)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            let entry: JsonEntry = serde_json::from_str(row.trim_end_matches('\n'))?;
+            Ok(Some(Record { entry: entry.into(), len: n as u64, in_batch: false, key_id: None }))
+        },
+        Format::Binary(_) => {
+            let mut prefix = [0u8; RECORD_PREFIX_LEN];
+            let n = read_full(reader, &mut prefix)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            if n < RECORD_PREFIX_LEN {
+                return Err(KvError::CorruptedRecord);
+            }
+            let body_len = read_u32(&prefix[0..4]) as usize;
+            let mut raw = prefix.to_vec();
+            // read through `take` so that a garbage length can't make us allocate it upfront
+            reader.by_ref().take(body_len as u64).read_to_end(&mut raw)?;
+            if raw.len() < RECORD_PREFIX_LEN + body_len {
+                return Err(KvError::CorruptedRecord);
+            }
+            let entry = decode(format, raw.as_slice(), gen, offset, keyring)?;
+            let body = &raw[RECORD_PREFIX_LEN..];
+            let key_id = if body[0] & FLAG_ENCRYPTED != 0 {
+                Some(crypto::sealed_key_id(&body[1..]))
+            } else {
+                None
+            };
+            Ok(Some(Record { entry, len: raw.len() as u64, in_batch: body[0] & FLAG_BATCH != 0, key_id }))
+        }
+    }
+}
+
+///
+/// decode the body of a binary record whose checksum is already verified
+///
+fn decode_body(body: &[u8], gen: u64, offset: u64, keyring: &Keyring) -> Result<LogEntry> {
+    if body.is_empty() {
+        return Err(KvError::CorruptedRecord);
+    }
+    if body[0] & FLAG_ENCRYPTED != 0 {
+        let sealed = &body[1..];
+        if sealed.len() < crypto::SEALED_HEADER_LEN {
+            return Err(KvError::CorruptedRecord);
+        }
+        let plain = keyring.open(sealed, &record_aad(body[0], gen, offset, crypto::sealed_key_id(sealed)))?;
+        return decode_plain_body(&plain);
+    }
+    decode_plain_body(body)
+}
+
+///
+/// decode the body of a binary record as it is before encryption
+///
+fn decode_plain_body(body: &[u8]) -> Result<LogEntry> {
+    if body.len() < BODY_HEADER_LEN {
+        return Err(KvError::CorruptedRecord);
+    }
+    let flags = body[0];
+    let key_len = read_u32(&body[1..5]) as usize;
+    let value_len = read_u32(&body[5..9]) as usize;
+    let expiry_len = if flags & FLAG_EXPIRY != 0 { EXPIRY_LEN } else { 0 };
+    if body.len() != BODY_HEADER_LEN + expiry_len + key_len + value_len {
+        return Err(KvError::CorruptedRecord);
+    }
+    let key_start = BODY_HEADER_LEN + expiry_len;
+    let key_end = key_start + key_len;
+    let key = body[key_start..key_end].to_vec();
+    if flags & FLAG_TOMBSTONE != 0 {
+        return Ok(LogEntry::Remove(key));
+    }
+    let expires_at = if expiry_len > 0 {
+        Some(read_u64(&body[BODY_HEADER_LEN..key_start]))
+    } else {
+        None
+    };
+    if flags & FLAG_BLOB != 0 {
+        let blob = BlobRef::decode(&body[key_end..])?;
+        return Ok(LogEntry::SetBlob { key, blob, expires_at });
+    }
+    let value = if flags & FLAG_LZ4 != 0 {
+        compress::decompress_lz4(&body[key_end..])?
+    } else {
+        body[key_end..].to_vec()
+    };
+    Ok(LogEntry::Set { key, value, expires_at })
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+///
+/// like `read_exact`, but return how many bytes were read instead of failing at EOF
+///
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
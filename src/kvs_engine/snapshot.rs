@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+
+use super::{Result, KvError, LogPos, Segment, LogEntry, BlobFile, is_expired_at};
+use super::index::{KeyIndex, KeyReader};
+use super::engine::{self, KvsSnapshot};
+
+/// every key of a hashed index with its position, in order
+type SortedKeys = Arc<Vec<(Vec<u8>, LogPos)>>;
+
+///
+/// read-only view of a `KvStore` as of the time it was taken, see `KvsEngine::snapshot`
+///
+/// it holds a copy of the index, that is the log position of every live key at that time,
+/// along with a handle on every segment and blob file those positions point into. A compaction
+/// goes on deleting its inputs, the records stay readable through the handles of the snapshot
+/// and their space is only reclaimed once every snapshot holding them is dropped
+///
+/// the copy is taken under the store lock: on a large store, expect writes to stall while
+/// it's made, and memory in proportion to the number of keys for each snapshot, dump and
+/// migration, see `KvStoreStats::index_bytes`
+///
+#[derive(Clone)]
+pub struct KvSnapshot {
+    index: Arc<KeyIndex>,
+    segments: Arc<BTreeMap<u64, Arc<Segment>>>,
+    blobs: Arc<BTreeMap<u64, Arc<BlobFile>>>,
+    /// time the snapshot was taken, expiries are checked against it
+    taken_at: u64,
+    /// keys of a hashed index, read back from the log by the first scan
+    /// and shared by the next ones, see `IndexMode::Hashed`
+    sorted_keys: Arc<Mutex<Option<SortedKeys>>>,
+}
+
+impl KvSnapshot {
+
+    pub(super) fn new(index: KeyIndex, segments: BTreeMap<u64, Arc<Segment>>,
+                      blobs: BTreeMap<u64, Arc<BlobFile>>, taken_at: u64) -> Self {
+        KvSnapshot {
+            index: Arc::new(index),
+            segments: Arc::new(segments),
+            blobs: Arc::new(blobs),
+            taken_at,
+            sorted_keys: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    ///
+    /// every key of a hashed index in order, sorted on the first call only,
+    /// None if the index isn't hashed
+    ///
+    fn sorted_keys(&self) -> Result<Option<SortedKeys>> {
+        let mut guard = self.sorted_keys.lock().map_err(|_| KvError::LockError)?;
+        if let Some(sorted) = guard.as_ref() {
+            return Ok(Some(sorted.clone()));
+        }
+        let whole = (Bound::Unbounded, Bound::Unbounded);
+        let sorted = match self.index.hashed_keys(&whole) {
+            Some(keys) => Arc::new(keys.sort(&whole, &*self.segments)?),
+            None => return Ok(None),
+        };
+        *guard = Some(sorted.clone());
+        Ok(Some(sorted))
+    }
+
+    ///
+    /// read the value of `k` and its expiry from the record at `pos`, None if it had expired when
+    /// the snapshot was taken, or the record is of another key with the same hash, see `IndexMode::Hashed`
+    ///
+    fn read_value(&self, k: &[u8], pos: LogPos) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let segment = self.segments.get(&pos.gen).ok_or(KvError::SegmentNotFound(pos.gen))?;
+        match segment.read_entry(pos)? {
+            ref entry if entry.key() != k => Ok(None),
+            LogEntry::Set { expires_at, .. } |
+            LogEntry::SetBlob { expires_at, .. } if is_expired_at(expires_at, self.taken_at) => Ok(None),
+            LogEntry::Set { value, expires_at, .. } => Ok(Some((value, expires_at))),
+            LogEntry::SetBlob { blob, expires_at, .. } => {
+                let blob_file = self.blobs.get(&blob.file).ok_or(KvError::BlobNotFound(blob.file))?;
+                Ok(Some((blob_file.read(blob)?, expires_at)))
+            },
+            LogEntry::Remove(_) => Err(KvError::KeyNotFound),
+        }
+    }
+}
+
+impl KeyReader for BTreeMap<u64, Arc<Segment>> {
+    fn read_key(&self, pos: LogPos) -> Result<Vec<u8>> {
+        let segment = self.get(&pos.gen).ok_or(KvError::SegmentNotFound(pos.gen))?;
+        Ok(segment.read_entry(pos)?.key().to_vec())
+    }
+}
+
+impl KvsSnapshot for KvSnapshot {
+
+    ///
+    /// get value by key as of the snapshot
+    ///
+    fn get(&self, k: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        match self.index.lookup(k.as_ref()) {
+            Some(pos) => Ok(self.read_value(k.as_ref(), pos)?.map(|(value, _)| value)),
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// scan the copied index, keys which had expired are left out,
+    /// the index is scanned further until `limit` is reached
+    /// a hashed index has its keys read back from the log and sorted once for the snapshot,
+    /// every scan then starts with a binary search in them
+    ///
+    fn scan_with_expiry<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>)
+        -> Result<Vec<(Vec<u8>, Vec<u8>, Option<u64>)>> {
+        let mut range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if engine::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let limit = limit.unwrap_or(usize::max_value());
+        let mut pairs = Vec::new();
+        if let Some(sorted) = self.sorted_keys()? {
+            let start = match &range.0 {
+                Bound::Included(start) => sorted.binary_search_by(|(k, _)| k.cmp(start)).unwrap_or_else(|i| i),
+                Bound::Excluded(start) => sorted.binary_search_by(|(k, _)| k.cmp(start)).map_or_else(|i| i, |i| i + 1),
+                Bound::Unbounded => 0,
+            };
+            for (k, pos) in &sorted[start..] {
+                if pairs.len() == limit || !range.contains(k) {
+                    break;
+                }
+                if let Some((value, expires_at)) = self.read_value(k, *pos)? {
+                    pairs.push((k.clone(), value, expires_at));
+                }
+            }
+            return Ok(pairs);
+        }
+        loop {
+            let wanted = limit - pairs.len();
+            if wanted == 0 || engine::is_empty_range(&range) {
+                break;
+            }
+            let positions = self.index.scan(&range, wanted, &*self.segments)?;
+            let exhausted = positions.len() < wanted;
+            if let Some((last, _)) = positions.last() {
+                range.0 = Bound::Excluded(last.clone());
+            }
+            for (k, pos) in positions {
+                if let Some((value, expires_at)) = self.read_value(&k, pos)? {
+                    pairs.push((k, value, expires_at));
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+        Ok(pairs)
+    }
+}
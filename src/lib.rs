@@ -24,11 +24,24 @@ pub mod engine;
 pub mod sled_engine;
 /// kvs engine;
 pub mod kvs_engine;
+/// lsm-tree engine
+pub mod lsm_engine;
 /// thread pool
 pub mod thread_pool;
+/// portable dump and restore, for any engine
+pub mod dump;
+/// offline conversion of a data directory between engines
+pub mod migrate;
 
 /// re-export
 pub use engine::KvsEngine;
+pub use engine::KvsSnapshot;
 pub use engine::Result;
-pub use kvs_engine::KvStore;
-pub use sled_engine::SledStore;
\ No newline at end of file
+pub use engine::WriteBatch;
+pub use engine::Transaction;
+pub use engine::EngineKind;
+pub use dump::DumpFormat;
+pub use kvs_engine::{KvStore, KvSnapshot, KvStoreOptions, KvStoreStats, SyncPolicy, RecoveryMode, RecoveryReport,
+                     EncryptionKey, Compression, IndexMode};
+pub use sled_engine::{SledStore, SledSnapshot};
+pub use lsm_engine::{LsmStore, LsmSnapshot, LsmOptions, LsmStoreStats};
\ No newline at end of file
@@ -0,0 +1,79 @@
+use super::{Result, KvError};
+
+/// most probes a filter makes per key, past that more bits per key hardly help
+const MAX_PROBES: u32 = 30;
+/// offset basis and prime of the 64-bit FNV-1a hash
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+///
+/// bloom filter over the keys of a table, tells for sure that a key is not in it
+///
+/// probes are derived from a single 64-bit hash by double hashing, the hash is written
+/// out here rather than taken from std, whose hashers may change between releases,
+/// as filters outlive the binary which wrote them
+///
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    probes: u32,
+}
+
+impl BloomFilter {
+
+    ///
+    /// filter over `hashes`, see `key_hash`, with about `bits_per_key` bits for every key
+    /// with no bits at all, every key may be in the table
+    ///
+    pub fn build(hashes: &[u64], bits_per_key: usize) -> Self {
+        if bits_per_key == 0 {
+            return BloomFilter { bits: vec![0xff; 8], probes: 1 };
+        }
+        // ln(2) * bits per key is the number of probes with the fewest false positives
+        let probes = ((bits_per_key as f64 * 0.69) as u32).max(1).min(MAX_PROBES);
+        let nbits = (hashes.len() * bits_per_key).max(64);
+        let mut filter = BloomFilter { bits: vec![0u8; (nbits + 7) / 8], probes };
+        for &hash in hashes {
+            for bit in filter.bit_positions(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    ///
+    /// whether the key hashed to `hash` may be in the table, false only if it isn't
+    ///
+    pub fn may_contain(&self, hash: u64) -> bool {
+        self.bit_positions(hash).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    ///
+    /// layout: | probes: u8 | bits |
+    ///
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + self.bits.len());
+        buf.push(self.probes as u8);
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub fn decode(raw: &[u8]) -> Result<Self> {
+        if raw.len() < 2 || raw[0] == 0 || raw[0] as u32 > MAX_PROBES {
+            return Err(KvError::CorruptedRecord);
+        }
+        Ok(BloomFilter { bits: raw[1..].to_vec(), probes: raw[0] as u32 })
+    }
+
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let nbits = (self.bits.len() * 8) as u64;
+        let delta = hash.rotate_left(32) | 1;
+        (0..self.probes as u64).map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % nbits) as usize)
+    }
+}
+
+///
+/// hash of `key` as probed in a filter
+///
+pub fn key_hash(key: &[u8]) -> u64 {
+    key.iter().fold(FNV_OFFSET, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
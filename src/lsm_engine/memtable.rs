@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use super::{Result, KvError};
+
+/// entry header: key length + value length + sequence number + flags
+const ENTRY_HEADER_LEN: usize = 17;
+/// flag marking a tombstone, which has no value
+const FLAG_TOMBSTONE: u8 = 0x01;
+/// flag marking an entry with an expiry, stored right after the header
+const FLAG_EXPIRY: u8 = 0x02;
+/// length of an expiry: milliseconds since the unix epoch
+const EXPIRY_LEN: usize = 8;
+/// bytes charged for every entry of a memtable on top of its key and value, for the bookkeeping
+const ENTRY_OVERHEAD: u64 = 64;
+
+///
+/// latest write of a key, as kept in memtables and tables
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slot {
+    /// sequence number of the write, which is the version of the key
+    pub seq: u64,
+    /// None for a tombstone
+    pub value: Option<Vec<u8>>,
+    /// when the key expires, in milliseconds since the unix epoch
+    pub expires_at: Option<u64>,
+}
+
+impl Slot {
+
+    ///
+    /// value of the key at time `now`, None if it's removed or expired by then
+    ///
+    pub fn live_value(&self, now: u64) -> Option<&Vec<u8>> {
+        match self.expires_at {
+            Some(expires_at) if expires_at <= now => None,
+            _ => self.value.as_ref(),
+        }
+    }
+}
+
+///
+/// writes not yet in a table, in key order, backed by the write-ahead log `wal`
+///
+#[derive(Debug, Clone)]
+pub struct Memtable {
+    entries: BTreeMap<Vec<u8>, Slot>,
+    /// rough size in memory, the memtable is sealed once it reaches `LsmOptions::memtable_size`
+    bytes: u64,
+    /// id of the write-ahead log holding the same writes
+    wal: u64,
+}
+
+impl Memtable {
+
+    pub fn new(wal: u64) -> Self {
+        Memtable { entries: BTreeMap::new(), bytes: 0, wal }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Slot> {
+        self.entries.get(key)
+    }
+
+    ///
+    /// record `slot` as the latest write of `key`
+    ///
+    pub fn insert(&mut self, key: Vec<u8>, slot: Slot) {
+        let key_len = key.len();
+        self.bytes += slot_charge(key_len, &slot);
+        if let Some(old) = self.entries.insert(key, slot) {
+            self.bytes -= slot_charge(key_len, &old);
+        }
+    }
+
+    ///
+    /// entries with keys in `range`, in order
+    ///
+    pub fn range<'a>(&'a self, range: &(Bound<Vec<u8>>, Bound<Vec<u8>>))
+        -> impl Iterator<Item = (&'a Vec<u8>, &'a Slot)> + 'a
+    {
+        self.entries.range(range.clone())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Slot)> {
+        self.entries.iter()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn wal(&self) -> u64 {
+        self.wal
+    }
+}
+
+///
+/// bytes an entry with a key `key_len` long counts for in a memtable
+///
+fn slot_charge(key_len: usize, slot: &Slot) -> u64 {
+    (key_len + slot.value.as_ref().map_or(0, |value| value.len())) as u64 + ENTRY_OVERHEAD
+}
+
+///
+/// append the entry of `key` to `buf`, as written in the write-ahead log and in table blocks
+/// layout: | key len: u32 | value len: u32 | seq: u64 | flags: u8 |
+///         | expiry: u64, only with `FLAG_EXPIRY` | key | value |
+///
+pub fn encode_entry(buf: &mut Vec<u8>, key: &[u8], slot: &Slot) {
+    let mut flags = 0;
+    if slot.value.is_none() {
+        flags |= FLAG_TOMBSTONE;
+    }
+    if slot.expires_at.is_some() {
+        flags |= FLAG_EXPIRY;
+    }
+    let value = slot.value.as_deref().unwrap_or(&[]);
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&slot.seq.to_le_bytes());
+    buf.push(flags);
+    if let Some(expires_at) = slot.expires_at {
+        buf.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+}
+
+///
+/// decode the entry at the start of `raw`, along with how many bytes it takes
+///
+pub fn decode_entry(raw: &[u8]) -> Result<(Vec<u8>, Slot, usize)> {
+    if raw.len() < ENTRY_HEADER_LEN {
+        return Err(KvError::CorruptedRecord);
+    }
+    let key_len = read_u32(&raw[0..4]) as usize;
+    let value_len = read_u32(&raw[4..8]) as usize;
+    let seq = read_u64(&raw[8..16]);
+    let flags = raw[16];
+    let expiry_len = if flags & FLAG_EXPIRY != 0 { EXPIRY_LEN } else { 0 };
+    let key_start = ENTRY_HEADER_LEN + expiry_len;
+    let len = key_start + key_len + value_len;
+    if raw.len() < len {
+        return Err(KvError::CorruptedRecord);
+    }
+    let expires_at = if expiry_len > 0 {
+        Some(read_u64(&raw[ENTRY_HEADER_LEN..key_start]))
+    } else {
+        None
+    };
+    let key = raw[key_start..key_start + key_len].to_vec();
+    let value = if flags & FLAG_TOMBSTONE != 0 {
+        None
+    } else {
+        Some(raw[key_start + key_len..len].to_vec())
+    };
+    Ok((key, Slot { seq, value, expires_at }, len))
+}
+
+pub fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+pub fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
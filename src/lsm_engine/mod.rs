@@ -0,0 +1,1137 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::iter::Peekable;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::engine::{self, Result, KvsEngine, KvError, WriteBatch, BatchOp, Transaction, EngineKind};
+use super::kvs_engine::{now_millis, expiry_after, read_exact_at, copy_file_prefix, parse_gen,
+                        remove_file_if_exists, tmp_path, SyncPolicy};
+
+use self::memtable::{Memtable, Slot};
+use self::sstable::{Table, TableBuilder, TableIter};
+use self::wal::Wal;
+pub use self::options::LsmOptions;
+pub use self::snapshot::LsmSnapshot;
+
+/// in-memory table of the latest writes, and the encoding of their entries
+mod memtable;
+/// write-ahead log of the memtable
+mod wal;
+/// sorted tables on disk
+mod sstable;
+/// bloom filters of the tables
+mod bloom;
+/// options to open a store with
+mod options;
+/// point-in-time read-only views
+mod snapshot;
+
+/// default directory of the store
+pub const DEFAULT_PATH: &'static str = "./database";
+/// extension of write-ahead logs, which are named `<id>.wal`
+const WAL_EXT: &'static str = "wal";
+/// extension of tables, which are named `<id>.sst`
+const TABLE_EXT: &'static str = "sst";
+/// file listing the tables of every level
+const MANIFEST_FILE: &'static str = "MANIFEST";
+/// magic bytes at the start of the manifest
+const MANIFEST_MAGIC: [u8; 4] = *b"KVMF";
+/// version of the manifest format
+const MANIFEST_VERSION: u16 = 1;
+/// manifest header: magic + format version + reserved bytes + log floor + number of tables
+const MANIFEST_HEADER_LEN: usize = 20;
+/// manifest entry: level + table id
+const MANIFEST_ENTRY_LEN: usize = 9;
+/// number of levels, the last one holds most of the data
+const MAX_LEVELS: usize = 7;
+/// sealed memtables waiting to be flushed past which writes flush one themselves
+const MAX_IMMUTABLE_MEMTABLES: usize = 4;
+/// how often the background thread checks for work when it isn't woken up,
+/// in case a flush or a compaction failed
+const BACKGROUND_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// the memtables and tables of a store, the newest first
+///
+/// a key is looked up in the memtable, then in the sealed memtables, then in level 0 whose
+/// tables may overlap, then in every other level, where a single table may hold it.
+/// The first entry found is the latest write of the key
+///
+#[derive(Clone)]
+struct Version {
+    mem: Arc<Memtable>,
+    /// memtables sealed and waiting to be flushed to level 0, the newest first
+    imm: Vec<Arc<Memtable>>,
+    /// tables of every level, level 0 the newest first, the others by key
+    levels: Arc<Vec<Vec<Arc<Table>>>>,
+}
+
+impl Version {
+
+    ///
+    /// id of the oldest write-ahead log still needed, the older ones are flushed to tables
+    ///
+    fn log_floor(&self) -> u64 {
+        self.imm.iter().map(|mem| mem.wal()).fold(self.mem.wal(), u64::min)
+    }
+}
+
+///
+/// figures about the memtables and the levels of a store, see `LsmStore::stats`
+///
+#[derive(Debug, Clone, Default)]
+pub struct LsmStoreStats {
+    /// rough size in memory of the memtable written to
+    pub memtable_bytes: u64,
+    /// memtables sealed and waiting to be flushed
+    pub immutable_memtables: u64,
+    /// number of tables of every level
+    pub level_tables: Vec<u64>,
+    /// bytes of the tables of every level
+    pub level_bytes: Vec<u64>,
+    /// memtables flushed to level 0 since the store was opened
+    pub flushes: u64,
+    /// compactions done since the store was opened
+    pub compactions: u64,
+    /// table lookups the bloom filter answered without reading a block
+    pub filtered_lookups: u64,
+    /// times the write-ahead log was synced: as the sync policy asks, on `KvsEngine::flush`,
+    /// and when sealing a memtable
+    pub syncs: u64,
+    /// writes appended since the write-ahead log was last synced, a power loss may lose them
+    pub unsynced_writes: u64,
+}
+
+///
+/// state shared by the handles on a store and its background thread
+///
+struct Shared {
+    dir: PathBuf,
+    options: LsmOptions,
+    version: RwLock<Version>,
+    /// id of the next write-ahead log or table, both are numbered together
+    next_file: AtomicU64,
+    /// held while a memtable is flushed, so that writes and the background thread
+    /// don't flush the same one
+    flush_lock: Mutex<()>,
+    flushes: AtomicU64,
+    compactions: AtomicU64,
+    filtered: Arc<AtomicU64>,
+}
+
+///
+/// writes go through it one at a time
+///
+struct Writer {
+    wal: Wal,
+    /// sequence number of the latest write, stamped on its entries as their version
+    seq: u64,
+    /// writes appended since the write-ahead log was last synced
+    unsynced_writes: u64,
+    last_sync: Instant,
+    /// times the write-ahead log was synced since the store was opened
+    syncs: u64,
+}
+
+impl Writer {
+
+    ///
+    /// sync the write-ahead log if `policy` asks for it by now
+    ///
+    fn sync_due(&mut self, policy: SyncPolicy) -> Result<()> {
+        let due = match policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryWrites(n) => self.unsynced_writes >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if due && self.unsynced_writes > 0 {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// force the writes appended so far to disk
+    ///
+    fn sync(&mut self) -> Result<()> {
+        self.wal.sync()?;
+        self.unsynced_writes = 0;
+        self.last_sync = Instant::now();
+        self.syncs += 1;
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let _ = self.wal.sync();
+    }
+}
+
+///
+/// log-structured merge tree: writes go to a memtable backed by a write-ahead log, which is
+/// flushed to a sorted table once it's full, tables are merged level by level in the background
+///
+/// reads look the key up from the newest data to the oldest, see `Version`, writes only append
+/// to the log and insert into the memtable, which suits write-heavy and ordered workloads
+///
+#[derive(Clone)]
+pub struct LsmStore {
+    shared: Arc<Shared>,
+    writer: Arc<Mutex<Writer>>,
+    /// the background thread stops once every clone of the store is dropped,
+    /// declared last so it's dropped after the writer
+    compactor: Arc<Compactor>,
+}
+
+///
+/// handle on the thread flushing memtables and compacting levels, which also syncs
+/// the write-ahead log under `SyncPolicy::Interval`
+///
+/// dropping it wakes the thread up and waits for it to stop
+///
+struct Compactor {
+    sender: Mutex<Option<Sender<()>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+
+    ///
+    /// have the thread check for work now
+    ///
+    fn wake(&self) {
+        if let Ok(sender) = self.sender.lock() {
+            if let Some(sender) = sender.as_ref() {
+                let _ = sender.send(());
+            }
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // the thread wakes up as soon as the sender is dropped
+        if let Ok(mut sender) = self.sender.lock() {
+            sender.take();
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Default for LsmStore {
+    fn default() -> Self {
+        LsmStore::open(DEFAULT_PATH).expect("Fail to create default LsmStore")
+    }
+}
+
+impl LsmStore {
+
+    ///
+    /// return initialized LsmStore
+    ///
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_options(path, LsmOptions::default())
+    }
+
+    ///
+    /// options to open an LsmStore with, see `LsmOptions`
+    ///
+    pub fn builder() -> LsmOptions {
+        LsmOptions::default()
+    }
+
+    ///
+    /// open the store in `path`, configured by `options`
+    /// the writes left in write-ahead logs are flushed to a table right away,
+    /// along the way files no level lists, left by a crash, are deleted
+    ///
+    fn open_with_options<P: AsRef<Path>>(path: P, options: LsmOptions) -> Result<Self> {
+        let dir = Self::ensure_path(path.as_ref())?;
+        fs::create_dir_all(&dir)?;
+        engine::write_engine_marker(&dir, EngineKind::Lsm)?;
+
+        let (log_floor, listed) = read_manifest(&dir)?.unwrap_or_default();
+        let mut levels = vec![Vec::new(); MAX_LEVELS];
+        let mut seq = 0;
+        for &(level, id) in listed.iter() {
+            let table = Table::open(&table_path(&dir, id), id)?;
+            seq = seq.max(table.max_seq());
+            levels[level].push(Arc::new(table));
+        }
+        let listed: BTreeSet<u64> = listed.iter().map(|&(_, id)| id).collect();
+        let mut max_id = 0;
+        let mut wals = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            if let Some(id) = parse_gen(&name, TABLE_EXT) {
+                max_id = max_id.max(id);
+                if !listed.contains(&id) {
+                    fs::remove_file(&path)?;
+                }
+            } else if let Some(id) = parse_gen(&name, WAL_EXT) {
+                max_id = max_id.max(id);
+                if id < log_floor {
+                    fs::remove_file(&path)?;
+                } else {
+                    wals.push(id);
+                }
+            }
+        }
+        wals.sort();
+
+        let shared = Shared {
+            dir: dir.clone(),
+            options,
+            version: RwLock::new(Version {
+                mem: Arc::new(Memtable::new(0)),
+                imm: Vec::new(),
+                levels: Arc::new(levels),
+            }),
+            next_file: AtomicU64::new(max_id + 1),
+            flush_lock: Mutex::new(()),
+            flushes: AtomicU64::new(0),
+            compactions: AtomicU64::new(0),
+            filtered: Arc::new(AtomicU64::new(0)),
+        };
+        let mut recovered = Memtable::new(0);
+        for &id in wals.iter() {
+            seq = seq.max(wal::replay(&wal_path(&dir, id), &mut recovered)?);
+        }
+        let wal_id = shared.new_file_id();
+        let wal = Wal::create(&wal_path(&dir, wal_id))?;
+        let tables = shared.build_tables(recovered.iter().map(|(key, slot)| Ok((key.clone(), slot.clone()))), None)?;
+        {
+            let mut version = shared.version.write().map_err(|_| KvError::LockError)?;
+            version.mem = Arc::new(Memtable::new(wal_id));
+            let levels = Arc::make_mut(&mut version.levels);
+            for table in tables {
+                levels[0].insert(0, table);
+            }
+            write_manifest(&dir, &version)?;
+        }
+        for id in wals {
+            remove_file_if_exists(&wal_path(&dir, id))?;
+        }
+
+        let shared = Arc::new(shared);
+        let writer = Arc::new(Mutex::new(Writer { wal, seq, unsynced_writes: 0, last_sync: Instant::now(), syncs: 0 }));
+        let (sender, receiver) = mpsc::channel();
+        let shared_cp = shared.clone();
+        let writer_cp = writer.clone();
+        let handle = thread::spawn(move || {
+            // the key every level was last compacted up to, to go round its tables
+            let mut pointers = vec![Vec::new(); MAX_LEVELS];
+            // writes only check the clock when they come in, so the last ones before
+            // the store goes idle are synced from here
+            let wait = match shared_cp.options.sync_policy {
+                SyncPolicy::Interval(interval) => interval.min(BACKGROUND_INTERVAL),
+                _ => BACKGROUND_INTERVAL,
+            };
+            loop {
+                if let Err(RecvTimeoutError::Disconnected) = receiver.recv_timeout(wait) {
+                    break;
+                }
+                // a write going on checks it on its own
+                if let Ok(mut guard) = writer_cp.try_lock() {
+                    let _ = guard.sync_due(shared_cp.options.sync_policy);
+                }
+                let _ = do_background_work(&shared_cp, &mut pointers);
+            }
+        });
+        let compactor = Compactor { sender: Mutex::new(Some(sender)), handle: Some(handle) };
+        // the levels may be due a compaction already
+        compactor.wake();
+        Ok(LsmStore {
+            shared,
+            writer,
+            compactor: Arc::new(compactor),
+        })
+    }
+
+    ///
+    /// Path must meet
+    /// 1. not be a file
+    /// 2. not be marked as belonging to another engine, and if not marked at all but
+    ///    non-empty, hold a manifest, so another engine's files are never adopted
+    ///
+    fn ensure_path(path: &Path) -> Result<PathBuf> {
+        if path.exists() {
+            if path.is_file() {
+                return Err(KvError::DirPathExpected);
+            }
+            engine::check_engine_marker(path, EngineKind::Lsm)?;
+            if fs::read_dir(path)?.next().is_some() && engine::read_engine_marker(path)?.is_none() &&
+                !path.join(MANIFEST_FILE).exists() {
+                return Err(KvError::FileMismatchInPath);
+            }
+        }
+        Ok(path.to_path_buf())
+    }
+
+    ///
+    /// size of the memtables and of every level, how many flushes and compactions were done
+    /// and how well the bloom filters do
+    ///
+    pub fn stats(&self) -> Result<LsmStoreStats> {
+        let (syncs, unsynced_writes) = {
+            let writer = self.lock_writer()?;
+            (writer.syncs, writer.unsynced_writes)
+        };
+        let version = self.current()?;
+        Ok(LsmStoreStats {
+            memtable_bytes: version.mem.bytes(),
+            immutable_memtables: version.imm.len() as u64,
+            level_tables: version.levels.iter().map(|level| level.len() as u64).collect(),
+            level_bytes: version.levels.iter().map(|level| level.iter().map(|table| table.size()).sum()).collect(),
+            flushes: self.shared.flushes.load(Ordering::Relaxed),
+            compactions: self.shared.compactions.load(Ordering::Relaxed),
+            filtered_lookups: self.shared.filtered.load(Ordering::Relaxed),
+            syncs,
+            unsynced_writes,
+        })
+    }
+
+    ///
+    /// the memtables and tables as of now
+    /// the memtable is copied by the next write as long as the version is held,
+    /// reads which don't outlive the call only hold the sealed memtables and the tables
+    ///
+    fn current(&self) -> Result<Version> {
+        match self.shared.version.read() {
+            Ok(guard) => Ok(guard.clone()),
+            Err(_) => Err(KvError::LockError),
+        }
+    }
+
+    ///
+    /// latest entry of `key`, which may be a tombstone or expired
+    ///
+    fn latest(&self, key: &[u8]) -> Result<Option<Slot>> {
+        let (imm, levels) = match self.shared.version.read() {
+            Ok(guard) => {
+                if let Some(slot) = guard.mem.get(key) {
+                    return Ok(Some(slot.clone()));
+                }
+                (guard.imm.clone(), guard.levels.clone())
+            },
+            Err(_) => return Err(KvError::LockError),
+        };
+        search(&imm, &levels, key, &self.shared.filtered)
+    }
+
+    ///
+    /// value of `key` along with its expiry, None if it's removed or expired
+    ///
+    fn live(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let now = now_millis();
+        Ok(self.latest(key)?.and_then(|slot| match slot.live_value(now) {
+            Some(_) => {
+                let expires_at = slot.expires_at;
+                slot.value.map(|value| (value, expires_at))
+            },
+            None => None,
+        }))
+    }
+
+    ///
+    /// append `writes` to the write-ahead log as a single record, then apply them to the memtable,
+    /// every write is a key with its value, None to remove it, and its expiry
+    /// seal the memtable once it's full, and flush one from here if the background thread
+    /// falls too far behind
+    ///
+    fn apply(&self, writer: &mut Writer, writes: Vec<(Vec<u8>, Option<Vec<u8>>, Option<u64>)>) -> Result<()> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let entries: Vec<(Vec<u8>, Slot)> = writes.into_iter()
+            .map(|(key, value, expires_at)| {
+                writer.seq += 1;
+                (key, Slot { seq: writer.seq, value, expires_at })
+            })
+            .collect();
+        writer.wal.append(&entries)?;
+        writer.unsynced_writes += 1;
+        writer.sync_due(self.shared.options.sync_policy)?;
+        let full = match self.shared.version.write() {
+            Ok(mut guard) => {
+                let mem = Arc::make_mut(&mut guard.mem);
+                for (key, slot) in entries {
+                    mem.insert(key, slot);
+                }
+                mem.bytes() >= self.shared.options.memtable_size
+            },
+            Err(_) => return Err(KvError::LockError),
+        };
+        if !full {
+            return Ok(());
+        }
+
+        // no other write comes in meanwhile, the writer is held
+        let wal_id = self.shared.new_file_id();
+        let wal = Wal::create(&wal_path(&self.shared.dir, wal_id))?;
+        writer.sync()?;
+        writer.wal = wal;
+        let sealed = match self.shared.version.write() {
+            Ok(mut guard) => {
+                let mem = mem::replace(&mut guard.mem, Arc::new(Memtable::new(wal_id)));
+                guard.imm.insert(0, mem);
+                guard.imm.len()
+            },
+            Err(_) => return Err(KvError::LockError),
+        };
+        self.compactor.wake();
+        if sealed > MAX_IMMUTABLE_MEMTABLES {
+            flush_oldest(&self.shared)?;
+        }
+        Ok(())
+    }
+
+    fn lock_writer(&self) -> Result<std::sync::MutexGuard<'_, Writer>> {
+        self.writer.lock().map_err(|_| KvError::LockError)
+    }
+}
+
+impl Shared {
+
+    fn new_file_id(&self) -> u64 {
+        self.next_file.fetch_add(1, Ordering::SeqCst)
+    }
+
+    ///
+    /// write `entries`, in key order, to new tables, cut once they reach `split` bytes if given
+    ///
+    fn build_tables<I>(&self, entries: I, split: Option<u64>) -> Result<Vec<Arc<Table>>>
+        where I: Iterator<Item = Result<(Vec<u8>, Slot)>>
+    {
+        let mut tables = Vec::new();
+        let mut builder: Option<(u64, TableBuilder)> = None;
+        for entry in entries {
+            let (key, slot) = entry?;
+            if builder.is_none() {
+                let id = self.new_file_id();
+                let table = TableBuilder::create(&table_path(&self.dir, id), self.options.block_size,
+                                                 self.options.bloom_bits_per_key)?;
+                builder = Some((id, table));
+            }
+            if let Some((_, table)) = builder.as_mut() {
+                table.add(&key, &slot)?;
+                if split.map_or(false, |split| table.size() >= split) {
+                    tables.push(self.finish_table(builder.take())?);
+                }
+            }
+        }
+        if builder.is_some() {
+            tables.push(self.finish_table(builder.take())?);
+        }
+        Ok(tables)
+    }
+
+    fn finish_table(&self, builder: Option<(u64, TableBuilder)>) -> Result<Arc<Table>> {
+        let (id, builder) = builder.expect("Fail to find the table being written");
+        builder.finish()?;
+        Ok(Arc::new(Table::open(&table_path(&self.dir, id), id)?))
+    }
+}
+
+///
+/// flush the sealed memtables, then compact as long as a level is over its limit
+///
+fn do_background_work(shared: &Shared, pointers: &mut Vec<Vec<u8>>) -> Result<()> {
+    while flush_oldest(shared)? {}
+    while compact_once(shared, pointers)? {}
+    Ok(())
+}
+
+///
+/// write the oldest sealed memtable to a table of level 0, return whether there was one
+/// action:
+/// - without any lock, write its entries to a new table
+/// - under the version lock, swap the table in for the memtable and write the manifest
+/// - delete the write-ahead log of the memtable, it's not replayed anymore
+///
+fn flush_oldest(shared: &Shared) -> Result<bool> {
+    let _flushing = shared.flush_lock.lock().map_err(|_| KvError::LockError)?;
+    let mem = match shared.version.read() {
+        Ok(guard) => match guard.imm.last() {
+            Some(mem) => mem.clone(),
+            None => return Ok(false),
+        },
+        Err(_) => return Err(KvError::LockError),
+    };
+    let tables = shared.build_tables(mem.iter().map(|(key, slot)| Ok((key.clone(), slot.clone()))), None)?;
+    match shared.version.write() {
+        Ok(mut guard) => {
+            guard.imm.pop();
+            let levels = Arc::make_mut(&mut guard.levels);
+            for table in tables {
+                levels[0].insert(0, table);
+            }
+            write_manifest(&shared.dir, &guard)?;
+        },
+        Err(_) => return Err(KvError::LockError),
+    }
+    remove_file_if_exists(&wal_path(&shared.dir, mem.wal()))?;
+    shared.flushes.fetch_add(1, Ordering::Relaxed);
+    Ok(true)
+}
+
+///
+/// tables of a level to merge into the next one, along with the tables of the next level
+/// they overlap
+///
+struct Compaction {
+    level: usize,
+    inputs: Vec<Arc<Table>>,
+    overlapping: Vec<Arc<Table>>,
+    /// whether no level below the next one holds any table, then removed and expired
+    /// entries can be dropped rather than copied
+    bottom: bool,
+}
+
+///
+/// compaction to do, if any: level 0 once it holds `level0_file_limit` tables, as a whole,
+/// or the first other level over its size, one table at a time going round its keys
+///
+fn pick_compaction(version: &Version, options: &LsmOptions, pointers: &mut Vec<Vec<u8>>) -> Option<Compaction> {
+    let levels = &version.levels;
+    let (level, inputs) = if levels[0].len() >= options.level0_file_limit.max(1) {
+        (0, levels[0].clone())
+    } else {
+        let mut max_bytes = options.level_base_size;
+        let mut picked = None;
+        for level in 1..MAX_LEVELS - 1 {
+            let bytes: u64 = levels[level].iter().map(|table| table.size()).sum();
+            if bytes > max_bytes {
+                let table = levels[level].iter()
+                    .find(|table| table.first_key() > pointers[level].as_slice())
+                    .unwrap_or(&levels[level][0]);
+                pointers[level] = table.last_key().to_vec();
+                picked = Some((level, vec![table.clone()]));
+                break;
+            }
+            max_bytes = max_bytes.saturating_mul(options.level_size_multiplier.max(1));
+        }
+        picked?
+    };
+    let first = inputs.iter().map(|table| table.first_key()).min()?.to_vec();
+    let last = inputs.iter().map(|table| table.last_key()).max()?.to_vec();
+    let overlapping = levels[level + 1].iter()
+        .filter(|table| table.overlaps(&first, &last))
+        .cloned()
+        .collect();
+    let bottom = levels[level + 2..].iter().all(|tables| tables.is_empty());
+    Some(Compaction { level, inputs, overlapping, bottom })
+}
+
+///
+/// merge the tables of a level into the next one if one is over its limit, return whether it was
+/// action:
+/// - under the version lock, pick the tables to merge, see `pick_compaction`
+/// - without any lock, merge them into new tables, only the latest entry of every key is kept
+/// - under the version lock, swap the new tables in for the merged ones and write the manifest
+/// - delete the merged tables, snapshots holding them keep reading through their handles
+///
+/// a single table overlapping nothing in the next level is moved down as it is
+///
+fn compact_once(shared: &Shared, pointers: &mut Vec<Vec<u8>>) -> Result<bool> {
+    let compaction = match shared.version.read() {
+        Ok(guard) => match pick_compaction(&guard, &shared.options, pointers) {
+            Some(compaction) => compaction,
+            None => return Ok(false),
+        },
+        Err(_) => return Err(KvError::LockError),
+    };
+    let level = compaction.level;
+    let moved = compaction.inputs.len() == 1 && compaction.overlapping.is_empty() && level > 0;
+    let outputs = if moved {
+        compaction.inputs.clone()
+    } else {
+        let mut sources: Vec<EntryIter> = Vec::new();
+        for table in compaction.inputs.iter().chain(compaction.overlapping.iter()) {
+            sources.push(Box::new(TableIter::new(table.clone(), Bound::Unbounded)));
+        }
+        let now = now_millis();
+        let bottom = compaction.bottom;
+        let merged = MergeIter::new(sources).filter(|entry| match entry {
+            Ok((_, slot)) if bottom => slot.live_value(now).is_some(),
+            _ => true,
+        });
+        shared.build_tables(merged, Some(shared.options.table_file_size))?
+    };
+
+    let merged: BTreeSet<u64> = compaction.inputs.iter()
+        .chain(compaction.overlapping.iter())
+        .map(|table| table.id())
+        .collect();
+    match shared.version.write() {
+        Ok(mut guard) => {
+            let levels = Arc::make_mut(&mut guard.levels);
+            levels[level].retain(|table| !merged.contains(&table.id()));
+            levels[level + 1].retain(|table| !merged.contains(&table.id()));
+            levels[level + 1].extend(outputs);
+            levels[level + 1].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+            write_manifest(&shared.dir, &guard)?;
+        },
+        Err(_) => return Err(KvError::LockError),
+    }
+    if !moved {
+        for id in merged {
+            remove_file_if_exists(&table_path(&shared.dir, id))?;
+        }
+    }
+    shared.compactions.fetch_add(1, Ordering::Relaxed);
+    Ok(true)
+}
+
+///
+/// latest entry of `key` in the sealed memtables and the tables, the memtable is looked at first
+///
+fn search(imm: &[Arc<Memtable>], levels: &[Vec<Arc<Table>>], key: &[u8], filtered: &AtomicU64) -> Result<Option<Slot>> {
+    for mem in imm.iter() {
+        if let Some(slot) = mem.get(key) {
+            return Ok(Some(slot.clone()));
+        }
+    }
+    for (level, tables) in levels.iter().enumerate() {
+        let candidates: Vec<&Arc<Table>> = if level == 0 {
+            tables.iter().collect()
+        } else {
+            let i = tables.partition_point(|table| table.last_key() < key);
+            tables.get(i).into_iter().collect()
+        };
+        for table in candidates {
+            let (slot, skipped) = table.get(key)?;
+            if skipped {
+                filtered.fetch_add(1, Ordering::Relaxed);
+            }
+            if slot.is_some() {
+                return Ok(slot);
+            }
+        }
+    }
+    Ok(None)
+}
+
+///
+/// entries in key order, from a memtable or a table
+///
+type EntryIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Slot)>> + 'a>;
+
+///
+/// merge of sources of entries each in key order, only the latest entry of every key comes out
+///
+struct MergeIter<'a> {
+    sources: Vec<Peekable<EntryIter<'a>>>,
+}
+
+impl<'a> MergeIter<'a> {
+
+    fn new(sources: Vec<EntryIter<'a>>) -> Self {
+        MergeIter { sources: sources.into_iter().map(|source| source.peekable()).collect() }
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Result<(Vec<u8>, Slot)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut smallest: Option<Vec<u8>> = None;
+        for source in self.sources.iter_mut() {
+            if let Some(Err(_)) = source.peek() {
+                return source.next();
+            }
+            if let Some(Ok((key, _))) = source.peek() {
+                if smallest.as_ref().map_or(true, |smallest| key < smallest) {
+                    smallest = Some(key.clone());
+                }
+            }
+        }
+        let smallest = smallest?;
+        let mut latest: Option<Slot> = None;
+        for source in self.sources.iter_mut() {
+            let is_smallest = match source.peek() {
+                Some(Ok((key, _))) => *key == smallest,
+                _ => false,
+            };
+            if !is_smallest {
+                continue;
+            }
+            if let Some(Ok((_, slot))) = source.next() {
+                if latest.as_ref().map_or(true, |latest| slot.seq > latest.seq) {
+                    latest = Some(slot);
+                }
+            }
+        }
+        latest.map(|slot| Ok((smallest, slot)))
+    }
+}
+
+///
+/// merge `mem`, the entries of the memtable in `range`, with the sealed memtables and the tables,
+/// and keep the live values until `limit` is reached or `end` is passed, as of time `now`
+/// return the pairs along with their expiry and whether `end` was reached, or `limit`
+///
+fn merge_range(mem: EntryIter, imm: &[Arc<Memtable>], levels: &[Vec<Arc<Table>>],
+               range: &(Bound<Vec<u8>>, Bound<Vec<u8>>), end: &Bound<Vec<u8>>, limit: usize,
+               now: u64) -> Result<(Vec<(Vec<u8>, Vec<u8>, Option<u64>)>, bool)> {
+    let mut sources: Vec<EntryIter> = vec![mem];
+    for mem in imm.iter() {
+        sources.push(Box::new(mem.range(range).map(|(key, slot)| Ok((key.clone(), slot.clone())))));
+    }
+    for (level, tables) in levels.iter().enumerate() {
+        let start = range.0.clone();
+        if level == 0 {
+            // tables of level 0 may overlap and aren't sorted by key
+            let tables = tables.iter()
+                .filter(|table| !is_before(table.last_key(), &start) && !is_after(table.first_key(), end));
+            for table in tables {
+                sources.push(Box::new(TableIter::new(table.clone(), start.clone())));
+            }
+        } else {
+            // tables of the other levels don't overlap, they are read one after the other
+            let first = tables.partition_point(|table| is_before(table.last_key(), &start));
+            let tables = tables[first..].iter().filter(|table| !is_after(table.first_key(), end));
+            sources.push(Box::new(tables.flat_map(move |table| TableIter::new(table.clone(), start.clone()))));
+        }
+    }
+    let mut pairs = Vec::new();
+    for entry in MergeIter::new(sources) {
+        let (key, slot) = entry?;
+        if is_after(&key, end) {
+            return Ok((pairs, true));
+        }
+        if slot.live_value(now).is_some() {
+            pairs.push((key, slot.value.unwrap_or_default(), slot.expires_at));
+            if pairs.len() >= limit {
+                return Ok((pairs, false));
+            }
+        }
+    }
+    Ok((pairs, true))
+}
+
+///
+/// whether `key` sorts before every key from `start` on
+///
+fn is_before(key: &[u8], start: &Bound<Vec<u8>>) -> bool {
+    match start {
+        Bound::Included(start) => key < start.as_slice(),
+        Bound::Excluded(start) => key <= start.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+///
+/// whether `key` sorts after every key up to `end`
+///
+fn is_after(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(end) => key > end.as_slice(),
+        Bound::Excluded(end) => key >= end.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+///
+/// path of the write-ahead log `id` in `dir`
+///
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.{}", id, WAL_EXT))
+}
+
+///
+/// path of the table `id` in `dir`
+///
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.{}", id, TABLE_EXT))
+}
+
+///
+/// write the manifest of `version` to `dir`, through a temporary file swapped in by renaming it
+/// layout: | magic | version: u16 | reserved: u16 | log floor: u64 | tables: u32 |
+///         | level: u8 | table id: u64 | ... | crc32: u32 |
+/// levels are listed in order, level 0 the newest table first
+///
+fn write_manifest(dir: &Path, version: &Version) -> Result<()> {
+    write_manifest_of(dir, version.log_floor(), &version.levels)
+}
+
+fn write_manifest_of(dir: &Path, log_floor: u64, levels: &[Vec<Arc<Table>>]) -> Result<()> {
+    let tables: usize = levels.iter().map(|tables| tables.len()).sum();
+    let mut buf = Vec::with_capacity(MANIFEST_HEADER_LEN + tables * MANIFEST_ENTRY_LEN + 4);
+    buf.extend_from_slice(&MANIFEST_MAGIC);
+    buf.extend_from_slice(&MANIFEST_VERSION.to_le_bytes());
+    buf.extend_from_slice(&[0u8; 2]);
+    buf.extend_from_slice(&log_floor.to_le_bytes());
+    buf.extend_from_slice(&(tables as u32).to_le_bytes());
+    for (level, tables) in levels.iter().enumerate() {
+        for table in tables {
+            buf.push(level as u8);
+            buf.extend_from_slice(&table.id().to_le_bytes());
+        }
+    }
+    buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+    let path = dir.join(MANIFEST_FILE);
+    let tmp = tmp_path(&path);
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+///
+/// log floor and tables of every level from the manifest of `dir`, None if there is none yet
+///
+fn read_manifest(dir: &Path) -> Result<Option<(u64, Vec<(usize, u64)>)>> {
+    let path = dir.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let raw = fs::read(path)?;
+    if raw.len() < MANIFEST_HEADER_LEN + 4 || raw[..4] != MANIFEST_MAGIC {
+        return Err(KvError::CorruptedRecord);
+    }
+    let version = u16::from_le_bytes([raw[4], raw[5]]);
+    if version > MANIFEST_VERSION {
+        return Err(KvError::UnsupportedFormatVersion(version));
+    }
+    let (body, crc) = raw.split_at(raw.len() - 4);
+    if crc32fast::hash(body) != memtable::read_u32(crc) {
+        return Err(KvError::ChecksumMismatch);
+    }
+    let log_floor = memtable::read_u64(&body[8..16]);
+    let count = memtable::read_u32(&body[16..20]) as usize;
+    if body.len() != MANIFEST_HEADER_LEN + count * MANIFEST_ENTRY_LEN {
+        return Err(KvError::CorruptedRecord);
+    }
+    let mut tables = Vec::with_capacity(count);
+    for entry in body[MANIFEST_HEADER_LEN..].chunks(MANIFEST_ENTRY_LEN) {
+        let level = entry[0] as usize;
+        if level >= MAX_LEVELS {
+            return Err(KvError::CorruptedRecord);
+        }
+        tables.push((level, memtable::read_u64(&entry[1..])));
+    }
+    Ok(Some((log_floor, tables)))
+}
+
+impl KvsEngine for LsmStore {
+
+    type Snapshot = LsmSnapshot;
+
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        self.apply(&mut writer, vec![(key.into(), Some(value.into()), None)])
+    }
+
+    fn set_with_ttl(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        self.apply(&mut writer, vec![(key.into(), Some(value.into()), Some(expiry_after(ttl)))])
+    }
+
+    ///
+    /// write the value again with the new expiry
+    ///
+    fn expire(&self, key: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        let key = key.into();
+        let mut writer = self.lock_writer()?;
+        let (value, _) = self.live(&key)?.ok_or(KvError::KeyNotFound)?;
+        self.apply(&mut writer, vec![(key, Some(value), Some(expiry_after(ttl)))])
+    }
+
+    fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        match self.live(key.as_ref())? {
+            None => Err(KvError::KeyNotFound),
+            Some((_, expires_at)) => Ok(expires_at.map(|expires_at|
+                Duration::from_millis(expires_at.saturating_sub(now_millis()))
+            )),
+        }
+    }
+
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let mut writer = self.lock_writer()?;
+        let (value, _) = self.live(&key)?.ok_or(KvError::KeyNotFound)?;
+        self.apply(&mut writer, vec![(key, Some(value), None)])
+    }
+
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.live(key.as_ref())?.map(|(value, _)| value))
+    }
+
+    ///
+    /// remove a key, an expired one is removed on the way but reported missing
+    ///
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let mut writer = self.lock_writer()?;
+        let slot = match self.latest(&key)? {
+            Some(ref slot) if slot.value.is_none() => return Err(KvError::KeyNotFound),
+            Some(slot) => slot,
+            None => return Err(KvError::KeyNotFound),
+        };
+        self.apply(&mut writer, vec![(key, None, None)])?;
+        if slot.live_value(now_millis()).is_none() {
+            return Err(KvError::KeyNotFound);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.lock_writer()?.sync()
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let writes = batch.into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value) => (key, Some(value), None),
+                BatchOp::Remove(key) => (key, None, None),
+            })
+            .collect();
+        let mut writer = self.lock_writer()?;
+        self.apply(&mut writer, writes)
+    }
+
+    fn compare_and_swap(&self, key: impl Into<Vec<u8>>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let mut writer = self.lock_writer()?;
+        let current = self.live(&key)?.map(|(value, _)| value);
+        if current != expected {
+            return Err(KvError::CompareAndSwapFailed(current));
+        }
+        // removing a missing key writes nothing
+        if current.is_none() && new.is_none() {
+            return Ok(());
+        }
+        self.apply(&mut writer, vec![(key, new, None)])
+    }
+
+    ///
+    /// the transaction begins at the latest sequence number, a key written after it conflicts
+    ///
+    fn begin(&self) -> Result<Transaction> {
+        Ok(Transaction::new(self.lock_writer()?.seq))
+    }
+
+    fn txn_get(&self, txn: &mut Transaction, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if let Some(value) = txn.pending(&key) {
+            return Ok(value);
+        }
+        if let Some((value, _)) = txn.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let slot = self.latest(&key)?;
+        let version = slot.as_ref().map_or(0, |slot| slot.seq);
+        if version > txn.start {
+            return Err(KvError::TransactionConflict);
+        }
+        let now = now_millis();
+        let value = slot.and_then(|slot| slot.live_value(now).cloned());
+        txn.reads.insert(key, (value.clone(), version));
+        Ok(value)
+    }
+
+    ///
+    /// fail if a key read or written was written by another write since the transaction began
+    ///
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        for key in txn.reads.keys().chain(txn.writes.keys()) {
+            if self.latest(key)?.map_or(0, |slot| slot.seq) > txn.start {
+                return Err(KvError::TransactionConflict);
+            }
+        }
+        let writes = txn.writes.into_iter().map(|(key, value)| (key, value, None)).collect();
+        self.apply(&mut writer, writes)
+    }
+
+    ///
+    /// merge the memtables and the tables in key order, the memtable a chunk at a time
+    /// so that it's not held, and so copied by writes, while tables are read
+    ///
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut pairs = Vec::new();
+        loop {
+            let wanted = limit.map_or(usize::max_value(), |limit| limit - pairs.len());
+            if wanted == 0 || engine::is_empty_range(&range) {
+                break;
+            }
+            let (chunk, imm, levels) = match self.shared.version.read() {
+                Ok(guard) => {
+                    let chunk: Vec<(Vec<u8>, Slot)> = guard.mem.range(&range)
+                        .take(wanted)
+                        .map(|(key, slot)| (key.clone(), slot.clone()))
+                        .collect();
+                    (chunk, guard.imm.clone(), guard.levels.clone())
+                },
+                Err(_) => return Err(KvError::LockError),
+            };
+            // the memtable is only known up to the end of the chunk if it was cut short
+            let chunk_end = match chunk.last() {
+                Some((key, _)) if chunk.len() == wanted => Bound::Included(key.clone()),
+                _ => range.1.clone(),
+            };
+            let mem: EntryIter = Box::new(chunk.into_iter().map(Ok));
+            let (found, ended) = merge_range(mem, &imm, &levels, &range, &chunk_end, wanted, now_millis())?;
+            pairs.extend(found.into_iter().map(|(key, value, _)| (key, value)));
+            match chunk_end {
+                Bound::Included(key) if ended => range.0 = Bound::Excluded(key),
+                _ => break,
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn snapshot(&self) -> Result<LsmSnapshot> {
+        let version = self.current()?;
+        Ok(LsmSnapshot::new(version, now_millis(), self.shared.filtered.clone()))
+    }
+
+    ///
+    /// copy the tables, and write the memtables to a write-ahead log of the checkpoint,
+    /// which it flushes to a table once opened
+    ///
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        engine::prepare_empty_dir(dest)?;
+        let version = self.current()?;
+        for table in version.levels.iter().flatten() {
+            copy_file_prefix(table.file(), table.size(), &table_path(dest, table.id()))?;
+        }
+        let wal_id = self.shared.new_file_id();
+        let mut wal = Wal::create(&wal_path(dest, wal_id))?;
+        let mut entries = Vec::new();
+        for mem in version.imm.iter().rev().chain(std::iter::once(&version.mem)) {
+            entries.extend(mem.iter().map(|(key, slot)| (key.clone(), slot.clone())));
+        }
+        if !entries.is_empty() {
+            wal.append(&entries)?;
+            wal.sync()?;
+        }
+        write_manifest_of(dest, wal_id, &version.levels)?;
+        engine::write_engine_marker(dest, EngineKind::Lsm)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::path::Path;
+
+use super::{Result, LsmStore, SyncPolicy};
+
+/// memtable size (in bytes) before it's sealed and flushed to a table
+const DEFAULT_MEMTABLE_SIZE: u64 = 4 * 1024 * 1024;
+/// data blocks are cut once they reach that many bytes
+const DEFAULT_BLOCK_SIZE: usize = 4 * 1024;
+/// about 1% of the lookups of a key a table doesn't have read a block anyway
+const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+/// level 0 is compacted into level 1 once it holds that many tables
+const DEFAULT_LEVEL0_FILE_LIMIT: usize = 4;
+/// level 1 is compacted into level 2 once it holds that many bytes
+const DEFAULT_LEVEL_BASE_SIZE: u64 = 10 * 1024 * 1024;
+/// every level holds that many times more bytes than the one above
+const DEFAULT_LEVEL_SIZE_MULTIPLIER: u64 = 10;
+/// tables written by a compaction are cut once they reach that many bytes
+const DEFAULT_TABLE_FILE_SIZE: u64 = 2 * 1024 * 1024;
+
+///
+/// options to open an LsmStore with, created by `LsmStore::builder()`
+///
+/// ```ignore
+/// let store = LsmStore::builder()
+///     .memtable_size(16 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::Always)
+///     .open("./database")?;
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    pub(super) memtable_size: u64,
+    pub(super) block_size: usize,
+    pub(super) bloom_bits_per_key: usize,
+    pub(super) level0_file_limit: usize,
+    pub(super) level_base_size: u64,
+    pub(super) level_size_multiplier: u64,
+    pub(super) table_file_size: u64,
+    pub(super) sync_policy: SyncPolicy,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: DEFAULT_MEMTABLE_SIZE,
+            block_size: DEFAULT_BLOCK_SIZE,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            level0_file_limit: DEFAULT_LEVEL0_FILE_LIMIT,
+            level_base_size: DEFAULT_LEVEL_BASE_SIZE,
+            level_size_multiplier: DEFAULT_LEVEL_SIZE_MULTIPLIER,
+            table_file_size: DEFAULT_TABLE_FILE_SIZE,
+            sync_policy: SyncPolicy::default(),
+        }
+    }
+}
+
+impl LsmOptions {
+
+    ///
+    /// size (in bytes) the memtable grows to before it's sealed and flushed to a table
+    ///
+    pub fn memtable_size(mut self, bytes: u64) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    ///
+    /// size (in bytes) of the data blocks of a table, a lookup reads a whole block
+    ///
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.block_size = bytes;
+        self
+    }
+
+    ///
+    /// bits of bloom filter for every key of a table, more bits read fewer blocks in vain,
+    /// with 0 every lookup reads a block of every table which may hold the key
+    ///
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.bloom_bits_per_key = bits;
+        self
+    }
+
+    ///
+    /// compact level 0 into level 1 once it holds that many tables, every lookup of a key
+    /// which isn't in a memtable may read one block of every table of level 0
+    ///
+    pub fn level0_file_limit(mut self, tables: usize) -> Self {
+        self.level0_file_limit = tables;
+        self
+    }
+
+    ///
+    /// bytes level 1 holds before it's compacted into level 2
+    ///
+    pub fn level_base_size(mut self, bytes: u64) -> Self {
+        self.level_base_size = bytes;
+        self
+    }
+
+    ///
+    /// how many times more bytes every level holds than the one above
+    ///
+    pub fn level_size_multiplier(mut self, multiplier: u64) -> Self {
+        self.level_size_multiplier = multiplier;
+        self
+    }
+
+    ///
+    /// size (in bytes) tables written by a compaction grow to before a new one is started
+    ///
+    pub fn table_file_size(mut self, bytes: u64) -> Self {
+        self.table_file_size = bytes;
+        self
+    }
+
+    ///
+    /// when the write-ahead log is synced, as for a `KvStore`, see `SyncPolicy`,
+    /// never by default: a power loss may lose the latest acknowledged writes
+    ///
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    ///
+    /// open the store in `path` with these options
+    ///
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<LsmStore> {
+        LsmStore::open_with_options(path, self.clone())
+    }
+}
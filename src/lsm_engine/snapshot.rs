@@ -0,0 +1,54 @@
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+
+use super::{Result, Version, EntryIter, search, merge_range};
+use super::engine::{self, KvsSnapshot};
+
+///
+/// read-only view of an `LsmStore` as of the time it was taken, see `KvsEngine::snapshot`
+///
+/// it holds the memtables and the tables of the store at that time, which are never written
+/// again: the next write to the store copies the memtable rather than change it under the
+/// snapshot, and compactions go on deleting tables, which stay readable through the handles
+/// of the snapshot until it's dropped
+///
+#[derive(Clone)]
+pub struct LsmSnapshot {
+    version: Version,
+    /// time the snapshot was taken, expiries are checked against it
+    taken_at: u64,
+    filtered: Arc<AtomicU64>,
+}
+
+impl LsmSnapshot {
+
+    pub(super) fn new(version: Version, taken_at: u64, filtered: Arc<AtomicU64>) -> Self {
+        LsmSnapshot { version, taken_at, filtered }
+    }
+}
+
+impl KvsSnapshot for LsmSnapshot {
+
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        let slot = match self.version.mem.get(key) {
+            Some(slot) => Some(slot.clone()),
+            None => search(&self.version.imm, &self.version.levels, key, &self.filtered)?,
+        };
+        Ok(slot.and_then(|slot| slot.live_value(self.taken_at).cloned()))
+    }
+
+    fn scan_with_expiry<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>)
+        -> Result<Vec<(Vec<u8>, Vec<u8>, Option<u64>)>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let limit = limit.unwrap_or(usize::max_value());
+        if limit == 0 || engine::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let mem: EntryIter = Box::new(self.version.mem.range(&range).map(|(key, slot)| Ok((key.clone(), slot.clone()))));
+        let (pairs, _) = merge_range(mem, &self.version.imm, &self.version.levels, &range, &range.1, limit,
+                                     self.taken_at)?;
+        Ok(pairs)
+    }
+}
@@ -0,0 +1,386 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+use std::vec;
+
+use super::{Result, KvError, read_exact_at};
+use super::bloom::{self, BloomFilter};
+use super::memtable::{self, Slot, read_u32, read_u64};
+
+/// magic bytes at the end of every table
+const TABLE_MAGIC: [u8; 4] = *b"KVST";
+/// version of the table format
+const TABLE_VERSION: u16 = 1;
+/// footer: filter offset + filter len + index offset + index len + entries + max seq
+/// + magic + format version + reserved bytes + crc32 of the rest of the footer
+const FOOTER_LEN: usize = 60;
+/// every block ends with the crc32 of its content
+const BLOCK_TRAILER_LEN: usize = 4;
+
+///
+/// where a data block of a table is, along with the last key it holds
+///
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    len: u64,
+}
+
+///
+/// writes a table out of entries given in key order, see `Table` for the layout
+///
+pub struct TableBuilder {
+    file: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    block_size: usize,
+    bits_per_key: usize,
+    index: Vec<BlockHandle>,
+    first_key: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    hashes: Vec<u64>,
+    max_seq: u64,
+}
+
+impl TableBuilder {
+
+    ///
+    /// start the table at `path`, cutting blocks at about `block_size` bytes
+    /// with a bloom filter of `bits_per_key` bits for every key
+    ///
+    pub fn create(path: &Path, block_size: usize, bits_per_key: usize) -> Result<Self> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        Ok(TableBuilder {
+            file: BufWriter::new(file),
+            offset: 0,
+            block: Vec::new(),
+            block_size,
+            bits_per_key,
+            index: Vec::new(),
+            first_key: None,
+            last_key: Vec::new(),
+            hashes: Vec::new(),
+            max_seq: 0,
+        })
+    }
+
+    ///
+    /// add the entry of `key`, which sorts after every key added so far
+    ///
+    pub fn add(&mut self, key: &[u8], slot: &Slot) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+        memtable::encode_entry(&mut self.block, key, slot);
+        self.last_key = key.to_vec();
+        self.hashes.push(bloom::key_hash(key));
+        self.max_seq = self.max_seq.max(slot.seq);
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// bytes written so far, the table is about that long once finished
+    ///
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    ///
+    /// write the pending block, the filter, the index and the footer, and sync the table
+    ///
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_block()?;
+        let filter = BloomFilter::build(&self.hashes, self.bits_per_key).encode();
+        let (filter_offset, filter_len) = self.write_block(&filter)?;
+
+        let first_key = self.first_key.take().unwrap_or_default();
+        let mut index = Vec::new();
+        index.extend_from_slice(&(first_key.len() as u32).to_le_bytes());
+        index.extend_from_slice(&first_key);
+        index.extend_from_slice(&(self.index.len() as u32).to_le_bytes());
+        for handle in self.index.iter() {
+            index.extend_from_slice(&(handle.last_key.len() as u32).to_le_bytes());
+            index.extend_from_slice(&handle.last_key);
+            index.extend_from_slice(&handle.offset.to_le_bytes());
+            index.extend_from_slice(&handle.len.to_le_bytes());
+        }
+        let (index_offset, index_len) = self.write_block(&index)?;
+
+        let mut footer = Vec::with_capacity(FOOTER_LEN);
+        for field in &[filter_offset, filter_len, index_offset, index_len, self.hashes.len() as u64, self.max_seq] {
+            footer.extend_from_slice(&field.to_le_bytes());
+        }
+        footer.extend_from_slice(&TABLE_MAGIC);
+        footer.extend_from_slice(&TABLE_VERSION.to_le_bytes());
+        footer.extend_from_slice(&[0u8; 2]);
+        footer.extend_from_slice(&crc32fast::hash(&footer).to_le_bytes());
+        self.file.write_all(&footer)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::take(&mut self.block);
+        let (offset, len) = self.write_block(&block)?;
+        self.index.push(BlockHandle { last_key: self.last_key.clone(), offset, len });
+        Ok(())
+    }
+
+    ///
+    /// append `content` followed by its checksum, return where it is and how long
+    ///
+    fn write_block(&mut self, content: &[u8]) -> Result<(u64, u64)> {
+        let offset = self.offset;
+        self.file.write_all(content)?;
+        self.file.write_all(&crc32fast::hash(content).to_le_bytes())?;
+        let len = (content.len() + BLOCK_TRAILER_LEN) as u64;
+        self.offset += len;
+        Ok((offset, len))
+    }
+}
+
+///
+/// sorted table of entries, immutable once written
+///
+/// the index and the bloom filter are kept in memory once the table is opened, so a lookup
+/// reads at most one data block, and none at all for most keys the table doesn't have
+/// layout: | data block | ... | filter block | index block | footer |
+/// data block: | entries, see `memtable::encode_entry` | crc32: u32 |
+/// index block: | first key len: u32 | first key | blocks: u32 |
+///              | last key len: u32 | last key | offset: u64 | len: u64 | ... | crc32: u32 |
+///
+pub struct Table {
+    id: u64,
+    file: File,
+    size: u64,
+    first_key: Vec<u8>,
+    last_key: Vec<u8>,
+    index: Vec<BlockHandle>,
+    filter: BloomFilter,
+    max_seq: u64,
+}
+
+impl Table {
+
+    ///
+    /// open the table `id` at `path`, reading its index and filter in
+    ///
+    pub fn open(path: &Path, id: u64) -> Result<Self> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN as u64 {
+            return Err(KvError::CorruptedRecord);
+        }
+        let mut footer = [0u8; FOOTER_LEN];
+        read_exact_at(&file, &mut footer, size - FOOTER_LEN as u64)?;
+        if footer[48..52] != TABLE_MAGIC {
+            return Err(KvError::CorruptedRecord);
+        }
+        let version = u16::from_le_bytes([footer[52], footer[53]]);
+        if version > TABLE_VERSION {
+            return Err(KvError::UnsupportedFormatVersion(version));
+        }
+        if crc32fast::hash(&footer[..56]) != read_u32(&footer[56..60]) {
+            return Err(KvError::ChecksumMismatch);
+        }
+        let field = |i: usize| read_u64(&footer[i * 8..]);
+        let filter = BloomFilter::decode(&read_block(&file, field(0), field(1))?)?;
+        let index = read_block(&file, field(2), field(3))?;
+
+        let mut rest = &index[..];
+        let first_key = take_key(&mut rest)?;
+        let count = read_u32(take(&mut rest, 4)?);
+        let mut handles = Vec::new();
+        for _ in 0..count {
+            let last_key = take_key(&mut rest)?;
+            let offset = read_u64(take(&mut rest, 8)?);
+            let len = read_u64(take(&mut rest, 8)?);
+            handles.push(BlockHandle { last_key, offset, len });
+        }
+        let last_key = handles.last().map(|handle| handle.last_key.clone()).unwrap_or_default();
+        Ok(Table {
+            id,
+            file,
+            size,
+            first_key,
+            last_key,
+            index: handles,
+            filter,
+            max_seq: field(5),
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    ///
+    /// length of the table file
+    ///
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn first_key(&self) -> &[u8] {
+        &self.first_key
+    }
+
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    ///
+    /// highest sequence number of the entries
+    ///
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq
+    }
+
+    ///
+    /// whether the table may hold keys between `first` and `last`, both included
+    ///
+    pub fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        self.first_key.as_slice() <= last && self.last_key.as_slice() >= first
+    }
+
+    ///
+    /// entry of `key`, None if the table doesn't have it
+    /// the second value tells whether the filter saved reading a block
+    ///
+    pub fn get(&self, key: &[u8]) -> Result<(Option<Slot>, bool)> {
+        if key < self.first_key.as_slice() || key > self.last_key.as_slice() {
+            return Ok((None, false));
+        }
+        if !self.filter.may_contain(bloom::key_hash(key)) {
+            return Ok((None, true));
+        }
+        let i = self.index.partition_point(|handle| handle.last_key.as_slice() < key);
+        if i == self.index.len() {
+            return Ok((None, false));
+        }
+        let slot = self.read_entries(i)?
+            .into_iter()
+            .find(|(entry_key, _)| entry_key.as_slice() == key)
+            .map(|(_, slot)| slot);
+        Ok((slot, false))
+    }
+
+    ///
+    /// entries of the data block `i`
+    ///
+    fn read_entries(&self, i: usize) -> Result<Vec<(Vec<u8>, Slot)>> {
+        let handle = &self.index[i];
+        let block = read_block(&self.file, handle.offset, handle.len)?;
+        let mut entries = Vec::new();
+        let mut rest = &block[..];
+        while !rest.is_empty() {
+            let (key, slot, len) = memtable::decode_entry(rest)?;
+            entries.push((key, slot));
+            rest = &rest[len..];
+        }
+        Ok(entries)
+    }
+}
+
+///
+/// entries of a table in key order from a given key on, reading one block at a time
+///
+pub struct TableIter {
+    table: Arc<Table>,
+    next_block: usize,
+    entries: vec::IntoIter<(Vec<u8>, Slot)>,
+    /// entries before it are skipped, only the first block read has some
+    start: Bound<Vec<u8>>,
+}
+
+impl TableIter {
+
+    pub fn new(table: Arc<Table>, start: Bound<Vec<u8>>) -> Self {
+        let next_block = match start {
+            Bound::Included(ref key) | Bound::Excluded(ref key) =>
+                table.index.partition_point(|handle| handle.last_key < *key),
+            Bound::Unbounded => 0,
+        };
+        TableIter { table, next_block, entries: Vec::new().into_iter(), start }
+    }
+
+    fn is_before_start(&self, key: &[u8]) -> bool {
+        match self.start {
+            Bound::Included(ref start) => key < start.as_slice(),
+            Bound::Excluded(ref start) => key <= start.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Slot)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, slot)) = self.entries.next() {
+                if self.is_before_start(&key) {
+                    continue;
+                }
+                return Some(Ok((key, slot)));
+            }
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_entries(self.next_block) {
+                Ok(entries) => {
+                    self.entries = entries.into_iter();
+                    self.next_block += 1;
+                },
+                Err(err) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+///
+/// read the block at `offset` and check it against its checksum, return its content
+///
+fn read_block(file: &File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    if len < BLOCK_TRAILER_LEN as u64 {
+        return Err(KvError::CorruptedRecord);
+    }
+    let mut block = vec![0u8; len as usize];
+    read_exact_at(file, &mut block, offset)?;
+    let content_len = block.len() - BLOCK_TRAILER_LEN;
+    if crc32fast::hash(&block[..content_len]) != read_u32(&block[content_len..]) {
+        return Err(KvError::ChecksumMismatch);
+    }
+    block.truncate(content_len);
+    Ok(block)
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if rest.len() < len {
+        return Err(KvError::CorruptedRecord);
+    }
+    let (head, tail) = rest.split_at(len);
+    *rest = tail;
+    Ok(head)
+}
+
+fn take_key(rest: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = read_u32(take(rest, 4)?) as usize;
+    Ok(take(rest, len)?.to_vec())
+}
@@ -0,0 +1,122 @@
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::path::Path;
+
+use super::{Result, KvError};
+use super::memtable::{self, Memtable, Slot};
+
+/// magic bytes at the start of every write-ahead log
+const WAL_MAGIC: [u8; 4] = *b"KVWL";
+/// version of the write-ahead log format
+const WAL_VERSION: u16 = 1;
+/// header: magic + format version + reserved bytes
+const WAL_HEADER_LEN: usize = 8;
+/// record prefix: length of the body + crc32 of the body
+const RECORD_PREFIX_LEN: usize = 8;
+
+///
+/// write-ahead log of a memtable, every write is appended to it before it's applied
+///
+/// a write is a single record holding all of its entries, so a batch is replayed whole or
+/// not at all
+/// layout: | header | body len: u32 | crc32: u32 | entries, see `memtable::encode_entry` | ...
+///
+pub struct Wal {
+    file: File,
+    /// bytes of the log up to the end of its last whole record
+    len: u64,
+}
+
+impl Wal {
+
+    ///
+    /// create the empty log at `path`
+    ///
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut header = [0u8; WAL_HEADER_LEN];
+        header[..4].copy_from_slice(&WAL_MAGIC);
+        header[4..6].copy_from_slice(&WAL_VERSION.to_le_bytes());
+        file.write_all(&header)?;
+        file.sync_all()?;
+        Ok(Wal { file, len: WAL_HEADER_LEN as u64 })
+    }
+
+    ///
+    /// append the entries of a write as one record
+    /// if it's only partly written, the log is cut back to the end of the previous record,
+    /// so that the records appended after it aren't left behind a torn one, which replay
+    /// would stop at
+    ///
+    pub fn append(&mut self, entries: &[(Vec<u8>, Slot)]) -> Result<()> {
+        let mut body = Vec::new();
+        for (key, slot) in entries {
+            memtable::encode_entry(&mut body, key, slot);
+        }
+        let mut buf = Vec::with_capacity(RECORD_PREFIX_LEN + body.len());
+        buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        buf.extend_from_slice(&body);
+        if let Err(err) = self.file.write_all(&buf) {
+            self.file.set_len(self.len)?;
+            self.file.seek(SeekFrom::Start(self.len))?;
+            return Err(KvError::IoErr(err));
+        }
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+///
+/// apply the writes of the log at `path` to `mem`, return the highest sequence number seen
+/// replay stops at the first torn or corrupted record, which was never acknowledged
+/// unless the log was synced, along with everything after it
+///
+pub fn replay(path: &Path, mem: &mut Memtable) -> Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0u8; WAL_HEADER_LEN];
+    if reader.read_exact(&mut header).is_err() {
+        // crashed while creating it, nothing was written yet
+        return Ok(0);
+    }
+    if header[..4] != WAL_MAGIC {
+        return Err(KvError::CorruptedRecord);
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > WAL_VERSION {
+        return Err(KvError::UnsupportedFormatVersion(version));
+    }
+    let mut max_seq = 0;
+    loop {
+        let mut prefix = [0u8; RECORD_PREFIX_LEN];
+        if reader.read_exact(&mut prefix).is_err() {
+            break;
+        }
+        let body_len = memtable::read_u32(&prefix[0..4]) as u64;
+        let crc = memtable::read_u32(&prefix[4..8]);
+        let mut body = Vec::new();
+        // read through `take` so that a garbage length can't make us allocate it upfront
+        reader.by_ref().take(body_len).read_to_end(&mut body)?;
+        if body.len() as u64 != body_len || crc32fast::hash(&body) != crc {
+            break;
+        }
+        let mut entries = Vec::new();
+        let mut rest = &body[..];
+        while !rest.is_empty() {
+            let (key, slot, len) = memtable::decode_entry(rest)?;
+            entries.push((key, slot));
+            rest = &rest[len..];
+        }
+        for (key, slot) in entries {
+            max_seq = max_seq.max(slot.seq);
+            mem.insert(key, slot);
+        }
+    }
+    Ok(max_seq)
+}
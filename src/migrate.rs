@@ -0,0 +1,119 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::engine::{self, Result, KvsEngine, KvError, EngineKind};
+use super::kvs_engine::{KvStore, SyncPolicy};
+use super::sled_engine::SledStore;
+use super::lsm_engine::LsmStore;
+use super::dump::{self, Digest};
+
+/// suffix of the directory the store is converted into, next to the original one
+const MIGRATE_TMP_SUFFIX: &'static str = ".migrate";
+/// suffix the original directory is moved aside with while the converted one is swapped in
+const MIGRATE_OLD_SUFFIX: &'static str = ".old";
+
+///
+/// convert the data directory `dir` from engine `from` to engine `to` in place, keys keep
+/// their values and expiries, return the digest of the converted store
+///
+/// the store is converted into a directory next to `dir`, then counted and checksummed
+/// against the source, only then is it swapped in, so a failed migration leaves `dir`
+/// untouched. The store must not be open meanwhile, the server included
+///
+pub fn migrate(dir: impl AsRef<Path>, from: EngineKind, to: EngineKind) -> Result<Digest> {
+    let dir = dir.as_ref();
+    if !dir.is_dir() {
+        return Err(KvError::StoreNotFound);
+    }
+    if let Some(name) = engine::read_engine_marker(dir)? {
+        if name != from.name() {
+            return Err(KvError::EngineMismatch(name));
+        }
+    }
+    let tmp = sibling(dir, MIGRATE_TMP_SUFFIX);
+    // left over by an interrupted migration
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    if from == to {
+        return match from {
+            EngineKind::Kvs => dump::digest(&open_kvs_source(dir)?),
+            EngineKind::Sled => dump::digest(&SledStore::open(dir)?),
+            EngineKind::Lsm => dump::digest(&LsmStore::open(dir)?),
+        };
+    }
+    // the stores are dropped, that is closed, at the end of the statement
+    let res = match from {
+        EngineKind::Kvs => convert_into(&open_kvs_source(dir)?, to, &tmp),
+        EngineKind::Sled => convert_into(&SledStore::open(dir)?, to, &tmp),
+        EngineKind::Lsm => convert_into(&LsmStore::open(dir)?, to, &tmp),
+    };
+    let digest = match res {
+        Ok(digest) => digest,
+        Err(err) => {
+            let _ = fs::remove_dir_all(&tmp);
+            return Err(err);
+        }
+    };
+
+    // neither store is open anymore, both directories can be moved
+    let old = sibling(dir, MIGRATE_OLD_SUFFIX);
+    fs::rename(dir, &old)?;
+    fs::rename(&tmp, dir)?;
+    fs::remove_dir_all(&old)?;
+    Ok(digest)
+}
+
+///
+/// copy every pair of `src` into `dst` and check `dst` ends up with exactly those
+///
+fn convert<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D) -> Result<Digest> {
+    let copied = dump::copy(src, dst)?;
+    if !dump::same_content(src, dst)? {
+        return Err(KvError::MigrationMismatch);
+    }
+    Ok(copied)
+}
+
+///
+/// copy `src` into a new store of engine `to` in `dest`, see `convert`
+///
+fn convert_into<S: KvsEngine>(src: &S, to: EngineKind, dest: &Path) -> Result<Digest> {
+    match to {
+        EngineKind::Kvs => convert(src, &open_kvs_dest(dest)?),
+        EngineKind::Sled => convert(src, &SledStore::open(dest)?),
+        EngineKind::Lsm => convert(src, &open_lsm_dest(dest)?),
+    }
+}
+
+///
+/// a kvs source is only read, and must exist
+/// a directory written before segments were introduced is read in place, see `KvStoreOptions::read_only`
+///
+fn open_kvs_source(dir: &Path) -> Result<KvStore> {
+    KvStore::builder().read_only(true).open(dir)
+}
+
+///
+/// a kvs destination syncs every write, so it's all on disk once copied, before it's swapped in
+///
+fn open_kvs_dest(dir: &Path) -> Result<KvStore> {
+    KvStore::builder().sync_policy(SyncPolicy::Always).error_if_exists(true).open(dir)
+}
+
+///
+/// an lsm destination syncs its write-ahead log on every write, like a kvs one
+///
+fn open_lsm_dest(dir: &Path) -> Result<LsmStore> {
+    LsmStore::builder().sync_policy(SyncPolicy::Always).open(dir)
+}
+
+///
+/// `dir` with `suffix` appended to its last component
+///
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    dir.with_file_name(name)
+}
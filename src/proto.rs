@@ -1,16 +1,46 @@
 use serde::{Serialize, Deserialize};
 
+use super::engine::WriteBatch;
+
 ///
 /// Simple command for interaction between kvs-client & kvs-server
+/// keys and values are raw bytes
 ///
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ReqProto {
     /// `get <KEY>`
-    Get(String),
+    Get(Vec<u8>),
     /// `rm <KEY>`
-    Remove(String),
+    Remove(Vec<u8>),
     /// `set <KEY> <VALUE>`
-    Set(String, String),
+    Set(Vec<u8>, Vec<u8>),
+    /// `set <KEY> <VALUE> --ttl <SECONDS>`
+    SetWithTtl(Vec<u8>, Vec<u8>, u64),
+    /// `expire <KEY> <SECONDS>`
+    Expire(Vec<u8>, u64),
+    /// `ttl <KEY>`, answered with the seconds left, -1 if the key never expires
+    Ttl(Vec<u8>),
+    /// `persist <KEY>`
+    Persist(Vec<u8>),
+    /// sets and removes applied atomically
+    Batch(WriteBatch),
+    /// `cas <KEY> [--expected <VALUE>] [--new <VALUE>]`, no expected value for an absent key,
+    /// no new value to remove the key
+    CompareAndSwap(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>),
+    /// begin a transaction, answered with its id
+    Begin,
+    /// `get <KEY>` within transaction `id`
+    TxnGet(u64, Vec<u8>),
+    /// `set <KEY> <VALUE>` within transaction `id`
+    TxnSet(u64, Vec<u8>, Vec<u8>),
+    /// `rm <KEY>` within transaction `id`
+    TxnRemove(u64, Vec<u8>),
+    /// commit transaction `id`
+    Commit(u64),
+    /// drop transaction `id` without applying it
+    Abort(u64),
+    /// `backup <DIR>`, checkpoint the store into a directory under the backup directory of the server
+    Backup(String),
 }
 
 ///
@@ -18,8 +48,10 @@ pub enum ReqProto {
 ///
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RespProto {
-    /// successful response
-    OK(Option<String>),
+    /// successful response, a value or a number as its decimal digits
+    OK(Option<Vec<u8>>),
+    /// a write was applied, nothing to answer
+    Done,
     /// error response
     Error(String)
 }
@@ -1,17 +1,94 @@
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{self, Sender, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use sled::{Db, IVec};
+use fs2::FileExt;
+use sled::{Batch, Db, IVec, Tree, Transactional};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionResult, TransactionalTree,
+};
 
-use super::engine::{Result, KvsEngine, KvError};
+use super::engine::{self, Result, KvsEngine, KvsSnapshot, KvError, WriteBatch, BatchOp, Transaction, EngineKind};
+use super::kvs_engine::{now_millis, expiry_after, is_expired, is_expired_at};
 
 /// default log file
 const DEFAULT_PATH: &'static str = "./database";
+/// tree holding the expiry of the keys which have one, in milliseconds since the unix epoch
+const EXPIRY_TREE: &'static str = "__kvs_expiry";
+/// file sled writes in every directory it opens
+const SLED_CONFIG_FILE: &'static str = "conf";
+/// file sled holds an exclusive lock on while the directory is open
+const SLED_DB_FILE: &'static str = "db";
+/// how often the reaper purges expired keys
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Wrapper for sled Db struct
+///
+/// stores are written by sled 0.34, whose on-disk format differs from the sled 0.24 the
+/// earlier releases were built with: it can't read their directories, which are refused
+/// with `KvError::OutdatedSledFormat` rather than opened. To carry one over, read its keys
+/// back with a release built on sled 0.24 and set them into a new directory
+///
 #[derive(Clone)]
 pub struct SledStore {
     db: Db,
+    expiries: Tree,
+    /// the reaper stops once every clone of the store is dropped,
+    /// declared after the db so it's dropped after it
+    _reaper: Arc<Reaper>,
+    /// writes share it, taking a snapshot or a checkpoint holds it exclusively while walking the data
+    gate: Arc<RwLock<()>>,
+}
+
+///
+/// handle on the thread purging expired keys
+///
+/// dropping it waits for the thread to stop, and so to release its handles on the db,
+/// then, if the store was opened from a directory, for sled to close it,
+/// the directory can be opened again as soon as the last clone of the store is dropped
+///
+struct Reaper {
+    sender: Mutex<Option<Sender<()>>>,
+    handle: Option<JoinHandle<()>>,
+    dir: Option<PathBuf>,
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        // the thread wakes up as soon as the sender is dropped
+        if let Ok(mut sender) = self.sender.lock() {
+            sender.take();
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        if let Some(dir) = self.dir.as_ref() {
+            let _ = wait_until_closed(dir);
+        }
+    }
+}
+
+///
+/// read-only view of a `SledStore` as of the time it was taken, see `KvsEngine::snapshot`
+///
+/// sled 0.34 has no snapshots of its own and its iterators aren't consistent across keys,
+/// so taking a snapshot walks the whole store while writes are held off. Only handles on the
+/// keys and values are kept, sled shares their bytes with its cache rather than copy them,
+/// but writes still stall for the walk and the snapshot keeps a handle per key until it's
+/// dropped: on a large store, expect a pause of every writer and memory in proportion
+/// to the number of keys for each snapshot, dump and migration
+///
+#[derive(Clone)]
+pub struct SledSnapshot {
+    /// live value of every key, along with its expiry
+    data: Arc<BTreeMap<IVec, (IVec, Option<u64>)>>,
 }
 
 impl Default for SledStore {
@@ -32,34 +109,65 @@ impl SledStore {
     /// init with a sled DB
     ///
     pub fn new(db: Db) -> Self {
-        SledStore { db }
+        Self::init(db, None)
     }
 
     ///
     /// return initialized KvStore
+    /// a directory written by sled 0.24 is refused with `KvError::OutdatedSledFormat`
     ///
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         let p = Self::ensure_path(path.as_ref())?;
-        let db = Db::start_default(p)?;
-        Ok(SledStore { db })
+        if is_outdated_format(&p)? {
+            return Err(KvError::OutdatedSledFormat);
+        }
+        let db = sled::open(&p)?;
+        engine::write_engine_marker(&p, EngineKind::Sled)?;
+        Ok(Self::init(db, Some(p)))
+    }
+
+    ///
+    /// wrap `db`, opened from `dir` if known
+    ///
+    fn init(db: Db, dir: Option<PathBuf>) -> Self {
+        let expiries = db.open_tree(EXPIRY_TREE).expect("Couldn't open sled expiry tree");
+        let (sender, receiver) = mpsc::channel();
+        let gate = Arc::new(RwLock::new(()));
+        let db_cp = db.clone();
+        let expiries_cp = expiries.clone();
+        let gate_cp = gate.clone();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(REAP_INTERVAL) {
+                let _ = reap_expired(&db_cp, &expiries_cp, &gate_cp);
+            }
+        });
+        let reaper = Reaper { sender: Mutex::new(Some(sender)), handle: Some(handle), dir };
+        SledStore { db, expiries, _reaper: Arc::new(reaper), gate }
     }
 
     ///
     /// Path must meet
     /// 1. not exist
     /// 2. if exist, there must be more than 1 file
+    /// 3. not be marked as belonging to another engine, and if not marked at all but
+    ///    non-empty, hold a sled config file, so another engine's files are never adopted
     ///
     fn ensure_path(path: &Path) -> Result<PathBuf> {
         if path.exists() {
             if path.is_file() {
                 return Err(KvError::DirPathExpected);
             }
+            engine::check_engine_marker(path, EngineKind::Sled)?;
             let dir_entry: Vec<fs::DirEntry> = fs::read_dir(path)?
                 .map(|dir| dir.expect("map DirEntry error"))
                 .collect();
             if dir_entry.len() == 1 {
                 return Err(KvError::FileMismatchInPath);
             }
+            if !dir_entry.is_empty() && engine::read_engine_marker(path)?.is_none() &&
+                !path.join(SLED_CONFIG_FILE).exists() {
+                return Err(KvError::FileMismatchInPath);
+            }
         }
         Ok(path.to_path_buf())
     }
@@ -67,33 +175,359 @@ impl SledStore {
 
 impl KvsEngine for SledStore {
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let res = self.db.set(key, IVec::from(value.as_bytes()));
-        match res {
-            Ok(_) => Ok(()),
-            Err(err) => Err(KvError::SledError(err)),
+    type Snapshot = SledSnapshot;
+
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.write(|data, expiries| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            expiries.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn set_with_ttl(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let expires_at = expiry_after(ttl);
+        self.write(|data, expiries| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            expiries.insert(key.as_slice(), &expires_at.to_be_bytes()[..])?;
+            Ok(())
+        })
+    }
+
+    fn expire(&self, key: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        let key = key.into();
+        let expires_at = expiry_after(ttl);
+        self.write(|data, expiries| {
+            if live_value(data, expiries, key.as_slice())?.is_none() {
+                return Err(ConflictableTransactionError::Abort(KvError::KeyNotFound));
+            }
+            expiries.insert(key.as_slice(), &expires_at.to_be_bytes()[..])?;
+            Ok(())
+        })
+    }
+
+    fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        let key = key.as_ref();
+        if self.db.get(key)?.is_none() {
+            return Err(KvError::KeyNotFound);
+        }
+        match self.expiries.get(key)?.map(|ivec| decode_expiry(&ivec)) {
+            None => Ok(None),
+            Some(expires_at) if is_expired(Some(expires_at)) => Err(KvError::KeyNotFound),
+            Some(expires_at) => Ok(Some(Duration::from_millis(expires_at.saturating_sub(now_millis())))),
         }
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let res = self.db.get(key);
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        self.write(|data, expiries| {
+            if live_value(data, expiries, key.as_slice())?.is_none() {
+                return Err(ConflictableTransactionError::Abort(KvError::KeyNotFound));
+            }
+            expiries.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let res = self.db.get(key.as_ref());
         match res {
             Ok(None) => Ok(None),
-            Ok(Some(ivec)) => Ok(Some(
-                String::from_utf8(ivec.to_vec()).expect("value is not utf-8 encoded")
-            )),
+            Ok(Some(_)) if self.is_expired_key(key.as_ref())? => Ok(None),
+            Ok(Some(ivec)) => Ok(Some(ivec.to_vec())),
             Err(err) => Err(KvError::SledError(err)),
         }
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        match self.db.del(key) {
-            Ok(Some(_)) => {
-                self.db.flush(); // FIXME: temporarily call flush here to make test pass
-                Ok(())
-            },
-            Ok(None) => Err(KvError::KeyNotFound),
-            Err(err) => Err(KvError::SledError(err))
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if engine::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let mut pairs = Vec::new();
+        let limit = limit.unwrap_or(usize::max_value());
+        for item in self.db.range(range) {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (key, ivec) = item?;
+            if self.is_expired_key(&key)? {
+                continue;
+            }
+            pairs.push((key.to_vec(), ivec.to_vec()));
         }
+        Ok(pairs)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let ops = batch.into_ops();
+        self.write(|data, expiries| {
+            for op in ops.iter() {
+                match op {
+                    BatchOp::Set(key, value) => data.insert(key.as_slice(), value.as_slice())?,
+                    BatchOp::Remove(key) => data.remove(key.as_slice())?,
+                };
+                expiries.remove(op_key(op).as_slice())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn compare_and_swap(&self, key: impl Into<Vec<u8>>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        // a transaction over both trees rather than sled's own compare_and_swap,
+        // an expired key which isn't reaped yet must compare as missing
+        self.write(|data, expiries| {
+            let current = live_value(data, expiries, key.as_slice())?;
+            if current.as_ref().map(|v| v.as_ref()) != expected.as_deref() {
+                return Err(ConflictableTransactionError::Abort(KvError::CompareAndSwapFailed(
+                    current.map(|ivec| ivec.to_vec())
+                )));
+            }
+            match new {
+                Some(ref value) => data.insert(key.as_slice(), value.as_slice())?,
+                None => data.remove(key.as_slice())?,
+            };
+            expiries.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn begin(&self) -> Result<Transaction> {
+        // sled keeps no versions, reads are checked by value at commit
+        Ok(Transaction::new(0))
+    }
+
+    fn txn_get(&self, txn: &mut Transaction, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if let Some(value) = txn.pending(&key) {
+            return Ok(value);
+        }
+        if let Some((value, _)) = txn.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.get(&key)?;
+        txn.reads.insert(key, (value.clone(), 0));
+        Ok(value)
+    }
+
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        self.write(|data, expiries| {
+            for (key, (value, _)) in txn.reads.iter() {
+                let current = live_value(data, expiries, key)?;
+                if current.as_ref().map(|v| v.as_ref()) != value.as_deref() {
+                    return Err(ConflictableTransactionError::Abort(KvError::TransactionConflict));
+                }
+            }
+            for (key, value) in txn.writes.iter() {
+                match value {
+                    Some(value) => data.insert(key.as_slice(), value.as_slice())?,
+                    None => data.remove(key.as_slice())?,
+                };
+                expiries.remove(key.as_slice())?;
+            }
+            Ok(())
+        })
     }
-}
\ No newline at end of file
+
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        // only the walk is done under the gate, the map is built once writes can go on
+        let (pairs, expiries, taken_at) = {
+            let _gate = self.gate.write().map_err(|_| KvError::LockError)?;
+            let pairs = self.db.iter().collect::<sled::Result<Vec<(IVec, IVec)>>>()?;
+            let expiries = self.expiries.iter().collect::<sled::Result<BTreeMap<IVec, IVec>>>()?;
+            (pairs, expiries, now_millis())
+        };
+        let mut data = BTreeMap::new();
+        for (key, value) in pairs {
+            let expires_at = expiries.get(&key).map(|ivec| decode_expiry(ivec));
+            if !is_expired_at(expires_at, taken_at) {
+                data.insert(key, (value, expires_at));
+            }
+        }
+        Ok(SledSnapshot { data: Arc::new(data) })
+    }
+
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        engine::prepare_empty_dir(dest.as_ref())?;
+        // copied under the gate, written out once writes can go on
+        let (data, expiries) = {
+            let _gate = self.gate.write().map_err(|_| KvError::LockError)?;
+            let data = self.db.iter().collect::<sled::Result<Vec<(IVec, IVec)>>>()?;
+            let expiries = self.expiries.iter().collect::<sled::Result<Vec<(IVec, IVec)>>>()?;
+            (data, expiries)
+        };
+        {
+            let db = sled::open(dest.as_ref())?;
+            engine::write_engine_marker(dest.as_ref(), EngineKind::Sled)?;
+            let mut batch = Batch::default();
+            for (key, value) in data {
+                batch.insert(key, value);
+            }
+            db.apply_batch(batch)?;
+            let mut batch = Batch::default();
+            for (key, expires_at) in expiries {
+                batch.insert(key, expires_at);
+            }
+            db.open_tree(EXPIRY_TREE)?.apply_batch(batch)?;
+            db.flush()?;
+        }
+        // the checkpoint is ready to be opened only once sled is done with it
+        wait_until_closed(dest.as_ref())
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        self.write(|data, expiries| {
+            let existed = live_value(data, expiries, key.as_slice())?.is_some();
+            // drop an expired key on the way, it's reported missing anyway
+            data.remove(key.as_slice())?;
+            expiries.remove(key.as_slice())?;
+            if !existed {
+                return Err(ConflictableTransactionError::Abort(KvError::KeyNotFound));
+            }
+            Ok(())
+        })?;
+        self.db.flush()?; // FIXME: temporarily call flush here to make test pass
+        Ok(())
+    }
+}
+
+impl SledStore {
+
+    ///
+    /// run `f` as a transaction over the data and the expiry trees
+    ///
+    fn write<T, F>(&self, f: F) -> Result<T>
+        where F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KvError>
+    {
+        let _gate = self.gate.read().map_err(|_| KvError::LockError)?;
+        let res: TransactionResult<T, KvError> = (&*self.db, &self.expiries)
+            .transaction(|(data, expiries)| f(data, expiries));
+        match res {
+            Ok(value) => Ok(value),
+            Err(TransactionError::Abort(err)) => Err(err),
+            Err(TransactionError::Storage(err)) => Err(KvError::SledError(err)),
+        }
+    }
+
+    fn is_expired_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.expiries.get(key)?.is_some_and(|ivec| is_expired(Some(decode_expiry(&ivec)))))
+    }
+}
+
+impl KvsSnapshot for SledSnapshot {
+
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.get(key.as_ref()).map(|(value, _)| value.to_vec()))
+    }
+
+    fn scan_with_expiry<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>)
+        -> Result<Vec<(Vec<u8>, Vec<u8>, Option<u64>)>> {
+        if engine::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let range = (as_slice_bound(range.start_bound()), as_slice_bound(range.end_bound()));
+        Ok(self.data.range::<[u8], _>(range)
+            .take(limit.unwrap_or(usize::max_value()))
+            .map(|(key, (value, expires_at))| (key.to_vec(), value.to_vec(), *expires_at))
+            .collect())
+    }
+}
+
+///
+/// `bound` on the keys as a bound on byte slices, to look them up among `IVec` keys
+///
+fn as_slice_bound(bound: Bound<&Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_slice()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_slice()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+///
+/// value of `key` within a transaction, None if it's missing or expired
+///
+fn live_value(data: &TransactionalTree, expiries: &TransactionalTree, key: &[u8])
+    -> ConflictableTransactionResult<Option<IVec>, KvError>
+{
+    let value = data.get(key)?;
+    if value.is_some() {
+        if let Some(ivec) = expiries.get(key)? {
+            if is_expired(Some(decode_expiry(&ivec))) {
+                return Ok(None);
+            }
+        }
+    }
+    Ok(value)
+}
+
+///
+/// wait for sled to close the directory `dir`, which it does in the background once
+/// every handle on its db is dropped, until then opening it again fails or races
+///
+fn wait_until_closed(dir: &Path) -> Result<()> {
+    let file = fs::OpenOptions::new().read(true).write(true).open(dir.join(SLED_DB_FILE))?;
+    file.lock_exclusive()?;
+    file.unlock()?;
+    Ok(())
+}
+
+///
+/// remove every expired key, a key whose expiry changed meanwhile is left alone
+///
+fn reap_expired(db: &Db, expiries: &Tree, gate: &RwLock<()>) -> Result<()> {
+    let _gate = gate.read().map_err(|_| KvError::LockError)?;
+    let now = now_millis();
+    for item in expiries.iter() {
+        let (key, ivec) = item?;
+        if decode_expiry(&ivec) > now {
+            continue;
+        }
+        let res: TransactionResult<(), KvError> = (&**db, expiries).transaction(|(data, expiries)| {
+            if expiries.get(&key)?.as_ref() == Some(&ivec) {
+                data.remove(&key)?;
+                expiries.remove(&key)?;
+            }
+            Ok(())
+        });
+        if let Err(TransactionError::Storage(err)) = res {
+            return Err(KvError::SledError(err));
+        }
+    }
+    Ok(())
+}
+
+fn op_key(op: &BatchOp) -> &Vec<u8> {
+    match op {
+        BatchOp::Set(key, _) | BatchOp::Remove(key) => key,
+    }
+}
+
+fn decode_expiry(ivec: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&ivec[..8]);
+    u64::from_be_bytes(buf)
+}
+
+///
+/// whether `dir` was written by sled 0.24 or older: their config file is serialized with bincode,
+/// from 0.29 on it's text, one `name: value` line per parameter, the version among them
+///
+fn is_outdated_format(dir: &Path) -> Result<bool> {
+    let raw = match fs::read(dir.join(SLED_CONFIG_FILE)) {
+        Ok(raw) => raw,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(KvError::IoErr(err)),
+    };
+    // both end with a checksum of what comes before
+    let conf = String::from_utf8_lossy(&raw[..raw.len().saturating_sub(4)]);
+    Ok(!conf.lines().any(|line| line.starts_with("version: ")))
+}
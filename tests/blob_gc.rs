@@ -0,0 +1,96 @@
+use kvs::{KvStore, KvsEngine};
+use kvs::engine::KvError;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn open(dir: &Path) -> KvStore {
+    KvStore::builder()
+        .blob_threshold(1000)
+        .blob_file_size(3000)
+        .compaction_stale_ratio(0.1)
+        .compaction_interval(Duration::from_millis(20))
+        .open(dir)
+        .expect("Fail to open the store")
+}
+
+fn value(seed: u8) -> Vec<u8> {
+    vec![seed; 1200]
+}
+
+fn blob_files(dir: &Path) -> Vec<String> {
+    let mut files: Vec<String> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "blob"))
+        .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+        .collect();
+    files.sort();
+    files
+}
+
+///
+/// wait for the background collection to drop every blob file of `files`
+///
+fn wait_collected(dir: &Path, files: &[String]) {
+    for _ in 0..250 {
+        if blob_files(dir).iter().all(|file| !files.contains(file)) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("Blob files {:?} weren't collected, left {:?}", files, blob_files(dir));
+}
+
+// the collection of a blob file drops the expired keys whose value is in it,
+// they must stay gone once the store is opened again
+#[test]
+fn expired_blobs_stay_gone_after_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = open(temp_dir.path());
+        store.set_with_ttl("expiring", value(1), Duration::from_millis(20)).unwrap();
+        store.set("overwritten", value(2)).unwrap();
+        // seal the blob file of the first two values
+        store.set("kept", value(3)).unwrap();
+        store.set("other", value(4)).unwrap();
+        let first = blob_files(temp_dir.path());
+        assert!(first.len() >= 2, "{:?}", first);
+        let first = first[..first.len() - 1].to_vec();
+
+        thread::sleep(Duration::from_millis(40));
+        store.set("overwritten", value(5)).unwrap();
+        wait_collected(temp_dir.path(), &first);
+        assert_eq!(store.get("expiring").unwrap(), None);
+    }
+
+    let store = open(temp_dir.path());
+    assert_eq!(store.get("expiring").unwrap(), None);
+    assert!(matches!(store.ttl("expiring"), Err(KvError::KeyNotFound)));
+    let keys: Vec<Vec<u8>> = store.scan(.., None).unwrap().into_iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, vec![b"kept".to_vec(), b"other".to_vec(), b"overwritten".to_vec()]);
+    assert_eq!(store.get("overwritten").unwrap(), Some(value(5)));
+    assert_eq!(store.get("kept").unwrap(), Some(value(3)));
+    assert_eq!(store.get("other").unwrap(), Some(value(4)));
+}
+
+// a blob collected after its value expired isn't needed to read the key
+#[test]
+fn expired_blob_record_without_its_file() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = open(temp_dir.path());
+        store.set_with_ttl("expiring", value(1), Duration::from_millis(20)).unwrap();
+        store.set("kept", value(2)).unwrap();
+    }
+    thread::sleep(Duration::from_millis(40));
+    // as if the store was closed in the middle of a collection, after the blob file was removed
+    for file in blob_files(temp_dir.path()) {
+        fs::remove_file(temp_dir.path().join(file)).unwrap();
+    }
+
+    let store = KvStore::builder().read_only(true).open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("expiring").unwrap(), None);
+    assert!(matches!(store.ttl("expiring"), Err(KvError::KeyNotFound)));
+}
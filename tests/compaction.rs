@@ -0,0 +1,74 @@
+use kvs::{KvStore, KvsEngine};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn open(dir: &Path, compaction_interval: Duration) -> KvStore {
+    KvStore::builder()
+        .segment_size(4 * 1024)
+        .compaction_min_stale_bytes(1)
+        .compaction_interval(compaction_interval)
+        .open(dir)
+        .expect("Fail to open the store")
+}
+
+fn overwrite(store: &KvStore, rounds: u32) {
+    for round in 0..rounds {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round)).unwrap();
+        }
+    }
+}
+
+// the background compaction gets the log back to about the size of the live keys
+#[test]
+fn background_compaction_reclaims_stale_bytes() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), Duration::from_millis(20));
+    overwrite(&store, 20);
+    let mut stats = store.stats().unwrap();
+    for _ in 0..250 {
+        if stats.stale_bytes * 2 < stats.log_bytes {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+        stats = store.stats().unwrap();
+    }
+    assert!(stats.stale_bytes * 2 < stats.log_bytes, "{:?}", stats);
+    assert_eq!(stats.background_errors, 0);
+    assert_eq!(store.last_background_error(), None);
+    for i in 0..100 {
+        assert_eq!(store.get_string(format!("key{}", i)).unwrap(), Some(format!("value{}-19", i)));
+    }
+
+    drop(store);
+    let store = open(temp_dir.path(), Duration::from_millis(20));
+    assert_eq!(store.get_string("key7").unwrap(), Some("value7-19".to_string()));
+}
+
+// a compaction which fails is counted, and the store stays readable
+#[test]
+fn background_failures_are_reported() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("store");
+    // the first compaction is due well after the writes
+    let store = open(&dir, Duration::from_secs(1));
+    overwrite(&store, 20);
+    // the compacted segments can't be created anymore
+    fs::remove_dir_all(&dir).unwrap();
+
+    let mut stats = store.stats().unwrap();
+    for _ in 0..250 {
+        if stats.background_errors > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+        stats = store.stats().unwrap();
+    }
+    assert!(stats.background_errors > 0, "{:?}", stats);
+    let last = store.last_background_error().expect("No background error kept");
+    assert!(last.starts_with("compaction: "), "{}", last);
+    assert_eq!(store.get_string("key3").unwrap(), Some("value3-19".to_string()));
+}
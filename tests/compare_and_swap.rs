@@ -0,0 +1,82 @@
+use kvs::{KvStore, KvsEngine, SledStore};
+use kvs::engine::KvError;
+use std::thread;
+use tempfile::TempDir;
+
+fn bytes(value: &str) -> Option<Vec<u8>> {
+    Some(value.as_bytes().to_vec())
+}
+
+// every swap applies only from the expected value, and a failed one reports the current value
+fn check_conditions<E: KvsEngine>(engine: &E) {
+    // set if absent
+    engine.compare_and_swap("a", None, bytes("1")).unwrap();
+    match engine.compare_and_swap("a", None, bytes("2")) {
+        Err(KvError::CompareAndSwapFailed(current)) => assert_eq!(current, bytes("1")),
+        other => panic!("Expected CompareAndSwapFailed, got {:?}", other),
+    }
+    // set if equal
+    engine.compare_and_swap("a", bytes("1"), bytes("2")).unwrap();
+    assert_eq!(engine.get_string("a").unwrap(), Some("2".to_string()));
+    // remove if equal
+    match engine.compare_and_swap("a", bytes("1"), None) {
+        Err(KvError::CompareAndSwapFailed(current)) => assert_eq!(current, bytes("2")),
+        other => panic!("Expected CompareAndSwapFailed, got {:?}", other),
+    }
+    engine.compare_and_swap("a", bytes("2"), None).unwrap();
+    assert_eq!(engine.get_string("a").unwrap(), None);
+    match engine.compare_and_swap("a", bytes("2"), bytes("3")) {
+        Err(KvError::CompareAndSwapFailed(current)) => assert_eq!(current, None),
+        other => panic!("Expected CompareAndSwapFailed, got {:?}", other),
+    }
+    // nothing expected, nothing written
+    engine.compare_and_swap("a", None, None).unwrap();
+    assert_eq!(engine.get_string("a").unwrap(), None);
+}
+
+// concurrent increments retried on failure lose no update
+fn check_concurrent_increments<E: KvsEngine>(engine: &E) {
+    engine.set("counter", "0").unwrap();
+    let handles: Vec<_> = (0..4).map(|_| {
+        let engine = engine.clone();
+        thread::spawn(move || {
+            let mut done = 0;
+            while done < 100 {
+                let current = engine.get_string("counter").unwrap().unwrap();
+                let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                match engine.compare_and_swap("counter", bytes(&current), bytes(&next)) {
+                    Ok(()) => done += 1,
+                    Err(KvError::CompareAndSwapFailed(_)) => {},
+                    Err(err) => panic!("{:?}", err),
+                }
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get_string("counter").unwrap(), Some("400".to_string()));
+}
+
+#[test]
+fn kvs_store_compare_and_swap() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        check_conditions(&store);
+        check_concurrent_increments(&store);
+        store.compare_and_swap("kept", None, bytes("value")).unwrap();
+    }
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_string("a").unwrap(), None);
+    assert_eq!(store.get_string("kept").unwrap(), Some("value".to_string()));
+    assert_eq!(store.get_string("counter").unwrap(), Some("400".to_string()));
+}
+
+#[test]
+fn sled_store_compare_and_swap() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    check_conditions(&store);
+    check_concurrent_increments(&store);
+}
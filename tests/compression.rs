@@ -0,0 +1,135 @@
+use kvs::{Compression, KvStore, KvsEngine, WriteBatch};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+///
+/// a json document of about 6 KB, which compresses well
+///
+fn doc(i: usize) -> String {
+    let mut doc = String::from("{");
+    for field in 0..200 {
+        doc.push_str(&format!("\"field{}\":\"value {} of doc {}\",", field, field, i));
+    }
+    doc.push('}');
+    doc
+}
+
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect()
+}
+
+fn log_bytes(dir: &Path) -> u64 {
+    fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+///
+/// compression ids in the headers of the segments
+///
+fn segment_compressions(dir: &Path) -> Vec<u8> {
+    fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .map(|path| fs::read(path).unwrap()[6])
+        .collect()
+}
+
+fn wait_recompressed(dir: &Path, id: u8) {
+    for _ in 0..250 {
+        if segment_compressions(dir).iter().all(|&compression| compression == id) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("Segments weren't recompressed: {:?}", segment_compressions(dir));
+}
+
+#[test]
+fn values_round_trip_compressed() {
+    let temp_dir = TempDir::new().unwrap();
+    let open = || KvStore::builder().compression(Compression::Lz4).open(temp_dir.path()).unwrap();
+    {
+        let store = open();
+        for i in 0..50 {
+            store.set(format!("key{}", i), doc(i)).unwrap();
+        }
+        let mut batch = WriteBatch::new();
+        batch.set("batched", doc(100));
+        store.write_batch(batch).unwrap();
+        store.set_with_ttl("expiring", doc(101), Duration::from_secs(100)).unwrap();
+        store.set("small", "tiny").unwrap();
+        // incompressible, stored raw
+        store.set("noise", noise(4096)).unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.values, 54);
+        assert_eq!(stats.compressed_values, 52);
+        assert!(stats.compression_ratio() > 3.0, "{:?}", stats);
+        assert!(log_bytes(temp_dir.path()) * 3 < stats.value_bytes);
+    }
+    let store = open();
+    for i in 0..50 {
+        assert_eq!(store.get_string(format!("key{}", i)).unwrap(), Some(doc(i)));
+    }
+    assert_eq!(store.get_string("batched").unwrap(), Some(doc(100)));
+    assert_eq!(store.get_string("expiring").unwrap(), Some(doc(101)));
+    assert!(store.ttl("expiring").unwrap().is_some());
+    assert_eq!(store.get_string("small").unwrap(), Some("tiny".to_string()));
+    assert_eq!(store.get("noise").unwrap(), Some(noise(4096)));
+}
+
+#[test]
+fn values_below_the_threshold_are_stored_raw() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::builder()
+        .compression(Compression::Lz4)
+        .compression_threshold(1 << 20)
+        .open(temp_dir.path())
+        .unwrap();
+    store.set("key", doc(1)).unwrap();
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.compressed_values, 0);
+    assert_eq!(stats.compression_ratio(), 1.0);
+}
+
+// compactions rewrite the values as the options of the store now ask,
+// compressed ones back in raw as well
+#[test]
+fn compaction_recompresses_values() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for i in 0..100 {
+            store.set(format!("key{}", i), doc(i)).unwrap();
+        }
+    }
+    let raw = log_bytes(temp_dir.path());
+    assert_eq!(segment_compressions(temp_dir.path()), vec![0]);
+
+    let options = || KvStore::builder().compaction_interval(Duration::from_millis(20));
+    {
+        let store = options().compression(Compression::Lz4).open(temp_dir.path()).unwrap();
+        wait_recompressed(temp_dir.path(), 1);
+        assert_eq!(store.get_string("key7").unwrap(), Some(doc(7)));
+    }
+    assert!(log_bytes(temp_dir.path()) * 3 < raw);
+
+    let store = options().open(temp_dir.path()).unwrap();
+    wait_recompressed(temp_dir.path(), 0);
+    for i in 0..100 {
+        assert_eq!(store.get_string(format!("key{}", i)).unwrap(), Some(doc(i)));
+    }
+    assert!(log_bytes(temp_dir.path()) >= raw);
+}
@@ -0,0 +1,182 @@
+use kvs::{EncryptionKey, KvStore, KvsEngine};
+use kvs::engine::KvError;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn key(byte: u8) -> EncryptionKey {
+    EncryptionKey::new([byte; 32])
+}
+
+fn open(dir: &Path, key: EncryptionKey) -> kvs::engine::Result<KvStore> {
+    KvStore::builder().encryption_key(key).open(dir)
+}
+
+fn files(dir: &Path, ext: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |e| e == ext))
+        .collect();
+    files.sort();
+    files
+}
+
+fn leaks(dir: &Path, needle: &[u8]) -> bool {
+    fs::read_dir(dir).unwrap()
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .any(|bytes| bytes.windows(needle.len()).any(|window| window == needle))
+}
+
+fn read_u32(bytes: &[u8]) -> usize {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+}
+
+///
+/// offsets and lengths of the records of a segment, past its header
+///
+fn records(bytes: &[u8]) -> Vec<(usize, usize)> {
+    let mut records = Vec::new();
+    let mut pos = 8;
+    while pos < bytes.len() {
+        let len = 8 + read_u32(&bytes[pos..]);
+        records.push((pos, len));
+        pos += len;
+    }
+    records
+}
+
+///
+/// wait for the compactions to rewrite every one of `segments`
+///
+fn wait_rewritten(dir: &Path, segments: &[PathBuf]) {
+    for _ in 0..250 {
+        if segments.iter().all(|segment| !segment.exists()) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("Segments {:?} weren't rewritten", segments);
+}
+
+#[test]
+fn records_are_only_readable_with_the_key() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = open(temp_dir.path(), key(1)).unwrap();
+        for i in 0..100 {
+            store.set(format!("secret-key{}", i), format!("secret-value{}", i)).unwrap();
+        }
+        store.set_with_ttl("expiring", "secret-value", Duration::from_secs(100)).unwrap();
+        store.remove("secret-key0").unwrap();
+    }
+    assert!(!leaks(temp_dir.path(), b"secret"));
+
+    let store = open(temp_dir.path(), key(1)).unwrap();
+    assert_eq!(store.get_string("secret-key0").unwrap(), None);
+    assert_eq!(store.get_string("secret-key99").unwrap(), Some("secret-value99".to_string()));
+    assert!(store.ttl("expiring").unwrap().is_some());
+    drop(store);
+
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::DecryptionFailed)));
+    assert!(matches!(open(temp_dir.path(), key(2)), Err(KvError::DecryptionFailed)));
+}
+
+// a record changed on disk fails to decrypt, even with a checksum which matches again,
+// and so does a record copied over another one
+#[test]
+fn tampered_records_fail_to_decrypt() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = open(temp_dir.path(), key(1)).unwrap();
+        store.set("key1", "aaaa").unwrap();
+        store.set("key2", "bbbb").unwrap();
+        store.set("key3", "cccc").unwrap();
+    }
+    let segment = files(temp_dir.path(), "log").pop().unwrap();
+    let original = fs::read(&segment).unwrap();
+    let records = records(&original);
+
+    let mut bytes = original.clone();
+    let (pos, len) = records[1];
+    bytes[pos + len - 1] ^= 0x01;
+    let crc = crc32fast::hash(&bytes[pos + 8..pos + len]);
+    bytes[pos + 4..pos + 8].copy_from_slice(&crc.to_le_bytes());
+    fs::write(&segment, bytes).unwrap();
+    assert!(matches!(open(temp_dir.path(), key(1)), Err(KvError::DecryptionFailed)));
+
+    let mut bytes = original.clone();
+    let ((first, first_len), (last, last_len)) = (records[0], records[2]);
+    assert_eq!(first_len, last_len);
+    let copied = bytes[first..first + first_len].to_vec();
+    bytes[last..last + last_len].copy_from_slice(&copied);
+    fs::write(&segment, bytes).unwrap();
+    assert!(matches!(open(temp_dir.path(), key(1)), Err(KvError::DecryptionFailed)));
+
+    fs::write(&segment, original).unwrap();
+    assert_eq!(open(temp_dir.path(), key(1)).unwrap().get_string("key3").unwrap(), Some("cccc".to_string()));
+}
+
+// compactions rewrite the records of a plain store with the key, then with a new key
+// once the old one is given as an old key
+#[test]
+fn compaction_rotates_the_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = || KvStore::builder().segment_size(4 * 1024).compaction_interval(Duration::from_millis(20));
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for i in 0..200 {
+            store.set(format!("key{}", i), format!("plain-value{}", i)).unwrap();
+        }
+    }
+
+    let plain = files(temp_dir.path(), "log");
+    {
+        let store = options().encryption_key(key(1)).open(temp_dir.path()).unwrap();
+        wait_rewritten(temp_dir.path(), &plain);
+        store.set("first", "key").unwrap();
+    }
+    assert!(!leaks(temp_dir.path(), b"plain-value"));
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::DecryptionFailed)));
+
+    let encrypted = files(temp_dir.path(), "log");
+    {
+        let store = options().encryption_key(key(2)).old_encryption_key(key(1)).open(temp_dir.path()).unwrap();
+        assert_eq!(store.get_string("key7").unwrap(), Some("plain-value7".to_string()));
+        wait_rewritten(temp_dir.path(), &encrypted);
+        store.set("second", "key").unwrap();
+    }
+    assert!(matches!(open(temp_dir.path(), key(1)), Err(KvError::DecryptionFailed)));
+    let store = open(temp_dir.path(), key(2)).unwrap();
+    for i in 0..200 {
+        assert_eq!(store.get_string(format!("key{}", i)).unwrap(), Some(format!("plain-value{}", i)));
+    }
+    assert_eq!(store.get_string("first").unwrap(), Some("key".to_string()));
+    assert_eq!(store.get_string("second").unwrap(), Some("key".to_string()));
+}
+
+#[test]
+fn keys_from_files_and_environment() {
+    let temp_dir = TempDir::new().unwrap();
+    let hex = temp_dir.path().join("hex.key");
+    fs::write(&hex, format!("{}\n", "07".repeat(32))).unwrap();
+    let raw = temp_dir.path().join("raw.key");
+    fs::write(&raw, [7u8; 32]).unwrap();
+    let short = temp_dir.path().join("short.key");
+    fs::write(&short, "0707").unwrap();
+    env::set_var("KVS_ENCRYPTION_TEST_KEY", "07".repeat(32));
+
+    let dir = temp_dir.path().join("store");
+    open(&dir, key(7)).unwrap().set("key", "value").unwrap();
+    for key in vec![
+        EncryptionKey::from_file(&hex).unwrap(),
+        EncryptionKey::from_file(&raw).unwrap(),
+        EncryptionKey::from_env("KVS_ENCRYPTION_TEST_KEY").unwrap(),
+    ] {
+        assert_eq!(open(&dir, key).unwrap().get_string("key").unwrap(), Some("value".to_string()));
+    }
+    assert!(matches!(EncryptionKey::from_file(&short), Err(KvError::InvalidEncryptionKey)));
+    assert!(matches!(EncryptionKey::from_env("KVS_ENCRYPTION_TEST_UNSET"), Err(KvError::InvalidEncryptionKey)));
+}
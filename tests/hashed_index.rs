@@ -0,0 +1,101 @@
+use kvs::{IndexMode, KvStore, KvsEngine, KvsSnapshot};
+use std::ops::Bound;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const KEYS: u32 = 500;
+
+type Range = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+fn open(dir: &Path, mode: IndexMode) -> KvStore {
+    KvStore::builder()
+        .index_mode(mode)
+        .segment_size(8 * 1024)
+        .open(dir)
+        .expect("Fail to open the store")
+}
+
+fn fill(store: &KvStore) {
+    for i in 0..KEYS {
+        store.set(format!("key{:04}", i), format!("value{}", i)).unwrap();
+    }
+    for i in (0..KEYS).step_by(7) {
+        store.remove(format!("key{:04}", i)).unwrap();
+    }
+    for i in (3..KEYS).step_by(11) {
+        store.set_with_ttl(format!("key{:04}", i), "gone", Duration::from_millis(10)).unwrap();
+    }
+    thread::sleep(Duration::from_millis(20));
+}
+
+fn key(i: u32) -> Vec<u8> {
+    format!("key{:04}", i).into_bytes()
+}
+
+fn ranges() -> Vec<Range> {
+    vec![
+        (Bound::Unbounded, Bound::Unbounded),
+        (Bound::Included(key(100)), Bound::Excluded(key(300))),
+        (Bound::Excluded(key(100)), Bound::Included(key(300))),
+        (Bound::Excluded(b"key0100x".to_vec()), Bound::Unbounded),
+        (Bound::Unbounded, Bound::Included(key(3))),
+        (Bound::Included(key(300)), Bound::Excluded(key(100))),
+    ]
+}
+
+// a hashed index scans the same keys in the same order as a full one
+#[test]
+fn hashed_scans_match_full() {
+    let full_dir = TempDir::new().unwrap();
+    let hashed_dir = TempDir::new().unwrap();
+    let full = open(full_dir.path(), IndexMode::Full);
+    let hashed = open(hashed_dir.path(), IndexMode::Hashed);
+    fill(&full);
+    fill(&hashed);
+
+    for range in ranges() {
+        for &limit in &[None, Some(1), Some(10), Some(1000)] {
+            assert_eq!(hashed.scan(range.clone(), limit).unwrap(), full.scan(range.clone(), limit).unwrap(),
+                       "{:?} {:?}", range, limit);
+        }
+    }
+    for i in 0..KEYS {
+        assert_eq!(hashed.get(key(i)).unwrap(), full.get(key(i)).unwrap());
+    }
+
+    // and once the index is rebuilt from the log
+    drop(hashed);
+    let hashed = open(hashed_dir.path(), IndexMode::Hashed);
+    assert_eq!(hashed.scan(.., None).unwrap(), full.scan(.., None).unwrap());
+}
+
+// paging through a snapshot of a hashed index, one scan after the other,
+// gives every key once and in order, whatever is written after it's taken
+#[test]
+fn hashed_snapshot_pages() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), IndexMode::Hashed);
+    fill(&store);
+    let expected = store.scan(.., None).unwrap();
+    let snapshot = store.snapshot().unwrap();
+    for i in 0..KEYS {
+        store.set(key(i), "later").unwrap();
+    }
+    store.set("key9999", "later").unwrap();
+
+    let mut paged = Vec::new();
+    let mut start = Bound::Unbounded;
+    loop {
+        let page = snapshot.scan((start, Bound::Unbounded), Some(16)).unwrap();
+        if page.is_empty() {
+            break;
+        }
+        start = Bound::Excluded(page.last().unwrap().0.clone());
+        paged.extend(page);
+    }
+    assert_eq!(paged, expected);
+    assert_eq!(snapshot.scan((Bound::Included(key(100)), Bound::Excluded(key(200))), None).unwrap(),
+               expected.iter().filter(|(k, _)| *k >= key(100) && *k < key(200)).cloned().collect::<Vec<_>>());
+}
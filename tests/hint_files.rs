@@ -0,0 +1,104 @@
+use kvs::{KvStore, KvsEngine};
+use kvs::engine::KvError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn open(dir: &Path) -> KvStore {
+    KvStore::builder()
+        .segment_size(4 * 1024)
+        .compaction_min_stale_bytes(1)
+        .compaction_interval(Duration::from_millis(20))
+        .open(dir)
+        .expect("Fail to open the store")
+}
+
+fn files(dir: &Path, ext: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |e| e == ext))
+        .collect();
+    files.sort();
+    files
+}
+
+///
+/// overwrite every key a few times, then wait for the compactions to leave a single
+/// segment of live records, with its hint file
+///
+fn write_compacted(dir: &Path) {
+    let store = open(dir);
+    for round in 0..10 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round)).unwrap();
+        }
+    }
+    for _ in 0..250 {
+        if store.stats().unwrap().stale_bytes == 0 && !files(dir, "hint").is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(files(dir, "hint").len(), 1, "{:?}", store.stats().unwrap());
+    store.set("tail", "after compaction").unwrap();
+}
+
+fn check(store: &KvStore) {
+    for i in 0..100 {
+        assert_eq!(store.get_string(format!("key{}", i)).unwrap(), Some(format!("value{}-9", i)));
+    }
+    assert_eq!(store.get_string("tail").unwrap(), Some("after compaction".to_string()));
+}
+
+// the segments written by a compaction get a hint file each, the store reads
+// the same with and without them
+#[test]
+fn compaction_writes_hint_files() {
+    let temp_dir = TempDir::new().unwrap();
+    write_compacted(temp_dir.path());
+    let hints = files(temp_dir.path(), "hint");
+    for hint in hints.iter() {
+        assert!(hint.with_extension("log").exists(), "{:?} has no segment", hint);
+    }
+    check(&open(temp_dir.path()));
+
+    for hint in hints.iter() {
+        let mut bytes = fs::read(hint).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        fs::write(hint, bytes).unwrap();
+    }
+    check(&open(temp_dir.path()));
+
+    for hint in files(temp_dir.path(), "hint") {
+        fs::remove_file(hint).unwrap();
+    }
+    check(&open(temp_dir.path()));
+}
+
+// a segment with a valid hint file isn't replayed on open: a corrupt record in it only
+// shows when it's read, while a replay refuses to open the store
+#[test]
+fn open_indexes_from_hint_files() {
+    let temp_dir = TempDir::new().unwrap();
+    write_compacted(temp_dir.path());
+    let hint = files(temp_dir.path(), "hint").remove(0);
+    let segment = hint.with_extension("log");
+    let mut bytes = fs::read(&segment).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    fs::write(&segment, bytes).unwrap();
+
+    {
+        let store = KvStore::builder().read_only(true).open(temp_dir.path()).unwrap();
+        let failed = (0..100)
+            .filter(|i| matches!(store.get(format!("key{}", i)), Err(KvError::ChecksumMismatch)))
+            .count();
+        assert_eq!(failed, 1);
+    }
+
+    fs::remove_file(&hint).unwrap();
+    assert!(matches!(KvStore::builder().read_only(true).open(temp_dir.path()), Err(KvError::CorruptedLog(_, _))));
+}
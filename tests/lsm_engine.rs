@@ -0,0 +1,235 @@
+use kvs::{KvsEngine, KvsSnapshot, LsmStore, LsmStoreStats, SyncPolicy, WriteBatch};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// options small enough for a few thousand keys to go through every level
+fn open_small(dir: &Path) -> LsmStore {
+    LsmStore::builder()
+        .memtable_size(4 * 1024)
+        .block_size(512)
+        .table_file_size(8 * 1024)
+        .level_base_size(32 * 1024)
+        .level0_file_limit(2)
+        .level_size_multiplier(4)
+        .open(dir)
+        .expect("Fail to open the store")
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("key{:05}", i).into_bytes()
+}
+
+///
+/// wait for the background thread to flush every sealed memtable and to bring level 0
+/// back under its limit
+///
+fn wait_settled(store: &LsmStore) -> LsmStoreStats {
+    for _ in 0..250 {
+        let stats = store.stats().unwrap();
+        if stats.immutable_memtables == 0 && stats.level_tables[0] < 2 {
+            return stats;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("The store didn't settle: {:?}", store.stats().unwrap());
+}
+
+fn check(store: &LsmStore, model: &BTreeMap<Vec<u8>, Vec<u8>>, rng: &mut Rng) {
+    assert_eq!(store.scan(.., None).unwrap(), model.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>());
+    for _ in 0..50 {
+        let (a, b) = (key(rng.next() % 3000), key(rng.next() % 3000));
+        let (low, high) = if a <= b { (a, b) } else { (b, a) };
+        let range = (Bound::Included(low), Bound::Excluded(high));
+        let limit = if rng.next() % 2 == 0 { Some((rng.next() % 40) as usize) } else { None };
+        let expected: Vec<_> = model.range(range.clone())
+            .take(limit.unwrap_or(usize::max_value()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        assert_eq!(store.scan(range.clone(), limit).unwrap(), expected, "{:?} {:?}", range, limit);
+    }
+    for i in 0..3000 {
+        assert_eq!(store.get(key(i)).unwrap().as_ref(), model.get(&key(i)));
+    }
+}
+
+// writes flushed to tables and compacted through the levels read back the same
+// as they were written, before and after the store is opened again
+#[test]
+fn reads_match_writes_across_levels() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut model = BTreeMap::new();
+    {
+        let store = open_small(temp_dir.path());
+        for round in 0..20u64 {
+            for _ in 0..200 {
+                let i = rng.next() % 3000;
+                if rng.next() % 5 == 0 {
+                    let removed = store.remove(key(i));
+                    assert_eq!(removed.is_ok(), model.remove(&key(i)).is_some());
+                } else {
+                    let value = format!("value{}-{}", i, round).into_bytes();
+                    store.set(key(i), value.clone()).unwrap();
+                    model.insert(key(i), value);
+                }
+            }
+            let mut batch = WriteBatch::new();
+            batch.set(key(round), "batched").remove(key(round + 1));
+            store.write_batch(batch).unwrap();
+            model.insert(key(round), b"batched".to_vec());
+            model.remove(&key(round + 1));
+        }
+        let stats = wait_settled(&store);
+        assert!(stats.flushes > 0, "{:?}", stats);
+        assert!(stats.compactions > 0, "{:?}", stats);
+        assert!(stats.level_tables[1..].iter().any(|&tables| tables > 0), "{:?}", stats);
+        check(&store, &model, &mut rng);
+    }
+    let store = open_small(temp_dir.path());
+    check(&store, &model, &mut rng);
+}
+
+#[test]
+fn expired_keys_are_left_out() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open_small(temp_dir.path());
+    for i in 0..500 {
+        if i % 3 == 0 {
+            store.set_with_ttl(key(i), "short", Duration::from_millis(20)).unwrap();
+        } else {
+            store.set(key(i), "kept").unwrap();
+        }
+    }
+    thread::sleep(Duration::from_millis(40));
+    let keys: Vec<Vec<u8>> = store.scan(.., None).unwrap().into_iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, (0..500).filter(|i| i % 3 != 0).map(key).collect::<Vec<_>>());
+    assert_eq!(store.get(key(3)).unwrap(), None);
+    assert!(store.ttl(key(3)).is_err());
+    assert_eq!(store.ttl(key(1)).unwrap(), None);
+}
+
+#[test]
+fn snapshot_keeps_its_view() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open_small(temp_dir.path());
+    for i in 0..1000 {
+        store.set(key(i), "before").unwrap();
+    }
+    let snapshot = store.snapshot().unwrap();
+    for i in 0..1000 {
+        if i % 2 == 0 {
+            store.remove(key(i)).unwrap();
+        } else {
+            store.set(key(i), "after").unwrap();
+        }
+    }
+    let pairs = snapshot.scan(.., None).unwrap();
+    assert_eq!(pairs.len(), 1000);
+    assert!(pairs.iter().all(|(_, v)| v == b"before"));
+    assert_eq!(snapshot.get(key(10)).unwrap(), Some(b"before".to_vec()));
+    assert_eq!(store.get(key(10)).unwrap(), None);
+}
+
+///
+/// the write-ahead log with the highest id, the one written to
+///
+fn latest_wal(dir: &Path) -> PathBuf {
+    fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "wal"))
+        .filter_map(|path| {
+            let id = path.file_stem()?.to_str()?.parse::<u64>().ok()?;
+            Some((id, path))
+        })
+        .max()
+        .map(|(_, path)| path)
+        .expect("No write-ahead log in the store")
+}
+
+// the writes still in the memtable are replayed from the write-ahead log,
+// up to a torn record, which was never acknowledged
+#[test]
+fn wal_is_replayed_up_to_a_torn_record() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = LsmStore::open(temp_dir.path()).unwrap();
+        for i in 0..100 {
+            store.set(key(i), format!("value{}", i)).unwrap();
+        }
+        store.set("torn", "tail").unwrap();
+    }
+    let wal = latest_wal(temp_dir.path());
+    let len = fs::metadata(&wal).unwrap().len();
+    OpenOptions::new().write(true).open(&wal).unwrap().set_len(len - 3).unwrap();
+
+    let store = LsmStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("torn").unwrap(), None);
+    for i in 0..100 {
+        assert_eq!(store.get_string(key(i)).unwrap(), Some(format!("value{}", i)));
+    }
+    // what's written next is replayed as well
+    store.set("after", "reopen").unwrap();
+    drop(store);
+    let store = LsmStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_string("after").unwrap(), Some("reopen".to_string()));
+    assert_eq!(store.get_string(key(99)).unwrap(), Some("value99".to_string()));
+}
+
+// the write-ahead log is synced at the points of the same policies as a kvs store
+#[test]
+fn sync_points() {
+    const WRITES: u64 = 40;
+    let policies = vec![
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::Interval(Duration::from_millis(50)),
+        SyncPolicy::EveryWrites(16),
+    ];
+    for policy in policies {
+        let temp_dir = TempDir::new().unwrap();
+        let store = LsmStore::builder().sync_policy(policy).open(temp_dir.path()).unwrap();
+        for i in 0..WRITES {
+            store.set(key(i), "value").unwrap();
+        }
+        let stats = store.stats().unwrap();
+        match policy {
+            SyncPolicy::Never => {
+                assert_eq!(stats.syncs, 0);
+                assert_eq!(stats.unsynced_writes, WRITES);
+            },
+            SyncPolicy::Always => {
+                assert_eq!(stats.syncs, WRITES);
+                assert_eq!(stats.unsynced_writes, 0);
+            },
+            SyncPolicy::EveryWrites(n) => {
+                assert_eq!(stats.syncs, WRITES / n);
+                assert_eq!(stats.unsynced_writes, WRITES % n);
+            },
+            SyncPolicy::Interval(interval) => {
+                // the last writes are synced from the background once the interval is over
+                thread::sleep(interval * 4);
+                let stats = store.stats().unwrap();
+                assert!(stats.syncs >= 1 && stats.syncs < WRITES, "{:?}", stats);
+                assert_eq!(stats.unsynced_writes, 0);
+            },
+        }
+        store.flush().unwrap();
+        assert_eq!(store.stats().unwrap().unsynced_writes, 0, "{:?}", policy);
+    }
+}
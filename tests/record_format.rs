@@ -0,0 +1,110 @@
+use kvs::{KvStore, KvsEngine};
+use kvs::engine::KvError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// segment header: magic + format version + compression + reserved byte
+const HEADER_LEN: usize = 8;
+
+fn segment(dir: &Path) -> PathBuf {
+    dir.join("1.log")
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+///
+/// split the records following the header of a segment into their flags, key and value,
+/// checking the length and checksum of each
+///
+fn records(bytes: &[u8]) -> Vec<(u8, Vec<u8>, Vec<u8>)> {
+    let mut records = Vec::new();
+    let mut pos = HEADER_LEN;
+    while pos < bytes.len() {
+        let body_len = read_u32(&bytes[pos..]) as usize;
+        let body = &bytes[pos + 8..pos + 8 + body_len];
+        assert_eq!(read_u32(&bytes[pos + 4..]), crc32fast::hash(body));
+        let key_len = read_u32(&body[1..]) as usize;
+        let value_len = read_u32(&body[5..]) as usize;
+        assert_eq!(body.len(), 9 + key_len + value_len);
+        records.push((body[0], body[9..9 + key_len].to_vec(), body[9 + key_len..].to_vec()));
+        pos += 8 + body_len;
+    }
+    records
+}
+
+#[test]
+fn records_follow_a_versioned_header() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1", "value1").unwrap();
+        store.set("key2", "line\nbreak \"quoted\"").unwrap();
+        store.remove("key1").unwrap();
+    }
+    let bytes = fs::read(segment(temp_dir.path())).unwrap();
+    assert_eq!(&bytes[..4], b"KVSG");
+    assert!(u16::from_le_bytes([bytes[4], bytes[5]]) >= 1);
+    assert_eq!(records(&bytes), vec![
+        (0, b"key1".to_vec(), b"value1".to_vec()),
+        (0, b"key2".to_vec(), b"line\nbreak \"quoted\"".to_vec()),
+        (1, b"key1".to_vec(), Vec::new()),
+    ]);
+}
+
+#[test]
+fn newer_format_version_is_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1", "value1").unwrap();
+    }
+    let mut bytes = fs::read(segment(temp_dir.path())).unwrap();
+    bytes[4..6].copy_from_slice(&u16::max_value().to_le_bytes());
+    fs::write(segment(temp_dir.path()), bytes).unwrap();
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::UnsupportedFormatVersion(version)) => assert_eq!(version, u16::max_value()),
+        other => panic!("Expected UnsupportedFormatVersion, got {:?}", other.err()),
+    }
+}
+
+// a flipped bit in a record is caught by its checksum when the record is read
+#[test]
+fn corrupted_record_fails_its_checksum() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1", "value1").unwrap();
+    store.set("key2", "value2").unwrap();
+
+    let mut bytes = fs::read(segment(temp_dir.path())).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    fs::write(segment(temp_dir.path()), bytes).unwrap();
+
+    assert!(matches!(store.get("key2"), Err(KvError::ChecksumMismatch)));
+    assert_eq!(store.get_string("key1").unwrap(), Some("value1".to_string()));
+}
+
+// a store written as json lines by earlier versions still opens, and what's
+// written to it afterwards goes to binary segments
+#[test]
+fn legacy_json_log_is_read() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("data.log"), concat!(
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n",
+        "{\"Set\":{\"key\":\"key2\",\"value\":\"q\\\"uote\"}}\n",
+        "{\"Remove\":\"key1\"}\n",
+    )).unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get_string("key1").unwrap(), None);
+        assert_eq!(store.get_string("key2").unwrap(), Some("q\"uote".to_string()));
+        store.set("key3", "value3").unwrap();
+    }
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_string("key2").unwrap(), Some("q\"uote".to_string()));
+    assert_eq!(store.get_string("key3").unwrap(), Some("value3".to_string()));
+}
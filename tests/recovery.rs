@@ -0,0 +1,106 @@
+use kvs::{KvStore, KvsEngine, RecoveryMode};
+use kvs::engine::KvError;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open_strict(dir: &Path) -> kvs::engine::Result<KvStore> {
+    KvStore::builder().recovery_mode(RecoveryMode::Strict).open(dir)
+}
+
+fn write_keys(dir: &Path, count: u32) -> u64 {
+    let store = KvStore::open(dir).unwrap();
+    for i in 0..count {
+        store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    drop(store);
+    fs::metadata(dir.join("1.log")).unwrap().len()
+}
+
+// a record cut in the middle is refused in strict mode, and truncated otherwise
+#[test]
+fn torn_tail() {
+    let temp_dir = TempDir::new().unwrap();
+    let segment = temp_dir.path().join("1.log");
+    let len = write_keys(temp_dir.path(), 10);
+    // the length and checksum of a record, with only the start of its body
+    let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+    file.write_all(&[40, 0, 0, 0, 1, 2, 3, 4, 0, 3]).unwrap();
+    drop(file);
+
+    match open_strict(temp_dir.path()) {
+        Err(KvError::CorruptedLog(gen, offset)) => assert_eq!((gen, offset), (1, len)),
+        other => panic!("Expected CorruptedLog, got {:?}", other.err()),
+    }
+    assert_eq!(fs::metadata(&segment).unwrap().len(), len + 10);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let report = store.recovery_report();
+    assert_eq!(report.truncated_segment, Some(1));
+    assert_eq!(report.discarded_bytes, 10);
+    assert_eq!(fs::metadata(&segment).unwrap().len(), len);
+    for i in 0..10 {
+        assert_eq!(store.get_string(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+    }
+    store.set("after", "recovery").unwrap();
+    drop(store);
+
+    let store = open_strict(temp_dir.path()).unwrap();
+    assert_eq!(store.recovery_report().truncated_segment, None);
+    assert_eq!(store.get_string("after").unwrap(), Some("recovery".to_string()));
+}
+
+// a whole record whose checksum doesn't match is discarded along with what follows it
+#[test]
+fn corrupt_tail() {
+    let temp_dir = TempDir::new().unwrap();
+    let segment = temp_dir.path().join("1.log");
+    let len = write_keys(temp_dir.path(), 10);
+    let mut bytes = fs::read(&segment).unwrap();
+    bytes[len as usize - 1] ^= 0x01;
+    fs::write(&segment, bytes).unwrap();
+
+    assert!(matches!(open_strict(temp_dir.path()), Err(KvError::CorruptedLog(1, _))));
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    // length + checksum + flags + key and value lengths, then "key9" and "value9"
+    assert_eq!(store.recovery_report().discarded_bytes, 8 + 9 + 4 + 6);
+    assert_eq!(store.get_string("key8").unwrap(), Some("value8".to_string()));
+    assert_eq!(store.get_string("key9").unwrap(), None);
+}
+
+// only the tail of the last segment can be torn by a crash,
+// corruption anywhere else is never truncated away
+#[test]
+fn corruption_before_the_tail_is_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::builder().segment_size(1024).open(temp_dir.path()).unwrap();
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+    }
+    let first = temp_dir.path().join("1.log");
+    assert!(temp_dir.path().join("2.log").exists());
+    let mut bytes = fs::read(&first).unwrap();
+    bytes[20] ^= 0x01;
+    fs::write(&first, bytes).unwrap();
+
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::CorruptedLog(1, _))));
+    assert!(matches!(open_strict(temp_dir.path()), Err(KvError::CorruptedLog(1, _))));
+}
+
+#[test]
+fn torn_legacy_json_line() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("data.log"), concat!(
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n",
+        "{\"Set\":{\"key\":\"key2\",\"va",
+    )).unwrap();
+
+    assert!(open_strict(temp_dir.path()).is_err());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert!(store.recovery_report().discarded_bytes > 0);
+    assert_eq!(store.get_string("key1").unwrap(), Some("value1".to_string()));
+    assert_eq!(store.get_string("key2").unwrap(), None);
+}
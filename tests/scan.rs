@@ -0,0 +1,99 @@
+use kvs::{KvStore, KvsEngine, SledStore};
+use std::ops::Bound;
+use tempfile::TempDir;
+
+fn keys(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<String> {
+    pairs.into_iter().map(|(key, _)| String::from_utf8(key).unwrap()).collect()
+}
+
+fn bytes(key: &str) -> Vec<u8> {
+    key.as_bytes().to_vec()
+}
+
+fn fill<E: KvsEngine>(engine: &E) {
+    for key in &["b", "a", "ab", "abc", "b\u{10FFFF}", "ac", "c"] {
+        engine.set(*key, format!("value-{}", key)).unwrap();
+    }
+    engine.remove("ac").unwrap();
+}
+
+fn check_scans<E: KvsEngine>(engine: &E) {
+    let all = engine.scan(.., None).unwrap();
+    assert_eq!(keys(all.clone()), vec!["a", "ab", "abc", "b", "b\u{10FFFF}", "c"]);
+    assert!(all.iter().all(|(key, value)| value == format!("value-{}", String::from_utf8_lossy(key)).as_bytes()));
+
+    assert_eq!(keys(engine.scan(bytes("ab")..bytes("b"), None).unwrap()), vec!["ab", "abc"]);
+    assert_eq!(keys(engine.scan(bytes("ab")..=bytes("b"), None).unwrap()), vec!["ab", "abc", "b"]);
+    assert_eq!(keys(engine.scan(bytes("ab").., Some(2)).unwrap()), vec!["ab", "abc"]);
+    assert_eq!(keys(engine.scan(..bytes("b"), None).unwrap()), vec!["a", "ab", "abc"]);
+    let after_abc = (Bound::Excluded(bytes("abc")), Bound::Unbounded);
+    assert_eq!(keys(engine.scan(after_abc, Some(2)).unwrap()), vec!["b", "b\u{10FFFF}"]);
+    assert_eq!(keys(engine.scan(.., Some(0)).unwrap()), Vec::<String>::new());
+
+    assert_eq!(keys(engine.scan_prefix("a", None).unwrap()), vec!["a", "ab", "abc"]);
+    assert_eq!(keys(engine.scan_prefix("b", None).unwrap()), vec!["b", "b\u{10FFFF}"]);
+    assert_eq!(keys(engine.scan_prefix("a", Some(1)).unwrap()), vec!["a"]);
+    assert!(engine.scan_prefix("d", None).unwrap().is_empty());
+
+    // inverted and empty ranges hold nothing
+    assert!(engine.scan(bytes("c")..bytes("a"), None).unwrap().is_empty());
+    assert!(engine.scan(bytes("b")..bytes("b"), None).unwrap().is_empty());
+    let empty = (Bound::Excluded(bytes("b")), Bound::Excluded(bytes("b")));
+    assert!(engine.scan(empty, None).unwrap().is_empty());
+}
+
+///
+/// page through all the keys `page` at a time, starting each scan after the last key returned
+///
+fn page_through<E: KvsEngine>(engine: &E, page: usize) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut start = Bound::Unbounded;
+    loop {
+        let pairs = engine.scan((start.clone(), Bound::Unbounded), Some(page)).unwrap();
+        if pairs.is_empty() {
+            return found;
+        }
+        start = Bound::Excluded(pairs[pairs.len() - 1].0.clone());
+        found.extend(keys(pairs));
+    }
+}
+
+#[test]
+fn scan_kvs_store() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        fill(&store);
+        check_scans(&store);
+    }
+    // the order is rebuilt when the log is replayed
+    check_scans(&KvStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn scan_sled_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    fill(&store);
+    check_scans(&store);
+}
+
+#[test]
+fn page_through_a_range() {
+    fn fill_many<E: KvsEngine>(engine: &E) {
+        for i in (0..1000).rev() {
+            engine.set(format!("key{:04}", i), "value").unwrap();
+        }
+        for i in (0..1000).step_by(3) {
+            engine.remove(format!("key{:04}", i)).unwrap();
+        }
+    }
+    let expected: Vec<String> = (0..1000).filter(|i| i % 3 != 0).map(|i| format!("key{:04}", i)).collect();
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+    fill_many(&store);
+    assert_eq!(page_through(&store, 7), expected);
+    let store = SledStore::open(temp_dir.path().join("sled")).unwrap();
+    fill_many(&store);
+    assert_eq!(page_through(&store, 7), expected);
+}
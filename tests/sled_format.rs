@@ -0,0 +1,28 @@
+use kvs::{KvsEngine, SledStore};
+use kvs::engine::KvError;
+use std::fs;
+use tempfile::TempDir;
+
+// config file of a directory written by sled 0.24
+const OLD_CONF: &[u8] = include_bytes!("fixtures/sled-0.24-conf");
+
+#[test]
+fn sled_024_directory_is_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("conf"), OLD_CONF).unwrap();
+    fs::write(temp_dir.path().join("db"), b"").unwrap();
+    assert!(matches!(SledStore::open(temp_dir.path()), Err(KvError::OutdatedSledFormat)));
+    // left as it was
+    assert_eq!(fs::read(temp_dir.path().join("conf")).unwrap(), OLD_CONF);
+}
+
+#[test]
+fn sled_directory_reopens() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = SledStore::open(temp_dir.path()).unwrap();
+        store.set("key", "value").unwrap();
+    }
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_string("key").unwrap(), Some("value".to_string()));
+}
@@ -0,0 +1,287 @@
+use kvs::{KvStore, KvsEngine, SyncPolicy};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const KEYS: u32 = 200;
+/// directory the child writer writes into, see `writer_process`
+const DIR_VAR: &str = "KVS_SYNC_TEST_DIR";
+/// policy it opens the store with
+const POLICY_VAR: &str = "KVS_SYNC_TEST_POLICY";
+/// what it writes
+const MODE_VAR: &str = "KVS_SYNC_TEST_MODE";
+
+fn policies() -> Vec<SyncPolicy> {
+    vec![
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::Interval(Duration::from_millis(50)),
+        SyncPolicy::EveryWrites(16),
+    ]
+}
+
+fn policy_arg(policy: SyncPolicy) -> String {
+    match policy {
+        SyncPolicy::Never => "never".to_string(),
+        SyncPolicy::Always => "always".to_string(),
+        SyncPolicy::Interval(interval) => format!("interval:{}", interval.as_millis()),
+        SyncPolicy::EveryWrites(n) => format!("every:{}", n),
+    }
+}
+
+fn parse_policy(arg: &str) -> SyncPolicy {
+    let mut parts = arg.splitn(2, ':');
+    match (parts.next(), parts.next().map(|n| n.parse::<u64>().unwrap())) {
+        (Some("never"), None) => SyncPolicy::Never,
+        (Some("always"), None) => SyncPolicy::Always,
+        (Some("interval"), Some(millis)) => SyncPolicy::Interval(Duration::from_millis(millis)),
+        (Some("every"), Some(n)) => SyncPolicy::EveryWrites(n),
+        _ => panic!("Unknown sync policy {}", arg),
+    }
+}
+
+fn open(dir: &Path, policy: SyncPolicy, segment_size: u64) -> KvStore {
+    KvStore::builder()
+        .segment_size(segment_size)
+        .sync_policy(policy)
+        .open(dir)
+        .expect("Fail to open the store")
+}
+
+fn expected(i: u32) -> Option<String> {
+    match i % 10 {
+        0 => Some(format!("again{}", i)),
+        5 => None,
+        _ => Some(format!("value{}", i)),
+    }
+}
+
+///
+/// the active segment, the one with the highest generation
+///
+fn active_segment(dir: &Path) -> PathBuf {
+    fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .filter_map(|path| {
+            let gen = path.file_stem()?.to_str()?.parse::<u64>().ok()?;
+            Some((gen, path))
+        })
+        .max()
+        .map(|(_, path)| path)
+        .expect("No segment in the store")
+}
+
+///
+/// body of the child process `write_and_kill` runs, does nothing when run as a test of its own
+///
+/// `workload` writes `KEYS` keys, overwrites and removes some of them, and `torn` writes one
+/// more key after them, `synced` writes keys one at a time and reports how many of them,
+/// and how much of the segment, were synced. Then it waits to be killed
+///
+#[test]
+fn writer_process() {
+    let dir = match env::var(DIR_VAR) {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => return,
+    };
+    let policy = parse_policy(&env::var(POLICY_VAR).unwrap());
+    let mode = env::var(MODE_VAR).unwrap();
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    // after the name of the test, which the harness prints without a newline
+    writeln!(out).unwrap();
+    if mode == "synced" {
+        let store = open(&dir, policy, 1024 * 1024);
+        let mut synced = (0, fs::metadata(active_segment(&dir)).unwrap().len());
+        for i in 0..KEYS {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+            if store.stats().unwrap().unsynced_writes == 0 {
+                synced = (i + 1, fs::metadata(active_segment(&dir)).unwrap().len());
+            }
+        }
+        writeln!(out, "synced {} {}", synced.0, synced.1).unwrap();
+    } else {
+        let store = open(&dir, policy, 4 * 1024);
+        for i in 0..KEYS {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        for i in (0..KEYS).step_by(10) {
+            store.set(format!("key{}", i), format!("again{}", i)).unwrap();
+        }
+        for i in (5..KEYS).step_by(10) {
+            store.remove(format!("key{}", i)).unwrap();
+        }
+        if mode == "torn" {
+            store.set("torn", "tail").unwrap();
+        }
+    }
+    writeln!(out, "written").unwrap();
+    out.flush().unwrap();
+    loop {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+///
+/// run `writer_process` in a child process and kill it once it's written everything,
+/// as a crash would: nothing is flushed, no hint is written, the background threads stop
+/// on the spot, return the lines it printed
+///
+fn write_and_kill(dir: &Path, policy: SyncPolicy, mode: &str) -> Vec<String> {
+    let mut child = Command::new(env::current_exe().unwrap())
+        .args(&["writer_process", "--exact", "--nocapture", "--test-threads=1"])
+        .env(DIR_VAR, dir)
+        .env(POLICY_VAR, policy_arg(policy))
+        .env(MODE_VAR, mode)
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Fail to start the writer");
+    let mut lines = Vec::new();
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let line = line.unwrap();
+        if line == "written" {
+            break;
+        }
+        lines.push(line);
+    }
+    child.kill().unwrap();
+    let status = child.wait().unwrap();
+    assert!(!status.success(), "The writer exited before it was killed");
+    lines
+}
+
+// the log is written without a buffer of its own, so a crash of the process loses
+// nothing acknowledged whatever the policy, which only matters on a power loss
+#[test]
+fn writes_survive_a_crash() {
+    for policy in policies() {
+        let temp_dir = TempDir::new().unwrap();
+        write_and_kill(temp_dir.path(), policy, "workload");
+
+        let store = open(temp_dir.path(), policy, 4 * 1024);
+        let report = store.recovery_report();
+        assert_eq!(report.truncated_segment, None, "{:?}", policy);
+        assert_eq!(report.discarded_bytes, 0, "{:?}", policy);
+        for i in 0..KEYS {
+            assert_eq!(store.get_string(format!("key{}", i)).unwrap(), expected(i), "{:?}", policy);
+        }
+    }
+}
+
+#[test]
+fn torn_tail_is_discarded() {
+    for policy in policies() {
+        let temp_dir = TempDir::new().unwrap();
+        write_and_kill(temp_dir.path(), policy, "torn");
+
+        // cut the last record in the middle, as if the crash hit while it was written
+        let segment = active_segment(temp_dir.path());
+        let gen = segment.file_stem().unwrap().to_str().unwrap().parse::<u64>().unwrap();
+        let len = fs::metadata(&segment).unwrap().len();
+        OpenOptions::new().write(true).open(&segment).unwrap().set_len(len - 3).unwrap();
+
+        let store = open(temp_dir.path(), policy, 4 * 1024);
+        let report = store.recovery_report();
+        assert_eq!(report.truncated_segment, Some(gen), "{:?}", policy);
+        assert!(report.discarded_bytes > 0, "{:?}", policy);
+        assert_eq!(fs::metadata(&segment).unwrap().len(), len - 3 - report.discarded_bytes, "{:?}", policy);
+        assert_eq!(store.get_string("torn").unwrap(), None, "{:?}", policy);
+        for i in 0..KEYS {
+            assert_eq!(store.get_string(format!("key{}", i)).unwrap(), expected(i), "{:?}", policy);
+        }
+
+        // appending after the truncated tail leaves a log which reads back whole
+        store.set("after", "crash").unwrap();
+        drop(store);
+        let store = open(temp_dir.path(), policy, 4 * 1024);
+        assert_eq!(store.recovery_report().truncated_segment, None, "{:?}", policy);
+        assert_eq!(store.get_string("after").unwrap(), Some("crash".to_string()), "{:?}", policy);
+        assert_eq!(store.get_string("key1").unwrap(), expected(1), "{:?}", policy);
+    }
+}
+
+// a power loss keeps what was synced, and the record it cut in the middle is
+// discarded on open: every acknowledged write is lost past the last sync point
+#[test]
+fn power_loss_keeps_synced_writes() {
+    for &policy in &[SyncPolicy::Always, SyncPolicy::EveryWrites(16)] {
+        let temp_dir = TempDir::new().unwrap();
+        let lines = write_and_kill(temp_dir.path(), policy, "synced");
+        let synced: Vec<u64> = lines.iter()
+            .find(|line| line.starts_with("synced "))
+            .expect("The writer didn't report its sync point")
+            .split(' ')
+            .skip(1)
+            .map(|n| n.parse().unwrap())
+            .collect();
+        let (synced_keys, synced_len) = (synced[0] as u32, synced[1]);
+        match policy {
+            SyncPolicy::EveryWrites(n) => assert_eq!(synced_keys, KEYS / n as u32 * n as u32),
+            _ => assert_eq!(synced_keys, KEYS),
+        }
+
+        // the page cache is lost past the last sync, up to the middle of the next record
+        let segment = active_segment(temp_dir.path());
+        let gen = segment.file_stem().unwrap().to_str().unwrap().parse::<u64>().unwrap();
+        let len = fs::metadata(&segment).unwrap().len();
+        let torn = (len - synced_len).min(5);
+        OpenOptions::new().write(true).open(&segment).unwrap().set_len(synced_len + torn).unwrap();
+
+        let store = open(temp_dir.path(), policy, 1024 * 1024);
+        let report = store.recovery_report();
+        assert_eq!(report.discarded_bytes, torn, "{:?}", policy);
+        assert_eq!(report.truncated_segment, if torn > 0 { Some(gen) } else { None }, "{:?}", policy);
+        for i in 0..KEYS {
+            let value = if i < synced_keys { Some(format!("value{}", i)) } else { None };
+            assert_eq!(store.get_string(format!("key{}", i)).unwrap(), value, "{:?}", policy);
+        }
+    }
+}
+
+// every policy syncs at its own points, counted by the stats
+#[test]
+fn sync_points() {
+    const WRITES: u64 = 40;
+    for policy in policies() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open(temp_dir.path(), policy, 1024 * 1024);
+        for i in 0..WRITES {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        let stats = store.stats().unwrap();
+        match policy {
+            SyncPolicy::Never => {
+                assert_eq!(stats.syncs, 0);
+                assert_eq!(stats.unsynced_writes, WRITES);
+            },
+            SyncPolicy::Always => {
+                assert_eq!(stats.syncs, WRITES);
+                assert_eq!(stats.unsynced_writes, 0);
+            },
+            SyncPolicy::EveryWrites(n) => {
+                assert_eq!(stats.syncs, WRITES / n);
+                assert_eq!(stats.unsynced_writes, WRITES % n);
+            },
+            SyncPolicy::Interval(interval) => {
+                // the last writes are synced from the background once the interval is over
+                thread::sleep(interval * 4);
+                let stats = store.stats().unwrap();
+                assert!(stats.syncs >= 1 && stats.syncs < WRITES, "{:?}", stats);
+                assert_eq!(stats.unsynced_writes, 0);
+            },
+        }
+
+        // a flush syncs whatever is left, and only then
+        let before = store.stats().unwrap();
+        store.flush().unwrap();
+        let after = store.stats().unwrap();
+        assert_eq!(after.syncs, before.syncs + 1, "{:?}", policy);
+        assert_eq!(after.unsynced_writes, 0, "{:?}", policy);
+    }
+}
@@ -0,0 +1,115 @@
+use kvs::{KvStore, KvsEngine, SledStore};
+use kvs::engine::KvError;
+use std::thread;
+use tempfile::TempDir;
+
+fn string(value: Option<Vec<u8>>) -> Option<String> {
+    value.map(|value| String::from_utf8(value).unwrap())
+}
+
+// reads see the writes of the transaction, which are applied together on commit
+fn check_commit<E: KvsEngine>(engine: &E) {
+    engine.set("a", "1").unwrap();
+    engine.set("b", "1").unwrap();
+    let mut txn = engine.begin().unwrap();
+    assert_eq!(string(engine.txn_get(&mut txn, "a").unwrap()), Some("1".to_string()));
+    txn.set("a", "2").remove("b").remove("missing");
+    assert_eq!(string(engine.txn_get(&mut txn, "a").unwrap()), Some("2".to_string()));
+    assert_eq!(engine.txn_get(&mut txn, "b").unwrap(), None);
+    // nothing shows before the commit
+    assert_eq!(engine.get_string("a").unwrap(), Some("1".to_string()));
+    engine.commit(txn).unwrap();
+    assert_eq!(engine.get_string("a").unwrap(), Some("2".to_string()));
+    assert_eq!(engine.get_string("b").unwrap(), None);
+}
+
+// a transaction whose reads were changed by another write fails as a whole
+fn check_read_conflicts<E: KvsEngine>(engine: &E) {
+    engine.set("a", "1").unwrap();
+    let mut txn = engine.begin().unwrap();
+    engine.txn_get(&mut txn, "a").unwrap();
+    engine.set("a", "3").unwrap();
+    txn.set("c", "written");
+    assert!(matches!(engine.commit(txn), Err(KvError::TransactionConflict)));
+    assert_eq!(engine.get_string("c").unwrap(), None);
+
+    // so does reading a key which doesn't exist, and is set afterwards
+    let mut txn = engine.begin().unwrap();
+    assert_eq!(engine.txn_get(&mut txn, "absent").unwrap(), None);
+    engine.set("absent", "now").unwrap();
+    txn.set("c", "written");
+    assert!(matches!(engine.commit(txn), Err(KvError::TransactionConflict)));
+    assert_eq!(engine.get_string("c").unwrap(), None);
+}
+
+// concurrent read-modify-write transactions retried on conflict lose no update
+fn check_concurrent_increments<E: KvsEngine>(engine: &E) {
+    engine.set("counter", "0").unwrap();
+    let handles: Vec<_> = (0..4).map(|_| {
+        let engine = engine.clone();
+        thread::spawn(move || {
+            let mut done = 0;
+            while done < 50 {
+                let mut txn = engine.begin().unwrap();
+                let n: u64 = match engine.txn_get(&mut txn, "counter") {
+                    Ok(n) => string(n).unwrap().parse().unwrap(),
+                    Err(KvError::TransactionConflict) => continue,
+                    Err(err) => panic!("{:?}", err),
+                };
+                txn.set("counter", (n + 1).to_string());
+                match engine.commit(txn) {
+                    Ok(()) => done += 1,
+                    Err(KvError::TransactionConflict) => {},
+                    Err(err) => panic!("{:?}", err),
+                }
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get_string("counter").unwrap(), Some("200".to_string()));
+}
+
+#[test]
+fn kvs_store_transactions() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        check_commit(&store);
+        check_read_conflicts(&store);
+        check_concurrent_increments(&store);
+    }
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_string("a").unwrap(), Some("3".to_string()));
+    assert_eq!(store.get_string("b").unwrap(), None);
+    assert_eq!(store.get_string("counter").unwrap(), Some("200".to_string()));
+}
+
+#[test]
+fn sled_store_transactions() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    check_commit(&store);
+    check_read_conflicts(&store);
+    check_concurrent_increments(&store);
+}
+
+// a kvs store reads as of the point the transaction began,
+// and refuses to commit over a key written since
+#[test]
+fn kvs_store_snapshot_isolation() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("a", "1").unwrap();
+
+    let mut txn = store.begin().unwrap();
+    store.set("a", "2").unwrap();
+    assert!(matches!(store.txn_get(&mut txn, "a"), Err(KvError::TransactionConflict)));
+
+    let mut txn = store.begin().unwrap();
+    txn.set("a", "3");
+    store.remove("a").unwrap();
+    assert!(matches!(store.commit(txn), Err(KvError::TransactionConflict)));
+    assert_eq!(store.get_string("a").unwrap(), None);
+}
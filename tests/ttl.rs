@@ -0,0 +1,124 @@
+use kvs::{KvStore, KvsEngine, SledStore, WriteBatch};
+use kvs::engine::KvError;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn check_expiry<E: KvsEngine>(engine: &E) {
+    engine.set_with_ttl("short", "1", Duration::from_millis(100)).unwrap();
+    engine.set_with_ttl("long", "2", Duration::from_secs(100)).unwrap();
+    engine.set("plain", "3").unwrap();
+    assert_eq!(engine.get_string("short").unwrap(), Some("1".to_string()));
+    assert!(engine.ttl("long").unwrap().unwrap() > Duration::from_secs(90));
+    assert_eq!(engine.ttl("plain").unwrap(), None);
+    assert!(matches!(engine.ttl("missing"), Err(KvError::KeyNotFound)));
+
+    engine.expire("plain", Duration::from_millis(100)).unwrap();
+    engine.persist("long").unwrap();
+    assert_eq!(engine.ttl("long").unwrap(), None);
+    // a plain set drops the expiry, and so does a batch
+    engine.set_with_ttl("reset", "4", Duration::from_millis(100)).unwrap();
+    engine.set("reset", "5").unwrap();
+    engine.set_with_ttl("batched", "6", Duration::from_millis(100)).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.set("batched", "7");
+    engine.write_batch(batch).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    // expired keys are gone for every operation at once
+    for key in &["short", "plain"] {
+        assert_eq!(engine.get_string(*key).unwrap(), None);
+        assert!(matches!(engine.ttl(*key), Err(KvError::KeyNotFound)));
+        assert!(matches!(engine.expire(*key, Duration::from_secs(1)), Err(KvError::KeyNotFound)));
+        assert!(matches!(engine.persist(*key), Err(KvError::KeyNotFound)));
+        assert!(matches!(engine.remove(*key), Err(KvError::KeyNotFound)));
+    }
+    let keys: Vec<Vec<u8>> = engine.scan(.., None).unwrap().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![b"batched".to_vec(), b"long".to_vec(), b"reset".to_vec()]);
+    assert_eq!(engine.get_string("reset").unwrap(), Some("5".to_string()));
+    assert_eq!(engine.get_string("batched").unwrap(), Some("7".to_string()));
+    // an expired key compares as missing
+    engine.compare_and_swap("short", None, Some(b"again".to_vec())).unwrap();
+    assert_eq!(engine.ttl("short").unwrap(), None);
+
+    engine.set_with_ttl("kept", "8", Duration::from_secs(100)).unwrap();
+}
+
+#[test]
+fn kvs_store_expiry() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        check_expiry(&store);
+    }
+    // expiries are kept in the log
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_string("plain").unwrap(), None);
+    assert_eq!(store.ttl("long").unwrap(), None);
+    assert!(store.ttl("kept").unwrap().unwrap() > Duration::from_secs(90));
+}
+
+#[test]
+fn sled_store_expiry() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = SledStore::open(temp_dir.path()).unwrap();
+        check_expiry(&store);
+    }
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_string("plain").unwrap(), None);
+    assert!(store.ttl("kept").unwrap().unwrap() > Duration::from_secs(90));
+}
+
+fn contains(dir: &Path, needle: &[u8]) -> bool {
+    fs::read_dir(dir).unwrap()
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .any(|bytes| bytes.windows(needle.len()).any(|window| window == needle))
+}
+
+// expired keys aren't copied by a compaction
+#[test]
+fn kvs_store_compaction_purges_expired_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::builder()
+        .segment_size(4 * 1024)
+        .compaction_stale_ratio(0.1)
+        .compaction_min_stale_bytes(1)
+        .compaction_interval(Duration::from_millis(20))
+        .open(temp_dir.path())
+        .unwrap();
+    for i in 0..100 {
+        store.set_with_ttl(format!("expiring{}", i), "value", Duration::from_millis(50)).unwrap();
+        store.set(format!("kept{}", i), "value").unwrap();
+    }
+    thread::sleep(Duration::from_millis(100));
+    // garbage to get a compaction going
+    for i in 0..100 {
+        store.set(format!("kept{}", i), "again").unwrap();
+    }
+    for _ in 0..250 {
+        if !contains(temp_dir.path(), b"expiring") {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!contains(temp_dir.path(), b"expiring"));
+    assert_eq!(store.get_string("kept99").unwrap(), Some("again".to_string()));
+}
+
+// the reaper purges expired keys from a sled store without them being read
+#[test]
+fn sled_store_reaper_purges_expired_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = SledStore::open(temp_dir.path()).unwrap();
+        store.set_with_ttl("expiring", "value", Duration::from_millis(50)).unwrap();
+        store.set("kept", "value").unwrap();
+        thread::sleep(Duration::from_millis(2500));
+    }
+    let db = sled::open(temp_dir.path()).unwrap();
+    assert!(db.get("expiring").unwrap().is_none());
+    assert!(db.get("kept").unwrap().is_some());
+}
@@ -0,0 +1,121 @@
+use kvs::{IndexMode, KvStore, KvsEngine, WriteBatch};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const CACHE_SIZE: u64 = 64 * 1024;
+
+fn open(dir: &Path, index_mode: IndexMode) -> KvStore {
+    KvStore::builder()
+        .index_mode(index_mode)
+        .value_cache_size(CACHE_SIZE)
+        .segment_size(8 * 1024)
+        .compaction_min_stale_bytes(1)
+        .compaction_interval(Duration::from_millis(20))
+        .open(dir)
+        .expect("Fail to open the store")
+}
+
+fn index_modes() -> Vec<IndexMode> {
+    vec![IndexMode::Full, IndexMode::Hashed]
+}
+
+#[test]
+fn repeated_reads_hit_the_cache() {
+    for index_mode in index_modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open(temp_dir.path(), index_mode);
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        for _ in 0..5 {
+            for i in 0..100 {
+                assert_eq!(store.get_string(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+            }
+        }
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.cache_misses, stats.cache_hits), (100, 400), "{:?}", index_mode);
+        assert!(stats.cache_bytes > 0);
+        assert_eq!(store.get("missing").unwrap(), None);
+    }
+}
+
+// every write drops the cached value of its key
+#[test]
+fn writes_invalidate_cached_values() {
+    for index_mode in index_modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open(temp_dir.path(), index_mode);
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+            store.get(format!("key{}", i)).unwrap();
+        }
+        store.set("key1", "set").unwrap();
+        store.remove("key2").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("key3", "batched").remove("key4");
+        store.write_batch(batch).unwrap();
+        store.compare_and_swap("key5", Some(b"value5".to_vec()), Some(b"swapped".to_vec())).unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.set("key6", "committed");
+        store.commit(txn).unwrap();
+        store.expire("key7", Duration::from_millis(50)).unwrap();
+        assert_eq!(store.get_string("key7").unwrap(), Some("value7".to_string()));
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(store.get_string("key1").unwrap(), Some("set".to_string()));
+        assert_eq!(store.get_string("key2").unwrap(), None);
+        assert_eq!(store.get_string("key3").unwrap(), Some("batched".to_string()));
+        assert_eq!(store.get_string("key4").unwrap(), None);
+        assert_eq!(store.get_string("key5").unwrap(), Some("swapped".to_string()));
+        assert_eq!(store.get_string("key6").unwrap(), Some("committed".to_string()));
+        assert_eq!(store.get_string("key7").unwrap(), None);
+        assert!(store.remove("key7").is_err());
+        assert_eq!(store.get_string("key8").unwrap(), Some("value8".to_string()));
+    }
+}
+
+// the cache stays within its size, and right while compactions move the records around
+#[test]
+fn cache_is_bounded_and_follows_compactions() {
+    for index_mode in index_modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = open(temp_dir.path(), index_mode);
+        for i in 0..100u8 {
+            store.set(format!("big{}", i), vec![i; 4000]).unwrap();
+        }
+        for _ in 0..3 {
+            for i in 0..100u8 {
+                assert_eq!(store.get(format!("big{}", i)).unwrap(), Some(vec![i; 4000]));
+            }
+        }
+        let stats = store.stats().unwrap();
+        assert!(stats.cache_bytes <= CACHE_SIZE, "{:?}", stats);
+
+        for round in 0..30 {
+            for i in 0..100 {
+                store.set(format!("key{}", i), format!("value{}-{}", i, round)).unwrap();
+            }
+            for i in 0..100 {
+                assert_eq!(store.get_string(format!("key{}", i)).unwrap(), Some(format!("value{}-{}", i, round)));
+            }
+        }
+        thread::sleep(Duration::from_millis(200));
+        for i in 0..100 {
+            assert_eq!(store.get_string(format!("key{}", i)).unwrap(), Some(format!("value{}-29", i)));
+        }
+        assert!(store.stats().unwrap().cache_bytes <= CACHE_SIZE);
+    }
+}
+
+#[test]
+fn cache_is_off_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key", "value").unwrap();
+    store.get("key").unwrap();
+    store.get("key").unwrap();
+    let stats = store.stats().unwrap();
+    assert_eq!((stats.cache_hits, stats.cache_misses, stats.cache_bytes), (0, 0, 0));
+}